@@ -0,0 +1,781 @@
+//! Typed, layered service configuration
+//!
+//! [ServerConfig] is loaded in layers, each overriding the previous one:
+//!
+//! 1. Defaults
+//! 2. TOML file `{config_dir}/{run_env}.toml` (optional)
+//! 3. Dotenv file `.env.{run_env}` (optional)
+//! 4. Environment variables
+//!
+//! Keys in files are case-insensitive and map to environment variable names, so `app_port = 8080`
+//! in TOML is the same as `APP_PORT=8080` in the environment. Nested TOML tables are flattened
+//! with underscore, arrays are joined with commas.
+//!
+//! Unknown keys in files are reported as invalid, so typos don't go unnoticed. Keys of the
+//! application have to be put in namespaces declared with [ConfigLoader::app_namespace]
+//! (f. ex. `[rabbit]` table for `RABBIT`), they are read from environment too and available
+//! with [ServerConfig::get]. `ENV` can't be set in files, as it selects them.
+//!
+//! Example:
+//! ```no_run
+//! use serwus::{config::ServerConfig, server::{Serwus, default_cors}, EmptyStats};
+//!
+//! #[derive(Clone, EmptyStats)]
+//! pub struct AppData;
+//!
+//! #[actix_web::main]
+//! async fn main() -> std::io::Result<()> {
+//!     // Reports all invalid keys at once
+//!     let config = ServerConfig::loader().app_namespace("rabbit").load()?;
+//!     let rabbit_url = config.get("rabbit_url");
+//!
+//!     Serwus::from_config(&config)
+//!         .start(|| AppData, |_app| {}, default_cors)
+//!         .await
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs, io};
+
+use crate::server::cors::{CorsConfig, origin_regex};
+
+/// Directory searched for `{run_env}.toml` files by [ServerConfig::load]
+pub const DEFAULT_CONFIG_DIR: &str = "config";
+
+const DEFAULT_RUN_ENV: &str = "dev";
+
+/// Source name of environment variables layer
+const ENVIRONMENT: &str = "environment";
+
+/// Keys known to serwus, read from environment variables (`ENV` is read before loading)
+const KEYS: &[&str] = &[
+    "APP_PORT",
+    "LOGGER_LEVEL",
+    "PROJECT_PREFIX",
+    "TEST",
+    "DATABASE_URL",
+    "SHUTDOWN_DRAIN_SECS",
+    "REQUEST_TIMEOUT_MS",
+    "JSON_LIMIT_BYTES",
+    "FORM_LIMIT_BYTES",
+    "PAYLOAD_LIMIT_BYTES",
+    "MANAGEMENT_LISTEN",
+    "MANAGEMENT_PORT",
+    "WORKERS",
+    "BACKLOG",
+    "MAX_CONNECTIONS",
+    "KEEP_ALIVE_SECS",
+    "CLIENT_REQUEST_TIMEOUT_MS",
+    "CLIENT_DISCONNECT_TIMEOUT_MS",
+    "SHUTDOWN_TIMEOUT_SECS",
+    "MAX_BLOCKING_THREADS",
+    "STATS_EXCLUDES",
+    "CORS_ALLOWED_ORIGINS",
+    "CORS_ALLOWED_ORIGIN_SUFFIXES",
+    "CORS_ALLOWED_ORIGIN_REGEX",
+    "CORS_ALLOWED_METHODS",
+    "CORS_ALLOWED_HEADERS",
+    "CORS_EXPOSED_HEADERS",
+    "CORS_ALLOW_CREDENTIALS",
+    "CORS_MAX_AGE_SECS",
+];
+
+/// Service configuration
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Environment name, f. ex. `dev`, `staging`, `prod` (`ENV`)
+    pub run_env: String,
+    /// Port of default listener (`APP_PORT`)
+    pub app_port: u16,
+    /// Logger level or filter directives (`LOGGER_LEVEL`)
+    pub logger_level: String,
+    /// Console logger prints debug logs only from modules containing this prefix (`PROJECT_PREFIX`)
+    pub project_prefix: String,
+    /// Test mode caps number of threads and connections to 2 (`TEST`)
+    pub test: bool,
+    /// Database connection url (`DATABASE_URL`)
+    pub database_url: Option<String>,
+    /// How long to serve requests after SIGTERM with readiness switched off (`SHUTDOWN_DRAIN_SECS`)
+    pub shutdown_drain: Duration,
+    /// Time limit for handling single request (`REQUEST_TIMEOUT_MS`)
+    pub request_timeout: Option<Duration>,
+    /// Size limits of request bodies
+    pub payload_limits: PayloadLimits,
+    /// Separate listener for internal endpoints, f. ex. `127.0.0.1:9000`
+    /// (`MANAGEMENT_LISTEN`, or `MANAGEMENT_PORT` for `0.0.0.0:{port}`)
+    pub management_listener: Option<String>,
+    /// HttpServer tuning
+    pub tuning: ServerTuning,
+    /// Paths or route patterns excluded from base stats, in addition to default ones (`STATS_EXCLUDES`)
+    pub stats_excludes: Vec<String>,
+    /// CORS policy (`CORS_*` keys, see [cors](crate::server::cors))
+    pub cors: CorsConfig,
+    /// Keys from [app namespaces](ConfigLoader::app_namespace), left for the application
+    pub extra: BTreeMap<String, String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            run_env: DEFAULT_RUN_ENV.to_string(),
+            app_port: 8000,
+            logger_level: "info".to_string(),
+            project_prefix: String::new(),
+            test: false,
+            database_url: None,
+            shutdown_drain: Duration::ZERO,
+            request_timeout: None,
+            payload_limits: PayloadLimits::default(),
+            management_listener: None,
+            tuning: ServerTuning::default(),
+            stats_excludes: Vec::new(),
+            cors: CorsConfig::default(),
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+/// HttpServer tuning, `None` means default
+#[derive(Clone, Debug, Default)]
+pub struct ServerTuning {
+    /// Number of workers, defaults to number of threads (`WORKERS`)
+    pub workers: Option<usize>,
+    /// Maximum number of pending connections, defaults to 2048 (`BACKLOG`)
+    pub backlog: Option<u32>,
+    /// Maximum number of concurrent connections per worker, defaults to 25k (`MAX_CONNECTIONS`)
+    pub max_connections: Option<usize>,
+    /// Keep-alive duration, zero disables keep-alive, defaults to 5 s (`KEEP_ALIVE_SECS`)
+    pub keep_alive: Option<Duration>,
+    /// Time to receive request head, defaults to 5 s (`CLIENT_REQUEST_TIMEOUT_MS`)
+    pub client_request_timeout: Option<Duration>,
+    /// Time to close connection gracefully, defaults to 0 (`CLIENT_DISCONNECT_TIMEOUT_MS`)
+    pub client_disconnect_timeout: Option<Duration>,
+    /// Time for workers to finish requests on stop, defaults to 30 s (`SHUTDOWN_TIMEOUT_SECS`)
+    pub shutdown_timeout: Option<Duration>,
+    /// Maximum number of blocking threads per worker (used by `web::block` and so by database queries),
+    /// defaults to number of threads which is also the default database pool size (`MAX_BLOCKING_THREADS`)
+    pub max_blocking_threads: Option<usize>,
+}
+
+/// Size limits of request bodies in bytes, `None` means actix default
+#[derive(Clone, Debug, Default)]
+pub struct PayloadLimits {
+    /// `web::Json` limit, defaults to 2 MiB (`JSON_LIMIT_BYTES`)
+    pub json: Option<usize>,
+    /// `web::Form` limit, defaults to 16 KiB (`FORM_LIMIT_BYTES`)
+    pub form: Option<usize>,
+    /// `Bytes` and `String` limit, defaults to 256 KiB (`PAYLOAD_LIMIT_BYTES`)
+    pub payload: Option<usize>,
+}
+
+/// Single source of configuration values
+struct Layer {
+    source: String,
+    values: Vec<(String, String)>,
+}
+
+/// Where and how [ServerConfig] is loaded from
+#[derive(Clone, Debug)]
+pub struct ConfigLoader {
+    run_env: Option<String>,
+    config_dir: PathBuf,
+    app_namespaces: Vec<String>,
+    /// Variables used instead of process environment, so tests don't have to modify it
+    environment: Option<Vec<(String, String)>>,
+}
+
+impl Default for ConfigLoader {
+    fn default() -> Self {
+        Self {
+            run_env: None,
+            config_dir: PathBuf::from(DEFAULT_CONFIG_DIR),
+            app_namespaces: Vec::new(),
+            environment: None,
+        }
+    }
+}
+
+impl ConfigLoader {
+    /// Sets environment, by default it's named in `ENV` variable (`dev` if not set)
+    pub fn run_env(mut self, run_env: impl Into<String>) -> Self {
+        self.run_env = Some(run_env.into());
+        self
+    }
+
+    /// Sets directory searched for `{run_env}.toml` (default: [DEFAULT_CONFIG_DIR])
+    pub fn config_dir(mut self, config_dir: impl Into<PathBuf>) -> Self {
+        self.config_dir = config_dir.into();
+        self
+    }
+
+    /// Accepts application keys named `namespace` or starting with `namespace_`,
+    /// f. ex. `RABBIT_URL` (`url` in `[rabbit]` table) for `rabbit`
+    pub fn app_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.app_namespaces.push(namespace.into().to_uppercase());
+        self
+    }
+
+    #[cfg(test)]
+    fn environment(mut self, environment: &[(&str, &str)]) -> Self {
+        self.environment = Some(
+            environment
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        );
+        self
+    }
+
+    fn env_vars(&self) -> Vec<(String, String)> {
+        match &self.environment {
+            Some(environment) => environment.clone(),
+            None => env::vars_os()
+                .filter_map(|(key, value)| {
+                    Some((key.into_string().ok()?, value.into_string().ok()?))
+                })
+                .collect(),
+        }
+    }
+
+    fn is_app_key(&self, key: &str) -> bool {
+        self.app_namespaces.iter().any(|namespace| {
+            key.strip_prefix(namespace.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('_'))
+        })
+    }
+
+    /// Loads configuration, reporting all invalid keys at once
+    pub fn load(&self) -> Result<ServerConfig, ConfigError> {
+        let env_vars = self.env_vars();
+        let run_env = self
+            .run_env
+            .clone()
+            .or_else(|| {
+                env_vars
+                    .iter()
+                    .find(|(key, _)| key == "ENV")
+                    .map(|(_, value)| value.clone())
+            })
+            .unwrap_or_else(|| DEFAULT_RUN_ENV.to_string());
+
+        let mut errors = Vec::new();
+        let mut layers = Vec::new();
+
+        let toml_path = self.config_dir.join(format!("{run_env}.toml"));
+        if toml_path.exists() {
+            match read_toml(&toml_path) {
+                Ok(layer) => layers.push(layer),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        let dotenv_path = format!(".env.{run_env}");
+        if Path::new(&dotenv_path).exists() {
+            match read_dotenv(&dotenv_path) {
+                Ok(layer) => layers.push(layer),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        layers.push(Layer {
+            source: ENVIRONMENT.to_string(),
+            values: env_vars
+                .into_iter()
+                .filter(|(key, _)| KEYS.contains(&key.as_str()) || self.is_app_key(key))
+                .collect(),
+        });
+
+        let config = ServerConfig {
+            run_env,
+            ..Default::default()
+        };
+
+        config.apply_layers(layers, errors, self)
+    }
+}
+
+impl ServerConfig {
+    /// Loads configuration for environment named in `ENV` variable (`dev` if not set)
+    /// using [DEFAULT_CONFIG_DIR].
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::loader().load()
+    }
+
+    /// Loads configuration for given environment, searching TOML file in `config_dir`
+    pub fn load_for(run_env: &str, config_dir: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Self::loader()
+            .run_env(run_env)
+            .config_dir(config_dir.as_ref())
+            .load()
+    }
+
+    /// Loader to be customized, f. ex. with application namespaces
+    pub fn loader() -> ConfigLoader {
+        ConfigLoader::default()
+    }
+
+    /// Returns value of key from [app namespace](ConfigLoader::app_namespace)
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.extra.get(&key.to_uppercase()).map(String::as_str)
+    }
+
+    /// Number of threads (and database connections) for this configuration
+    pub fn num_threads(&self) -> usize {
+        crate::threads::num_threads_for(self.test)
+    }
+
+    fn apply_layers(
+        mut self,
+        layers: Vec<Layer>,
+        mut errors: Vec<InvalidKey>,
+        loader: &ConfigLoader,
+    ) -> Result<Self, ConfigError> {
+        for layer in layers {
+            for (key, value) in layer.values {
+                let result = if loader.is_app_key(&key) {
+                    self.extra.insert(key.clone(), value);
+                    Ok(())
+                } else {
+                    self.set(&key, &value)
+                };
+
+                if let Err(message) = result {
+                    errors.push(InvalidKey {
+                        key,
+                        source: layer.source.clone(),
+                        message,
+                    });
+                }
+            }
+        }
+
+        errors.extend(self.validate());
+
+        if errors.is_empty() {
+            Ok(self)
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "ENV" => return Err("can't be set in files, it selects them".to_string()),
+            "APP_PORT" => self.app_port = parse(value)?,
+            "LOGGER_LEVEL" => self.logger_level = value.to_string(),
+            "PROJECT_PREFIX" => self.project_prefix = value.to_string(),
+            // Presence of empty TEST variable means test mode, as it always did
+            "TEST" => self.test = value.is_empty() || parse_bool(value)?,
+            "DATABASE_URL" => self.database_url = Some(value.to_string()),
+            "SHUTDOWN_DRAIN_SECS" => self.shutdown_drain = Duration::from_secs(parse(value)?),
+            "JSON_LIMIT_BYTES" => self.payload_limits.json = Some(parse(value)?),
+            "FORM_LIMIT_BYTES" => self.payload_limits.form = Some(parse(value)?),
+            "PAYLOAD_LIMIT_BYTES" => self.payload_limits.payload = Some(parse(value)?),
+            "REQUEST_TIMEOUT_MS" => {
+                self.request_timeout = Some(Duration::from_millis(parse(value)?))
+            }
+            "MANAGEMENT_LISTEN" => self.management_listener = Some(value.to_string()),
+            "MANAGEMENT_PORT" => {
+                let port: u16 = parse(value)?;
+                self.management_listener = Some(format!("0.0.0.0:{port}"));
+            }
+            "WORKERS" => self.tuning.workers = Some(parse(value)?),
+            "BACKLOG" => self.tuning.backlog = Some(parse(value)?),
+            "MAX_CONNECTIONS" => self.tuning.max_connections = Some(parse(value)?),
+            "KEEP_ALIVE_SECS" => self.tuning.keep_alive = Some(Duration::from_secs(parse(value)?)),
+            "CLIENT_REQUEST_TIMEOUT_MS" => {
+                self.tuning.client_request_timeout = Some(Duration::from_millis(parse(value)?))
+            }
+            "CLIENT_DISCONNECT_TIMEOUT_MS" => {
+                self.tuning.client_disconnect_timeout = Some(Duration::from_millis(parse(value)?))
+            }
+            "SHUTDOWN_TIMEOUT_SECS" => {
+                self.tuning.shutdown_timeout = Some(Duration::from_secs(parse(value)?))
+            }
+            "MAX_BLOCKING_THREADS" => self.tuning.max_blocking_threads = Some(parse(value)?),
+            "STATS_EXCLUDES" => self.stats_excludes = parse_list(value)?,
+            "CORS_ALLOWED_ORIGINS" => self.cors.allowed_origins = parse_list(value)?,
+            "CORS_ALLOWED_ORIGIN_SUFFIXES" => {
+                self.cors.allowed_origin_suffixes = parse_list(value)?
+            }
+            "CORS_ALLOWED_ORIGIN_REGEX" => {
+                self.cors.allowed_origin_regex =
+                    Some(origin_regex(value).map_err(|err| err.to_string())?)
+            }
+            "CORS_ALLOWED_METHODS" => {
+                self.cors.allowed_methods = parse_list(&value.to_uppercase())?
+            }
+            "CORS_ALLOWED_HEADERS" => self.cors.allowed_headers = parse_list(value)?,
+            "CORS_EXPOSED_HEADERS" => self.cors.exposed_headers = parse_list(value)?,
+            "CORS_ALLOW_CREDENTIALS" => self.cors.allow_credentials = parse_bool(value)?,
+            "CORS_MAX_AGE_SECS" => self.cors.max_age = Some(Duration::from_secs(parse(value)?)),
+            _ => return Err("unknown key, declare app namespace for own keys".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Checks values which parsed correctly but are not acceptable
+    fn validate(&self) -> Vec<InvalidKey> {
+        let mut errors = Vec::new();
+        let mut invalid = |key: &str, message: String| {
+            errors.push(InvalidKey {
+                key: key.to_string(),
+                source: "validation".to_string(),
+                message,
+            })
+        };
+
+        if self.run_env.is_empty() {
+            invalid("ENV", "must not be empty".to_string());
+        }
+
+        if self.app_port == 0 {
+            invalid("APP_PORT", "must be greater than 0".to_string());
+        }
+
+        if let Err(message) = validate_log_filter(&self.logger_level) {
+            invalid("LOGGER_LEVEL", message);
+        }
+
+        if self.tuning.workers == Some(0) {
+            invalid("WORKERS", "must be greater than 0".to_string());
+        }
+
+        if self.tuning.max_blocking_threads == Some(0) {
+            invalid("MAX_BLOCKING_THREADS", "must be greater than 0".to_string());
+        }
+
+        for (key, message) in self.cors.problems() {
+            invalid(key, message);
+        }
+
+        errors
+    }
+}
+
+/// Configuration could not be loaded, contains every invalid key
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<InvalidKey>);
+
+/// Single configuration problem
+#[derive(Debug)]
+pub struct InvalidKey {
+    /// Key name (as environment variable)
+    pub key: String,
+    /// File name, `environment` or `validation`
+    pub source: String,
+    pub message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for error in &self.0 {
+            write!(f, "\n  {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for InvalidKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.key, self.source, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(err: ConfigError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+    }
+}
+
+fn read_toml(path: &Path) -> Result<Layer, InvalidKey> {
+    let source = path.display().to_string();
+
+    let table = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|content| parse_toml(&content))
+        .map_err(|message| InvalidKey {
+            key: "*".to_string(),
+            source: source.clone(),
+            message,
+        })?;
+
+    Ok(Layer {
+        source,
+        values: table,
+    })
+}
+
+fn parse_toml(content: &str) -> Result<Vec<(String, String)>, String> {
+    let table = content
+        .parse::<toml::Table>()
+        .map_err(|err| err.to_string())?;
+
+    let mut values = Vec::new();
+    flatten_toml("", table, &mut values)?;
+    Ok(values)
+}
+
+fn flatten_toml(
+    prefix: &str,
+    table: toml::Table,
+    values: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let key = format!("{prefix}{}", key.to_uppercase());
+
+        let value = match value {
+            toml::Value::Table(table) => {
+                flatten_toml(&format!("{key}_"), table, values)?;
+                continue;
+            }
+            toml::Value::Array(items) => items
+                .into_iter()
+                .map(toml_scalar)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("{key}: arrays can contain only plain values"))?
+                .join(","),
+            scalar => toml_scalar(scalar).ok_or_else(|| format!("{key}: unsupported value"))?,
+        };
+
+        values.push((key, value));
+    }
+    Ok(())
+}
+
+fn toml_scalar(value: toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        toml::Value::Datetime(value) => Some(value.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => None,
+    }
+}
+
+fn read_dotenv(path: &str) -> Result<Layer, InvalidKey> {
+    let invalid = |message: String| InvalidKey {
+        key: "*".to_string(),
+        source: path.to_string(),
+        message,
+    };
+
+    // Iterator is deprecated in favour of loading into process environment,
+    // but here file has to stay a separate layer
+    #[allow(deprecated)]
+    let values = dotenv::from_filename_iter(path)
+        .map_err(|err| invalid(err.to_string()))?
+        .map(|item| item.map(|(key, value)| (key.to_uppercase(), value)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid(err.to_string()))?;
+
+    Ok(Layer {
+        source: path.to_string(),
+        values,
+    })
+}
+
+fn parse<T>(value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|err| format!("can't parse {value:?}: {err}"))
+}
+
+/// Comma separated values, empty ones skipped
+fn parse_list<T>(value: &str) -> Result<Vec<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(parse)
+        .collect()
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("can't parse {value:?} as boolean")),
+    }
+}
+
+/// Accepts level (`debug`) or comma separated directives (`info,my_crate::db=debug`),
+/// with `tracing` any `EnvFilter` directives (f. ex. `my_crate[request{id}]=trace`)
+fn validate_log_filter(filter: &str) -> Result<(), String> {
+    filter.parse::<crate::logger::LogFilter>().map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigLoader, Layer, ServerConfig, parse_toml};
+    use std::time::Duration;
+
+    #[test]
+    fn later_layers_override_earlier() {
+        let toml = parse_toml(
+            r#"
+                app_port = 9000
+                shutdown_drain_secs = 5
+                [rabbit]
+                url = "amqp://localhost"
+                [cors]
+                allowed_origins = ["https://a.example.com", "https://b.example.com"]
+            "#,
+        )
+        .unwrap();
+
+        let layers = vec![
+            Layer {
+                source: "prod.toml".to_string(),
+                values: toml,
+            },
+            Layer {
+                source: "environment".to_string(),
+                values: vec![("APP_PORT".to_string(), "9100".to_string())],
+            },
+        ];
+
+        let loader = ConfigLoader::default().app_namespace("rabbit");
+        let config = ServerConfig::default()
+            .apply_layers(layers, Vec::new(), &loader)
+            .unwrap();
+
+        assert_eq!(config.app_port, 9100);
+        assert_eq!(config.shutdown_drain, Duration::from_secs(5));
+        assert_eq!(config.get("rabbit_url"), Some("amqp://localhost"));
+        assert_eq!(config.cors.allowed_origins.len(), 2);
+    }
+
+    #[test]
+    fn parses_tuning_keys() {
+        let toml = parse_toml(
+            r#"
+                workers = 4
+                backlog = 512
+                max_connections = 1000
+                keep_alive_secs = 0
+                client_request_timeout_ms = 1500
+                client_disconnect_timeout_ms = 200
+                shutdown_timeout_secs = 20
+                max_blocking_threads = 8
+            "#,
+        )
+        .unwrap();
+
+        let layers = vec![Layer {
+            source: "prod.toml".to_string(),
+            values: toml,
+        }];
+
+        let tuning = ServerConfig::default()
+            .apply_layers(layers, Vec::new(), &ConfigLoader::default())
+            .unwrap()
+            .tuning;
+
+        assert_eq!(tuning.workers, Some(4));
+        assert_eq!(tuning.backlog, Some(512));
+        assert_eq!(tuning.max_connections, Some(1000));
+        assert_eq!(tuning.keep_alive, Some(Duration::ZERO));
+        assert_eq!(
+            tuning.client_request_timeout,
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            tuning.client_disconnect_timeout,
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(tuning.shutdown_timeout, Some(Duration::from_secs(20)));
+        assert_eq!(tuning.max_blocking_threads, Some(8));
+    }
+
+    #[test]
+    fn reports_every_invalid_key() {
+        let layers = vec![Layer {
+            source: "environment".to_string(),
+            values: vec![
+                ("APP_PROT".to_string(), "8080".to_string()),
+                ("ENV".to_string(), "prod".to_string()),
+                ("APP_PORT".to_string(), "http".to_string()),
+                ("TEST".to_string(), "maybe".to_string()),
+                ("LOGGER_LEVEL".to_string(), "info,db=loud".to_string()),
+                ("CORS_ALLOWED_ORIGINS".to_string(), "*".to_string()),
+                ("CORS_ALLOW_CREDENTIALS".to_string(), "true".to_string()),
+            ],
+        }];
+
+        let errors = ServerConfig::default()
+            .apply_layers(layers, Vec::new(), &ConfigLoader::default())
+            .unwrap_err()
+            .0;
+
+        let keys: Vec<_> = errors.iter().map(|err| err.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            [
+                "APP_PROT",
+                "ENV",
+                "APP_PORT",
+                "TEST",
+                "LOGGER_LEVEL",
+                "CORS_ALLOW_CREDENTIALS"
+            ]
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn accepts_tracing_filter_directives() {
+        let filter = "info,my_app[request{id}]=debug,my_app::db=trace";
+        let layers = vec![Layer {
+            source: "environment".to_string(),
+            values: vec![("LOGGER_LEVEL".to_string(), filter.to_string())],
+        }];
+
+        let config = ServerConfig::default()
+            .apply_layers(layers, Vec::new(), &ConfigLoader::default())
+            .unwrap();
+        assert_eq!(config.logger_level, filter);
+    }
+
+    #[test]
+    fn environment_overrides_app_keys_from_files() {
+        let dir = std::env::temp_dir().join(format!("serwus-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("test.toml"),
+            "[serwustest]\nurl = \"amqp://file\"\nqueue = \"jobs\"\n",
+        )
+        .unwrap();
+
+        let config = ServerConfig::loader()
+            .environment(&[("SERWUSTEST_URL", "amqp://env")])
+            .run_env("test")
+            .config_dir(&dir)
+            .app_namespace("serwustest")
+            .load()
+            .unwrap();
+
+        assert_eq!(config.get("serwustest_url"), Some("amqp://env"));
+        assert_eq!(config.get("serwustest_queue"), Some("jobs"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,12 +1,11 @@
 //! Simple key->value cache with defined time to live
 
-use std::{collections::HashMap, hash::Hash, time::Instant};
-
-// Maybe TODO: Cache ready to use as shared in tokio loop
-// pub(super) type SharedCache<V> = Rc<RwLock<CacheValue<V>>>;
-// pub(super) type SharedCacheMap<K, V> = Rc<RwLock<HashMap<K, CacheValue<V>>>>;
-
-// TODO: Unit-tests needed
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 
 pub struct CacheMap<K, V> {
     values: HashMap<K, CacheValue<V>>,
@@ -51,6 +50,98 @@ impl<K: Hash + Eq, V> CacheMap<K, V> {
     pub fn insert(&mut self, key: K, value: V) -> Option<CacheValue<V>> {
         self.values.insert(key, CacheValue::from(Some(value)))
     }
+
+    pub fn remove(&mut self, key: &K) -> Option<CacheValue<V>> {
+        self.values.remove(key)
+    }
+
+    /// Drops values older than ttl
+    pub fn remove_expired(&mut self) {
+        let ttl = self.ttl;
+        self.values.retain(|_, value| value.age() < ttl);
+    }
+
+    /// Keeps only values with keys for which `f` returns true
+    pub fn retain(&mut self, mut f: impl FnMut(&K) -> bool) {
+        self.values.retain(|key, _| f(key));
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// [CacheMap] shareable between threads, f. ex. actix workers
+pub struct SharedCacheMap<K, V>(Arc<RwLock<CacheMap<K, V>>>);
+
+impl<K, V> Clone for SharedCacheMap<K, V> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> SharedCacheMap<K, V> {
+    pub fn new(ttl: u64) -> Self {
+        Self(Arc::new(RwLock::new(CacheMap::new(ttl))))
+    }
+
+    /// Value stored less than ttl ago
+    pub fn get_fresh(&self, key: &K) -> Option<V> {
+        let cache = self.0.read().ok()?;
+        match cache.should_retain(key) {
+            Some(true) => cache.get(key).cloned(),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        if let Ok(mut cache) = self.0.write() {
+            cache.insert(key, value);
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.0
+            .write()
+            .ok()
+            .and_then(|mut cache| cache.remove(key))
+            .and_then(|value| value.value)
+    }
+
+    pub fn remove_expired(&self) {
+        if let Ok(mut cache) = self.0.write() {
+            cache.remove_expired();
+        }
+    }
+
+    /// Keeps only values with keys for which `f` returns true
+    pub fn retain(&self, f: impl FnMut(&K) -> bool) {
+        if let Ok(mut cache) = self.0.write() {
+            cache.retain(f);
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut cache) = self.0.write() {
+            cache.clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.read().map(|cache| cache.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl<K, V> Entry<'_, K, V> {
@@ -92,3 +183,82 @@ impl<T> From<Option<T>> for CacheValue<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheMap, CacheValue, SharedCacheMap};
+
+    #[test]
+    fn expires_values_after_ttl() {
+        let mut fresh = CacheMap::new(60);
+        fresh.insert("a", 1);
+        assert_eq!(fresh.should_retain(&"a"), Some(true));
+        assert_eq!(fresh.get(&"a"), Some(&1));
+        assert!(fresh.entry(&"a").unwrap().is_fresh());
+        assert_eq!(fresh.should_retain(&"b"), None);
+        assert!(fresh.entry(&"b").is_none());
+
+        // With zero ttl every value is already expired
+        let mut expired = CacheMap::new(0);
+        expired.insert("a", 1);
+        assert_eq!(expired.should_retain(&"a"), Some(false));
+        assert!(!expired.entry(&"a").unwrap().should_retain());
+        assert_eq!(expired.get(&"a"), Some(&1));
+
+        assert!(!CacheValue::<u32>::default().is_fresh(60));
+    }
+
+    #[test]
+    fn removes_expired_and_selected_values() {
+        let mut expired = CacheMap::new(0);
+        expired.insert("a", 1);
+        expired.insert("b", 2);
+        expired.remove_expired();
+        assert!(expired.is_empty());
+
+        let mut cache = CacheMap::new(60);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+        cache.remove_expired();
+        assert_eq!(cache.len(), 3);
+
+        cache.retain(|key| *key != "b");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"b"), None);
+
+        assert_eq!(cache.remove(&"a").and_then(|value| value.value), Some(1));
+        assert_eq!(cache.remove(&"a").map(|value| value.value), None);
+        assert_eq!(cache.len(), 1);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn shares_values_between_clones() {
+        let cache = SharedCacheMap::new(60);
+        let clone = cache.clone();
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+        assert_eq!(clone.get_fresh(&"a"), Some(1));
+        assert_eq!(clone.len(), 3);
+
+        clone.retain(|key| *key != "b");
+        assert_eq!(cache.get_fresh(&"b"), None);
+        assert_eq!(cache.remove(&"a"), Some(1));
+        assert_eq!(cache.remove(&"a"), None);
+        cache.remove_expired();
+        assert_eq!(clone.len(), 1);
+
+        clone.clear();
+        assert!(cache.is_empty());
+
+        let expired = SharedCacheMap::new(0);
+        expired.insert("a", 1);
+        assert_eq!(expired.get_fresh(&"a"), None);
+        expired.remove_expired();
+        assert!(expired.is_empty());
+    }
+}
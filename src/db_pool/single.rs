@@ -2,6 +2,7 @@ use diesel::r2d2::ConnectionManager;
 use log::{error, info};
 use std::env;
 
+use crate::config::ServerConfig;
 use crate::threads::num_threads;
 
 use super::DbConnection;
@@ -22,16 +23,33 @@ pub fn init_default_pool() -> Result<Pool, r2d2::Error> {
 /// Database URL is taken from `DATABASE` env variable.
 /// If `TEST` env variable is defined then size is capped to 2.
 pub fn init_pool(size: usize) -> Result<Pool, r2d2::Error> {
-    info!("Connecting to database");
-
-    let manager = ConnectionManager::<DbConnection>::new(default_database_url());
-
     let max_size = if env::var("TEST").is_ok() && size > 2 {
         2
     } else {
         size
     };
 
+    init_pool_with_url(default_database_url(), max_size)
+}
+
+/// Init pool of N connections to database from configuration, where N is number of threads.
+///
+/// Falls back to `DATABASE_URL` env variable if configuration has no database url.
+pub fn init_config_pool(config: &ServerConfig) -> Result<Pool, r2d2::Error> {
+    let url = config
+        .database_url
+        .clone()
+        .unwrap_or_else(default_database_url);
+
+    init_pool_with_url(url, config.num_threads())
+}
+
+/// Init pool of `max_size` connections to database at `url`
+pub fn init_pool_with_url(url: String, max_size: usize) -> Result<Pool, r2d2::Error> {
+    info!("Connecting to database");
+
+    let manager = ConnectionManager::<DbConnection>::new(url);
+
     // #[allow(clippy::cast_possible_truncation)]
     Pool::builder()
         .max_size(max_size as u32)
@@ -41,6 +41,7 @@
 
 #![deny(clippy::all)]
 
+pub mod config;
 pub mod containers;
 pub mod utils;
 
@@ -1,18 +1,317 @@
 use chrono::*;
 use colored::*;
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError, info};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+
+use crate::config::ServerConfig;
+use crate::server::request_id;
 
 pub struct ConsoleLogger;
 
 pub static LOGGER: ConsoleLogger = ConsoleLogger;
 
+static SETTINGS: OnceLock<LoggerSettings> = OnceLock::new();
+
+/// Filter from settings or `LOGGER_LEVEL` read at startup
+static INITIAL_FILTER: OnceLock<LogFilter> = OnceLock::new();
+
+/// Filters changed at runtime
+static RUNTIME_FILTERS: RwLock<RuntimeFilters> = RwLock::new(RuntimeFilters {
+    base: None,
+    temporary: None,
+});
+
+/// Incremented on every filter change, so revert task woken just before the change does nothing
+static FILTER_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Task restoring base filter after time given to [set_log_filter], aborted on next change
+static REVERT_TASK: Mutex<Option<tokio::task::AbortHandle>> = Mutex::new(None);
+
+struct RuntimeFilters {
+    /// Replaces initial filter, see [replace_base_log_filter]
+    base: Option<LogFilter>,
+    /// Set with [set_log_filter], with optional revert time
+    temporary: Option<(LogFilter, Option<DateTime<Utc>>)>,
+}
+
+impl RuntimeFilters {
+    fn current(&self) -> &LogFilter {
+        match (&self.temporary, &self.base) {
+            (Some((filter, _)), _) | (None, Some(filter)) => filter,
+            (None, None) => initial_filter(),
+        }
+    }
+}
+
+/// Logger settings taking precedence over `LOGGER_LEVEL`, `ENV` and `PROJECT_PREFIX` env variables
+pub struct LoggerSettings {
+    pub level: String,
+    pub env: String,
+    pub project_prefix: String,
+}
+
+impl From<&ServerConfig> for LoggerSettings {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            level: config.logger_level.clone(),
+            env: config.run_env.clone(),
+            project_prefix: config.project_prefix.clone(),
+        }
+    }
+}
+
+/// Sets logger settings instead of reading env variables. Only the first call has effect.
+pub fn configure(settings: LoggerSettings) {
+    let _ = SETTINGS.set(settings);
+}
+
 pub fn logger_level() -> String {
-    ::std::env::var("LOGGER_LEVEL").unwrap_or_else(|_| "info".to_string())
+    match SETTINGS.get() {
+        Some(settings) => settings.level.clone(),
+        None => ::std::env::var("LOGGER_LEVEL").unwrap_or_else(|_| "info".to_string()),
+    }
+}
+
+/// Log filter: default level and per-module levels, f. ex. `info,my_app::db=debug`.
+///
+/// Module directive applies to module and its submodules, the most specific one wins.
+/// With `tracing` feature any `EnvFilter` directives are accepted, span and field ones
+/// (f. ex. `my_app[request{id}]=trace`) apply only to tracing subscriber.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    directives: String,
+}
+
+impl LogFilter {
+    /// Level for records of given target (module path)
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// The most verbose level of all directives
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+
+    /// Directives as given, for tracing `EnvFilter`
+    pub fn directives(&self) -> &str {
+        &self.directives
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        #[cfg(feature = "tracing")]
+        tracing_subscriber::EnvFilter::try_new(filter)
+            .map_err(|err| format!("invalid log filter {filter:?}: {err}"))?;
+
+        let mut parsed = LogFilter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+            directives: filter.trim().to_string(),
+        };
+
+        for directive in filter.split(',').map(str::trim) {
+            // Validated by EnvFilter, span and field directives don't apply to log records
+            #[cfg(feature = "tracing")]
+            if directive.is_empty() || directive.contains('[') {
+                continue;
+            }
+
+            let (module, level) = match directive.rsplit_once('=') {
+                Some((module, level)) => (Some(module.trim()), level.trim()),
+                // Bare target enables all its levels in EnvFilter
+                #[cfg(feature = "tracing")]
+                None if LevelFilter::from_str(directive).is_err() => (Some(directive), "trace"),
+                None => (None, directive),
+            };
+
+            let level = LevelFilter::from_str(level)
+                .map_err(|_| format!("unknown log level {level:?} in {filter:?}"))?;
+
+            match module {
+                Some("") => return Err(format!("empty module name in {filter:?}")),
+                Some(module) => parsed.modules.push((module.to_string(), level)),
+                None => parsed.default = level,
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.directives)
+    }
+}
+
+fn initial_filter() -> &'static LogFilter {
+    if let Some(filter) = INITIAL_FILTER.get() {
+        return filter;
+    }
+
+    // Logged after initialization, logger itself asks for this filter
+    let (filter, error) = match logger_level().parse() {
+        Ok(filter) => (filter, None),
+        Err(err) => {
+            let filter = LogFilter {
+                default: LevelFilter::Info,
+                modules: Vec::new(),
+                directives: "info".to_string(),
+            };
+            (filter, Some(err))
+        }
+    };
+
+    let filter = INITIAL_FILTER.get_or_init(|| filter);
+    if let Some(err) = error {
+        log::error!("Invalid LOGGER_LEVEL, using info: {err}");
+    }
+    filter
+}
+
+fn runtime_filters() -> std::sync::RwLockReadGuard<'static, RuntimeFilters> {
+    RUNTIME_FILTERS
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+fn runtime_filters_mut() -> std::sync::RwLockWriteGuard<'static, RuntimeFilters> {
+    RUNTIME_FILTERS
+        .write()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// Currently active log filter and time of its automatic revert, if set
+pub fn log_filter() -> (LogFilter, Option<DateTime<Utc>>) {
+    let filters = runtime_filters();
+    let revert_at = filters
+        .temporary
+        .as_ref()
+        .and_then(|(_, revert_at)| *revert_at);
+    (filters.current().clone(), revert_at)
+}
+
+/// Replaces log filter of console logger and tracing subscriber at runtime.
+///
+/// With `revert_after` base filter (from settings or `LOGGER_LEVEL`) is restored after given time
+/// by task spawned on current Tokio runtime, unless filter is changed again in the meantime.
+pub fn set_log_filter(filter: LogFilter, revert_after: Option<std::time::Duration>) {
+    let generation = FILTER_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    abort_revert();
+
+    let revert_at = revert_after
+        .and_then(|after| TimeDelta::from_std(after).ok())
+        .and_then(|after| Utc::now().checked_add_signed(after));
+
+    apply_filter(&filter);
+    info!("Log filter set to {filter}");
+    runtime_filters_mut().temporary = Some((filter, revert_at));
+
+    let Some(after) = revert_after else {
+        return;
+    };
+
+    let deadline = tokio::time::Instant::now().checked_add(after);
+    let (Some(_), Some(deadline)) = (revert_at, deadline) else {
+        log::error!("Log filter won't be reverted, time of revert is out of range");
+        return;
+    };
+
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => {
+            let task = runtime.spawn(async move {
+                tokio::time::sleep_until(deadline).await;
+                if FILTER_GENERATION.load(Ordering::SeqCst) == generation {
+                    restore_log_filter();
+                }
+            });
+            *revert_task() = Some(task.abort_handle());
+        }
+        Err(_) => log::error!("Log filter won't be reverted, no Tokio runtime"),
+    }
+}
+
+/// Drops filter set with [set_log_filter], restoring base one
+pub fn reset_log_filter() {
+    abort_revert();
+    restore_log_filter();
+}
+
+fn restore_log_filter() {
+    FILTER_GENERATION.fetch_add(1, Ordering::SeqCst);
+
+    let filter = {
+        let mut filters = runtime_filters_mut();
+        filters.temporary = None;
+        filters.current().clone()
+    };
+
+    apply_filter(&filter);
+    info!("Log filter restored to {filter}");
+}
+
+fn revert_task() -> std::sync::MutexGuard<'static, Option<tokio::task::AbortHandle>> {
+    REVERT_TASK.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn abort_revert() {
+    if let Some(task) = revert_task().take() {
+        task.abort();
+    }
+}
+
+/// Replaces base filter, f. ex. with `LOGGER_LEVEL` reloaded from configuration,
+/// and drops filter set with [set_log_filter]
+pub fn replace_base_log_filter(filter: LogFilter) {
+    runtime_filters_mut().base = Some(filter);
+    reset_log_filter();
+}
+
+fn apply_filter(filter: &LogFilter) {
+    #[cfg(feature = "tracing")]
+    crate::server::tracing::reload_filter(filter);
+
+    log::set_max_level(filter.max_level());
+}
+
+fn run_env() -> String {
+    match SETTINGS.get() {
+        Some(settings) => settings.env.clone(),
+        None => ::std::env::var("ENV").unwrap_or_else(|_| "dev".to_string()),
+    }
+}
+
+fn project_prefix() -> String {
+    match SETTINGS.get() {
+        Some(settings) => settings.project_prefix.clone(),
+        None => ::std::env::var("PROJECT_PREFIX").unwrap_or_else(|_| "".to_string()),
+    }
 }
 
 impl log::Log for ConsoleLogger {
     fn enabled(&self, metadata: &Metadata<'_>) -> bool {
-        metadata.level() <= Level::Info || logger_level() == "debug"
+        metadata.level() <= runtime_filters().current().level_for(metadata.target())
     }
 
     fn log(&self, record: &Record<'_>) {
@@ -41,24 +340,29 @@ impl log::Log for ConsoleLogger {
                 format!("{}", record.level()).yellow()
             };
 
-            let env = ::std::env::var("ENV").unwrap_or_else(|_| "dev".to_string());
-            let proj_prefix = ::std::env::var("PROJECT_PREFIX").unwrap_or_else(|_| "".to_string());
+            let env = run_env();
+            let proj_prefix = project_prefix();
             let lib_name = env!("CARGO_PKG_NAME");
+            let request_id = request_id::current()
+                .map(|id| format!(" req={id}"))
+                .unwrap_or_default();
 
             if [Level::Error, Level::Warn].contains(&record.level()) && env != "dev" {
                 println!(
-                    "[{} {}] {}:{} - {}",
+                    "[{} {}{}] {}:{} - {}",
                     level,
                     record.module_path().unwrap_or(""),
+                    request_id,
                     record.file().unwrap_or(""),
                     record.line().unwrap_or(0),
                     format!("{}", record.args()).green(),
                 )
             } else if env != "dev" && record.level() != Level::Debug {
                 println!(
-                    "[{} {}] - {}",
+                    "[{} {}{}] - {}",
                     level,
                     record.module_path().unwrap_or(""),
+                    request_id,
                     record.args(),
                 )
             } else if record.module_path().unwrap_or("").contains(&proj_prefix)
@@ -66,11 +370,12 @@ impl log::Log for ConsoleLogger {
                 || record.level() != Level::Debug
             {
                 println!(
-                    "{}{} {} {}{} {}",
+                    "{}{} {} {}{}{} {}",
                     "[".to_string().white(),
                     date,
                     level,
                     record.module_path().unwrap_or(""),
+                    request_id,
                     "]".to_string().white(),
                     format!("{}", record.args()).white(),
                 )
@@ -83,14 +388,43 @@ impl log::Log for ConsoleLogger {
 
 pub fn init_logger() -> Result<(), SetLoggerError> {
     log::set_logger(&LOGGER)?;
-
-    if logger_level() == "debug" {
-        log::set_max_level(LevelFilter::Debug);
-    } else {
-        log::set_max_level(LevelFilter::Info);
-    }
+    log::set_max_level(initial_filter().max_level());
 
     info!("Logger init...");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use log::LevelFilter;
+
+    use super::LogFilter;
+
+    #[test]
+    fn most_specific_directive_wins() {
+        let filter: LogFilter = "warn,my_app=debug,my_app::db=error".parse().unwrap();
+
+        assert_eq!(filter.level_for("other"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("my_app"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("my_app::api"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("my_app::db::pool"), LevelFilter::Error);
+        assert_eq!(filter.level_for("my_application"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+        assert_eq!(filter.to_string(), "warn,my_app=debug,my_app::db=error");
+
+        assert!("info,my_app=loud".parse::<LogFilter>().is_err());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn keeps_tracing_directives() {
+        let directives = "warn,my_app[request{id=1}]=trace,my_app::db=error,other_app";
+        let filter: LogFilter = directives.parse().unwrap();
+
+        assert_eq!(filter.level_for("my_app"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("my_app::db"), LevelFilter::Error);
+        assert_eq!(filter.level_for("other_app"), LevelFilter::Trace);
+        assert_eq!(filter.directives(), directives);
+    }
+}
@@ -0,0 +1,74 @@
+//! Access log without credentials
+//!
+//! Request targets are written to access log (and tracing root span) with values of
+//! [REDACTED_QUERY_PARAMS] replaced by `REDACTED`, so tokens passed in query string
+//! (f. ex. by browser WebSocket clients, see `websocket::WsConfig::token_query_param`)
+//! don't end up in logs.
+
+use std::borrow::Cow;
+
+use actix_web::{dev::ServiceRequest, middleware::Logger};
+
+/// Query params carrying credentials, hidden in access logs and tracing spans
+pub const REDACTED_QUERY_PARAMS: [&str; 1] = ["access_token"];
+
+/// Query string with values of [REDACTED_QUERY_PARAMS] replaced
+pub(crate) fn redact_query(query: &str) -> Cow<'_, str> {
+    let name = |pair: &str| pair.split('=').next().unwrap_or_default().to_string();
+    let is_redacted = |pair: &str| REDACTED_QUERY_PARAMS.contains(&name(pair).as_str());
+
+    if !query.split('&').any(is_redacted) {
+        return Cow::Borrowed(query);
+    }
+
+    query
+        .split('&')
+        .map(|pair| match is_redacted(pair) {
+            true => format!("{}=REDACTED", name(pair)),
+            false => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+        .into()
+}
+
+/// First line of request, like `%r` of [Logger], with redacted query
+fn request_line(req: &ServiceRequest) -> String {
+    match req.query_string() {
+        "" => format!("{} {} {:?}", req.method(), req.path(), req.version()),
+        query => format!(
+            "{} {}?{} {:?}",
+            req.method(),
+            req.path(),
+            redact_query(query),
+            req.version()
+        ),
+    }
+}
+
+/// [Logger] with default format, but redacted request line
+pub fn access_logger() -> Logger {
+    Logger::new(r#"%a "%{request_line}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        .custom_request_replace("request_line", request_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::{redact_query, request_line};
+
+    #[actix_web::test]
+    async fn redacts_tokens_in_query() {
+        assert_eq!(redact_query("page=1"), "page=1");
+        assert_eq!(
+            redact_query("access_token=secret&page=1&access_token_hint=x"),
+            "access_token=REDACTED&page=1&access_token_hint=x"
+        );
+
+        let req = TestRequest::get()
+            .uri("/ws?access_token=secret")
+            .to_srv_request();
+        assert_eq!(request_line(&req), "GET /ws?access_token=REDACTED HTTP/1.1");
+    }
+}
@@ -1,6 +1,18 @@
 use actix_cors::Cors;
-use actix_web::{App, HttpServer, middleware::ErrorHandlers};
+use actix_service::{ServiceFactory, Transform};
+use actix_web::{
+    App, Error, HttpServer,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::{Condition, ErrorHandlers},
+};
 use dotenv::dotenv;
+use std::collections::HashSet;
+use std::io;
+#[cfg(any(unix, feature = "tls"))]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(not(feature = "swagger"))]
 use actix_web::web;
@@ -11,53 +23,153 @@ use paperclip::{
     v2::models::DefaultApiRaw,
 };
 
+use crate::config::{ConfigLoader, PayloadLimits, ServerConfig, ServerTuning};
+use crate::logger::LoggerSettings;
 use crate::server::json_error::default_error_handler;
 
+use super::access_log::access_logger;
+use super::compression::Compression;
+use super::conditional::ConditionalGet;
+use super::listener::Listener;
+use super::log_level;
+use super::maintenance::{self, Maintenance};
+use super::middleware::{
+    BoxedService, CustomMiddlewares, MiddlewareFn, MiddlewarePosition, middleware_fn,
+};
+use super::request_id::RequestIdWrapper;
+use super::security_headers::SecurityHeaders;
+use super::shutdown::{Shutdown, handle_signals};
 use super::threads;
+use super::timeout::Timeout;
+
+#[cfg(feature = "tls")]
+use super::tls::{RELOAD_CHECK_INTERVAL, ReloadableCert, watch_files};
 
+#[cfg(feature = "static_files")]
+use super::static_files::StaticFiles;
+
+use super::scheduler::{Job, JobStates, spawn_jobs};
 use super::stats::{
-    AppDataWrapper, BaseStats, StatsPresenter, StatsWrapper, default_healthcheck_handler,
-    default_readiness_handler, default_stats_handler,
+    AppDataWrapper, BaseStats, StatsPresenter, StatsWrapper, default_excludes,
+    default_healthcheck_handler, default_readiness_handler, default_stats_handler,
 };
+use super::tasks::{BackgroundTask, HookFn, TaskContext, TaskStates, run_hooks, spawn_tasks};
+
+/// Same as actix-web default
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
-pub struct Serwus<'a> {
-    app_port: &'a str,
-    run_env: &'a str,
+pub struct Serwus {
+    app_port: u16,
+    run_env: String,
     #[cfg(feature = "swagger")]
-    swagger_mount: &'a str,
+    swagger_mount: String,
     #[cfg(feature = "swagger")]
     swagger_spec: DefaultApiRaw,
     json_errors: bool,
+    log_level_endpoint: bool,
+    #[cfg(unix)]
+    log_filter_reload: Option<ConfigLoader>,
+    maintenance: Option<Maintenance>,
+    maintenance_endpoint: bool,
+    shutdown_drain: Duration,
+    request_timeout: Option<Duration>,
+    payload_limits: PayloadLimits,
+    compression: Option<Compression>,
+    security_headers: Option<SecurityHeaders>,
+    conditional_get: bool,
+    #[cfg(feature = "metrics")]
+    metrics: super::metrics::middleware::Metrics,
+    stats_excludes: HashSet<String>,
+    #[cfg(feature = "static_files")]
+    static_files: Vec<StaticFiles>,
+    listeners: Vec<Listener>,
+    management_listener: Option<String>,
+    tuning: ServerTuning,
+    middlewares: Vec<(MiddlewarePosition, MiddlewareFn)>,
+    on_start: Vec<HookFn>,
+    on_shutdown: Vec<HookFn>,
+    tasks: Vec<BackgroundTask>,
+    jobs: Vec<Job>,
+    logger: Option<LoggerSettings>,
 }
 
-impl Default for Serwus<'_> {
+impl Default for Serwus {
     fn default() -> Self {
         Serwus {
-            app_port: "8000",
-            run_env: "dev",
+            app_port: 8000,
+            run_env: "dev".to_string(),
             #[cfg(feature = "swagger")]
-            swagger_mount: "/swagger",
+            swagger_mount: "/swagger".to_string(),
             #[cfg(feature = "swagger")]
             swagger_spec: DefaultApiRaw::default(),
             json_errors: false,
+            log_level_endpoint: false,
+            #[cfg(unix)]
+            log_filter_reload: None,
+            maintenance: None,
+            maintenance_endpoint: false,
+            shutdown_drain: Duration::ZERO,
+            request_timeout: None,
+            payload_limits: PayloadLimits::default(),
+            compression: None,
+            security_headers: None,
+            conditional_get: false,
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+            stats_excludes: default_excludes(),
+            #[cfg(feature = "static_files")]
+            static_files: Vec::new(),
+            listeners: Vec::new(),
+            management_listener: None,
+            tuning: ServerTuning::default(),
+            middlewares: Vec::new(),
+            on_start: Vec::new(),
+            on_shutdown: Vec::new(),
+            tasks: Vec::new(),
+            jobs: Vec::new(),
+            logger: None,
         }
     }
 }
 
-impl<'a> Serwus<'a> {
-    pub fn set_app_port(mut self, app_port: &'a str) -> Self {
+impl Serwus {
+    /// Creates builder from loaded configuration.
+    ///
+    /// Logger uses configured level, environment and project prefix instead of env variables.
+    pub fn from_config(config: &ServerConfig) -> Self {
+        let mut tuning = config.tuning.clone();
+        tuning.workers.get_or_insert(config.num_threads());
+        tuning
+            .max_blocking_threads
+            .get_or_insert(config.num_threads());
+
+        Serwus {
+            app_port: config.app_port,
+            run_env: config.run_env.clone(),
+            shutdown_drain: config.shutdown_drain,
+            request_timeout: config.request_timeout,
+            payload_limits: config.payload_limits.clone(),
+            management_listener: config.management_listener.clone(),
+            tuning,
+            logger: Some(LoggerSettings::from(config)),
+            ..Default::default()
+        }
+        .exclude_from_stats(config.stats_excludes.iter().cloned())
+    }
+
+    pub fn set_app_port(mut self, app_port: u16) -> Self {
         self.app_port = app_port;
         self
     }
 
-    pub fn set_run_env(mut self, run_env: &'a str) -> Self {
-        self.run_env = run_env;
+    pub fn set_run_env(mut self, run_env: impl Into<String>) -> Self {
+        self.run_env = run_env.into();
         self
     }
 
     #[cfg(feature = "swagger")]
-    pub fn set_swagger_mount(mut self, swagger_mount: &'a str) -> Self {
-        self.swagger_mount = swagger_mount;
+    pub fn set_swagger_mount(mut self, swagger_mount: impl Into<String>) -> Self {
+        self.swagger_mount = swagger_mount.into();
         self
     }
 
@@ -81,18 +193,320 @@ impl<'a> Serwus<'a> {
     }
 
     // Replaces default error handlers with custom one that
-    // any non-JSON error wraps into JSON with GenericError schem
+    // any non-JSON error wraps into JSON with GenericError schem.
+    // Also extractor errors are described in JsonError data, see [extract](super::extract).
     pub fn json_errors(mut self) -> Self {
         self.json_errors = true;
         self
     }
 
+    /// Adds internal endpoint `/_loglevel` changing log filter at runtime, see [log_level](super::log_level).
+    ///
+    /// It has no authorization, so use it only with [management listener](Self::set_management_listener)
+    /// or behind a proxy blocking it.
+    pub fn enable_log_level_endpoint(mut self) -> Self {
+        self.log_level_endpoint = true;
+        self
+    }
+
+    /// Reloads `LOGGER_LEVEL` on `SIGHUP`, see [log_level](super::log_level).
+    ///
+    /// Pass loader used for startup configuration, so the same files and namespaces are read.
+    #[cfg(unix)]
+    pub fn reload_log_filter_on_sighup(mut self, loader: ConfigLoader) -> Self {
+        self.log_filter_reload = Some(loader);
+        self
+    }
+
+    /// Adds maintenance mode switched with `SIGUSR1`, flag file or (if enabled with
+    /// [enable_maintenance_endpoint](Self::enable_maintenance_endpoint)) internal endpoint,
+    /// see [maintenance](super::maintenance).
+    pub fn set_maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    /// Adds internal endpoint `/_maintenance` switching maintenance mode set with
+    /// [set_maintenance](Self::set_maintenance), see [maintenance](super::maintenance).
+    ///
+    /// It has no authorization, so use it only with [management listener](Self::set_management_listener)
+    /// or behind a proxy blocking it.
+    pub fn enable_maintenance_endpoint(mut self) -> Self {
+        self.maintenance_endpoint = true;
+        self
+    }
+
+    /// Sets how long server keeps serving requests after SIGTERM with readiness switched off,
+    /// before it waits for in-flight requests and stops.
+    pub fn set_shutdown_drain(mut self, shutdown_drain: Duration) -> Self {
+        self.shutdown_drain = shutdown_drain;
+        self
+    }
+
+    /// Sets size limit of `web::Json` bodies (default: 2 MiB)
+    pub fn set_json_limit(mut self, limit: usize) -> Self {
+        self.payload_limits.json = Some(limit);
+        self
+    }
+
+    /// Sets size limit of `web::Form` bodies (default: 16 KiB)
+    pub fn set_form_limit(mut self, limit: usize) -> Self {
+        self.payload_limits.form = Some(limit);
+        self
+    }
+
+    /// Sets size limit of `Bytes` and `String` bodies (default: 256 KiB)
+    pub fn set_payload_limit(mut self, limit: usize) -> Self {
+        self.payload_limits.payload = Some(limit);
+        self
+    }
+
+    /// Compresses responses of whole app (including internal endpoints), see [Compression].
+    ///
+    /// Scopes can override it with their own [Compression].
+    pub fn set_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Adds security headers to responses of whole app (including internal endpoints),
+    /// f. ex. `SecurityHeaders::strict()`, see [SecurityHeaders].
+    ///
+    /// Scopes can override them with their own [SecurityHeaders].
+    pub fn set_security_headers(mut self, security_headers: SecurityHeaders) -> Self {
+        self.security_headers = Some(security_headers);
+        self
+    }
+
+    /// Adds ETags to JSON responses and answers conditional GETs with 304, see [ConditionalGet]
+    pub fn enable_conditional_get(mut self) -> Self {
+        self.conditional_get = true;
+        self
+    }
+
+    /// Sets labels, buckets and excludes of request metrics, see [Metrics](super::metrics::middleware::Metrics)
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(mut self, metrics: super::metrics::middleware::Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Excludes paths or route patterns (f. ex. `/users/{id}`) from base stats,
+    /// in addition to [DEFAULT_STATS_EXCLUDES](super::stats::DEFAULT_STATS_EXCLUDES)
+    pub fn exclude_from_stats(
+        mut self,
+        paths: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.stats_excludes
+            .extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Replaces all paths excluded from base stats, including default ones
+    pub fn set_stats_excludes(
+        mut self,
+        paths: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.stats_excludes = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Serves directory of static assets for requests not matched by any route, see [StaticFiles].
+    ///
+    /// Can be called many times, first mount accepting the path serves it.
+    #[cfg(feature = "static_files")]
+    pub fn add_static_files(mut self, static_files: StaticFiles) -> Self {
+        self.static_files.push(static_files);
+        self
+    }
+
+    /// Responds with 504 to requests not handled in given time, see [Timeout].
+    ///
+    /// Scopes can override it with their own [Timeout].
+    pub fn set_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Adds plain HTTP listener on TCP address, f. ex. `[::]:8000` or `10.1.2.3:8000`.
+    ///
+    /// Can be called many times. If no listener is added, server listens on `0.0.0.0:{app_port}`.
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        self.listeners.push(Listener::Tcp(addr.into()));
+        self
+    }
+
+    /// Adds plain HTTP listener on Unix domain socket
+    #[cfg(unix)]
+    pub fn listen_uds(mut self, path: impl Into<PathBuf>) -> Self {
+        self.listeners.push(Listener::Uds(path.into()));
+        self
+    }
+
+    /// Adds HTTPS listener on TCP address with certificate chain and private key from PEM files.
+    ///
+    /// Files are reloaded when they change, see [tls](super::tls) module.
+    #[cfg(feature = "tls")]
+    pub fn listen_rustls(
+        mut self,
+        addr: impl Into<String>,
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> Self {
+        self.listeners.push(Listener::Rustls {
+            addr: addr.into(),
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        });
+        self
+    }
+
+    /// Serves internal endpoints (`_healthcheck`, `_ready`, `_stats`, `_prometheus`, `metrics`,
+    /// enabled `_loglevel` and `_maintenance`) and Swagger only on separate listener,
+    /// f. ex. `0.0.0.0:9000`, instead of app listeners.
+    pub fn set_management_listener(mut self, addr: impl Into<String>) -> Self {
+        self.management_listener = Some(addr.into());
+        self
+    }
+
+    /// Same as [set_management_listener](Self::set_management_listener) with `0.0.0.0:{port}`
+    pub fn set_management_port(self, port: u16) -> Self {
+        self.set_management_listener(format!("0.0.0.0:{port}"))
+    }
+
+    /// Sets number of workers (default: number of threads)
+    pub fn set_workers(mut self, workers: usize) -> Self {
+        self.tuning.workers = Some(workers);
+        self
+    }
+
+    /// Sets maximum number of pending connections (default: 2048)
+    pub fn set_backlog(mut self, backlog: u32) -> Self {
+        self.tuning.backlog = Some(backlog);
+        self
+    }
+
+    /// Sets maximum number of concurrent connections per worker (default: 25k)
+    pub fn set_max_connections(mut self, max_connections: usize) -> Self {
+        self.tuning.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Sets keep-alive duration, zero disables keep-alive (default: 5 s)
+    pub fn set_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.tuning.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Sets time limit for receiving request head (default: 5 s)
+    pub fn set_client_request_timeout(mut self, timeout: Duration) -> Self {
+        self.tuning.client_request_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets time limit for closing connection gracefully (default: 0)
+    pub fn set_client_disconnect_timeout(mut self, timeout: Duration) -> Self {
+        self.tuning.client_disconnect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long workers can finish in-flight requests when stopping (default: 30 s)
+    pub fn set_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.tuning.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets maximum number of blocking threads per worker, used by `web::block`
+    /// and so by [db_pool](crate::db_pool) async queries.
+    ///
+    /// Defaults to number of threads, which is also the default database pool size.
+    pub fn set_max_blocking_threads(mut self, max_blocking_threads: usize) -> Self {
+        self.tuning.max_blocking_threads = Some(max_blocking_threads);
+        self
+    }
+
+    /// Wraps whole app (including internal endpoints) in custom middleware at given position
+    /// relative to built-in middlewares, see [MiddlewarePosition].
+    ///
+    /// Factory is called once per worker. Middlewares added for the same position are applied
+    /// in order, so the last one is the outermost, like with `App::wrap`.
+    ///
+    /// ```no_run
+    /// # use actix_web::{Error, body::MessageBody, dev::{ServiceRequest, ServiceResponse}, middleware::Next};
+    /// # use serwus::server::{MiddlewarePosition, Serwus};
+    /// # async fn my_middleware(
+    /// #     req: ServiceRequest,
+    /// #     next: Next<impl MessageBody>,
+    /// # ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    /// #     next.call(req).await
+    /// # }
+    /// Serwus::default().add_middleware(MiddlewarePosition::OutsideStats, || {
+    ///     actix_web::middleware::from_fn(my_middleware)
+    /// })
+    /// # ;
+    /// ```
+    pub fn add_middleware<M, B>(
+        mut self,
+        position: MiddlewarePosition,
+        factory: impl Fn() -> M + Send + Sync + 'static,
+    ) -> Self
+    where
+        M: Transform<
+                BoxedService,
+                ServiceRequest,
+                Response = ServiceResponse<B>,
+                Error = Error,
+                InitError = (),
+            > + 'static,
+        B: MessageBody + 'static,
+    {
+        self.middlewares.push((position, middleware_fn(factory)));
+        self
+    }
+
+    /// Runs hook after app data is prepared, before server starts listening.
+    ///
+    /// Hooks run in order of adding, error stops server start and is returned from [start](Self::start).
+    pub fn on_start<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: FnOnce(TaskContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<()>> + 'static,
+    {
+        self.on_start.push(Box::new(move |ctx| Box::pin(hook(ctx))));
+        self
+    }
+
+    /// Runs hook after server stopped, before [start](Self::start) returns.
+    ///
+    /// Hooks run in order of adding, also when server stopped with error.
+    pub fn on_shutdown<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: FnOnce(TaskContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<()>> + 'static,
+    {
+        self.on_shutdown
+            .push(Box::new(move |ctx| Box::pin(hook(ctx))));
+        self
+    }
+
+    /// Runs background task supervised by server, see [tasks](super::tasks)
+    pub fn add_task(mut self, task: BackgroundTask) -> Self {
+        self.tasks.push(task);
+        self
+    }
+
+    /// Runs periodic job, see [scheduler](super::scheduler)
+    pub fn add_job(mut self, job: Job) -> Self {
+        self.jobs.push(job);
+        self
+    }
+
     pub async fn start<D, T, F, C>(
-        self,
+        mut self,
         prepare_app_data: impl Fn() -> T + Sized,
         configure_app: F,
         cors_factory: C,
-    ) -> std::io::Result<()>
+    ) -> io::Result<()>
     where
         D: AppDataWrapper + 'static,
         T: StatsPresenter<D> + 'static + Clone + Send + Sync,
@@ -101,6 +515,10 @@ impl<'a> Serwus<'a> {
     {
         dotenv().ok();
 
+        if let Some(settings) = self.logger.take() {
+            crate::logger::configure(settings);
+        }
+
         #[cfg(feature = "tracing")]
         super::tracing::register_tracing();
 
@@ -110,94 +528,588 @@ impl<'a> Serwus<'a> {
             Err(_) => log::error!("Error logger initialization"),
         };
 
-        let numthreads = threads::num_threads();
-        log::info!("Configuring for {numthreads} threads");
+        let tuning = self.tuning.clone();
+        let numthreads = tuning.workers.unwrap_or_else(threads::num_threads);
+        let max_blocking_threads = tuning
+            .max_blocking_threads
+            .unwrap_or_else(threads::num_threads);
+        let shutdown_timeout = tuning.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+        log::info!(
+            "Configuring for {numthreads} threads with up to {max_blocking_threads} blocking threads each"
+        );
 
-        let app_data = web::Data::new(prepare_app_data());
-        let stats = web::Data::new(BaseStats::default());
+        let shared = self.shared_data(prepare_app_data());
+        let shutdown = shared.shutdown.get_ref().clone();
+        let stats_for_signals = shared.stats.get_ref().clone();
+        let shutdown_drain = self.shutdown_drain;
 
-        #[allow(unused)]
-        let prod_env = self.run_env == "prod";
+        let task_context = TaskContext::new(shared.app_data.clone(), shutdown.clone());
+        run_hooks(std::mem::take(&mut self.on_start), &task_context).await?;
+        let on_shutdown = std::mem::take(&mut self.on_shutdown);
+        let tasks = std::mem::take(&mut self.tasks);
+        let task_states = shared.tasks.get_ref().clone();
+        let jobs = std::mem::take(&mut self.jobs);
+        #[cfg(unix)]
+        let log_filter_reload = self.log_filter_reload.take();
+        let job_states = shared.jobs.get_ref().clone();
 
+        let management_listener = self.management_listener.take();
+        let separate_management = management_listener.is_some();
+
+        // Swagger is served with internal endpoints, so spec of app has to be prepared for them
         #[cfg(feature = "swagger")]
-        let swagger_mount = self.swagger_mount.to_string();
-
-        log::info!("Starting HTTP server on port {}", self.app_port);
-        #[allow(clippy::let_and_return)]
-        HttpServer::new(move || {
-            let app = App::new()
-                .app_data(app_data.clone())
-                .app_data(stats.clone())
-                .route(
-                    "_healthcheck",
-                    actix_web::web::get().to(default_healthcheck_handler),
-                )
-                .route(
-                    "_ready",
-                    actix_web::web::get().to(default_readiness_handler::<T, D>),
-                )
-                .route(
-                    "_stats",
-                    actix_web::web::get().to(default_stats_handler::<T, D>),
-                );
-
-            #[cfg(feature = "prometheus")]
-            let app = app.route(
-                "_prometheus",
-                actix_web::web::get().to(super::prometheus::prometheus_stats_handler::<T, D>),
-            );
+        if separate_management {
+            self.swagger_spec = self.api_spec(configure_app.clone());
+        }
 
-            #[cfg(feature = "metrics")]
-            let app = app.wrap(super::metrics::middleware::Metrics).route(
-                "metrics",
-                actix_web::web::get().to(super::metrics::handler::metrics),
-            );
+        let listeners = if self.listeners.is_empty() {
+            vec![Listener::Tcp(format!("0.0.0.0:{}", self.app_port))]
+        } else {
+            std::mem::take(&mut self.listeners)
+        };
 
-            #[cfg(feature = "swagger")]
-            let app = if prod_env {
-                app.wrap_api()
-            } else {
-                app.wrap_api_with_spec(self.swagger_spec.clone())
-                    .with_json_spec_at(&format!("{swagger_mount}_spec"))
-                    .with_swagger_ui_at(&swagger_mount)
-            };
+        let serwus_maintenance = self.maintenance.clone();
+        let serwus = Arc::new(self);
 
-            // If you added the "v3" feature, you can also include
-            // .with_json_spec_v3_at("/api/spec/v3")
-            #[cfg(feature = "openapi_v3")]
-            let app = app.with_json_spec_v3_at(&format!("{swagger_mount}_spec_v3"));
+        let management = match management_listener {
+            Some(addr) => {
+                log::info!("Starting management server on http://{addr}");
 
-            let app = app.configure(configure_app.clone());
+                let shared = shared.clone();
+                let serwus = serwus.clone();
 
-            let app = app
-                .wrap(cors_factory())
-                .wrap(StatsWrapper::default())
-                .wrap({
-                    let error_handlers = ErrorHandlers::new();
+                let server = HttpServer::new(move || serwus.build_management_app::<D, T>(&shared))
+                    .workers(1)
+                    .shutdown_timeout(shutdown_timeout.as_secs())
+                    .disable_signals()
+                    .bind(&addr)
+                    .map_err(|err| {
+                        io::Error::new(err.kind(), format!("Can't bind http://{addr}: {err}"))
+                    })?
+                    .run();
 
-                    if self.json_errors {
-                        error_handlers.default_handler(default_error_handler)
-                    } else {
-                        error_handlers
+                Some(server)
+            }
+            None => None,
+        };
+
+        let mut server = HttpServer::new(move || {
+            serwus.build_app::<D, T, F>(
+                &shared,
+                !separate_management,
+                configure_app.clone(),
+                cors_factory(),
+            )
+        })
+        .workers(numthreads)
+        .worker_max_blocking_threads(max_blocking_threads)
+        .shutdown_timeout(shutdown_timeout.as_secs())
+        .disable_signals();
+
+        if let Some(backlog) = tuning.backlog {
+            server = server.backlog(backlog);
+        }
+        if let Some(max_connections) = tuning.max_connections {
+            server = server.max_connections(max_connections);
+        }
+        if let Some(keep_alive) = tuning.keep_alive {
+            server = server.keep_alive(keep_alive);
+        }
+        if let Some(timeout) = tuning.client_request_timeout {
+            server = server.client_request_timeout(timeout);
+        }
+        if let Some(timeout) = tuning.client_disconnect_timeout {
+            server = server.client_disconnect_timeout(timeout);
+        }
+
+        for listener in listeners {
+            log::info!("Starting HTTP server on {listener}");
+
+            server = match &listener {
+                Listener::Tcp(addr) => server.bind(addr),
+                #[cfg(unix)]
+                Listener::Uds(path) => server.bind_uds(path),
+                #[cfg(feature = "tls")]
+                Listener::Rustls {
+                    addr,
+                    cert_path,
+                    key_path,
+                } => {
+                    let cert = Arc::new(ReloadableCert::load(cert_path, key_path)?);
+                    actix_web::rt::spawn(watch_files(cert.clone(), RELOAD_CHECK_INTERVAL));
+                    server.bind_rustls_0_23(addr, cert.server_config()?)
+                }
+            }
+            .map_err(|err| io::Error::new(err.kind(), format!("Can't bind {listener}: {err}")))?;
+        }
+
+        let server = server.run();
+
+        let mut handles = vec![server.handle()];
+        handles.extend(management.as_ref().map(|server| server.handle()));
+
+        spawn_tasks(tasks, task_context.clone(), task_states);
+        spawn_jobs(jobs, task_context.clone(), job_states);
+
+        #[cfg(unix)]
+        if let Some(loader) = log_filter_reload {
+            actix_web::rt::spawn(log_level::reload_on_sighup(loader));
+        }
+
+        if let Some(maintenance) = &serwus_maintenance {
+            maintenance::spawn_switches(maintenance);
+        }
+
+        actix_web::rt::spawn(handle_signals(
+            handles,
+            shutdown,
+            stats_for_signals,
+            shutdown_drain,
+        ));
+
+        let result = match management {
+            Some(management) => futures::future::try_join(server, management)
+                .await
+                .map(|_| ()),
+            None => server.await,
+        };
+
+        let hooks_result = run_hooks(on_shutdown, &task_context).await;
+        result.and(hooks_result)
+    }
+
+    /// Builds app with all built-in middlewares, used by server workers and [TestServer](super::TestServer)
+    pub(super) fn build_app<D, T, F>(
+        &self,
+        shared: &SharedData<T>,
+        with_internal_routes: bool,
+        configure_app: F,
+        cors: Cors,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody + use<D, T, F>>,
+            Error = Error,
+            InitError = (),
+        > + use<D, T, F>,
+    >
+    where
+        D: AppDataWrapper + 'static,
+        T: StatsPresenter<D> + 'static,
+        F: Fn(&mut web::ServiceConfig) + 'static,
+    {
+        let app = App::new()
+            .app_data(shared.app_data.clone())
+            .app_data(shared.stats.clone())
+            .app_data(shared.shutdown.clone())
+            .app_data(shared.tasks.clone())
+            .app_data(shared.jobs.clone())
+            .app_data(shared.maintenance.clone());
+
+        let app = app.configure(|cfg| {
+            super::extract::configure(cfg, &self.payload_limits, self.json_errors)
+        });
+
+        let app = if with_internal_routes {
+            app.configure(|cfg| self.internal_endpoints::<T, D>(cfg))
+        } else {
+            app
+        };
+
+        #[cfg(feature = "metrics")]
+        let app = app.wrap(self.metrics.clone());
+
+        #[cfg(feature = "swagger")]
+        let app = self.wrap_swagger(app, with_internal_routes);
+
+        let app = app.configure(configure_app);
+
+        #[cfg(feature = "static_files")]
+        let app = if self.static_files.is_empty() {
+            app
+        } else {
+            app.default_service(super::static_files::default_service(
+                self.static_files.clone(),
+            ))
+        };
+
+        let app = app
+            .wrap(Condition::new(self.conditional_get, ConditionalGet))
+            .wrap(Condition::new(
+                self.request_timeout.is_some(),
+                Timeout::new(self.request_timeout.unwrap_or_default()),
+            ))
+            .wrap(Condition::new(
+                self.maintenance.is_some(),
+                self.maintenance.clone().unwrap_or_default(),
+            ))
+            .wrap(self.middlewares_at(MiddlewarePosition::InsideCors))
+            .wrap(cors)
+            .wrap(self.middlewares_at(MiddlewarePosition::OutsideCors))
+            .wrap(StatsWrapper::new(self.stats_excludes.clone()))
+            .wrap(self.middlewares_at(MiddlewarePosition::OutsideStats))
+            .wrap({
+                let error_handlers = ErrorHandlers::new();
+
+                if self.json_errors {
+                    error_handlers.default_handler(default_error_handler)
+                } else {
+                    error_handlers
+                }
+            })
+            .wrap(Condition::new(
+                self.security_headers.is_some(),
+                self.security_headers.clone().unwrap_or_default(),
+            ))
+            .wrap(Condition::new(
+                self.compression.is_some(),
+                self.compression.clone().unwrap_or_default(),
+            ))
+            .wrap(self.middlewares_at(MiddlewarePosition::OutsideErrorHandlers));
+
+        #[cfg(feature = "tracing")]
+        let app = app.wrap(tracing_actix_web::TracingLogger::<
+            super::tracing::TracingSpanBuilder,
+        >::new());
+
+        let app = app
+            .wrap(access_logger())
+            .wrap(RequestIdWrapper)
+            .wrap(self.middlewares_at(MiddlewarePosition::Outermost));
+
+        #[cfg(feature = "swagger")]
+        let app = app.build();
+
+        app
+    }
+
+    /// Builds app of management listener with internal endpoints (and Swagger, if enabled)
+    pub(super) fn build_management_app<D, T>(
+        &self,
+        shared: &SharedData<T>,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody + use<D, T>>,
+            Error = Error,
+            InitError = (),
+        > + use<D, T>,
+    >
+    where
+        D: AppDataWrapper + 'static,
+        T: StatsPresenter<D> + 'static,
+    {
+        let app = App::new()
+            .app_data(shared.app_data.clone())
+            .app_data(shared.stats.clone())
+            .app_data(shared.shutdown.clone())
+            .app_data(shared.tasks.clone())
+            .app_data(shared.jobs.clone())
+            .app_data(shared.maintenance.clone())
+            .configure(|cfg| self.internal_endpoints::<T, D>(cfg));
+
+        #[cfg(feature = "swagger")]
+        let app = self.wrap_swagger(app, true).build();
+
+        app
+    }
+
+    /// Registers internal endpoints enabled in builder
+    fn internal_endpoints<T, D>(&self, cfg: &mut actix_web::web::ServiceConfig)
+    where
+        D: AppDataWrapper + 'static,
+        T: StatsPresenter<D> + 'static,
+    {
+        internal_routes::<T, D>(cfg);
+        if self.log_level_endpoint {
+            log_level::routes(cfg);
+        }
+        if self.maintenance_endpoint && self.maintenance.is_some() {
+            maintenance::routes(cfg);
+        }
+    }
+
+    /// Wraps app for API spec generation, serving spec and Swagger UI if `with_swagger`
+    /// (and not in `prod` environment)
+    #[cfg(feature = "swagger")]
+    fn wrap_swagger<S>(&self, app: App<S>, with_swagger: bool) -> paperclip::actix::App<S>
+    where
+        S: ServiceFactory<ServiceRequest, Config = (), Error = Error, InitError = ()>,
+    {
+        if !with_swagger {
+            return app.wrap_api();
+        }
+
+        let app = if self.run_env == "prod" {
+            app.wrap_api()
+        } else {
+            app.wrap_api_with_spec(self.swagger_spec.clone())
+                .with_json_spec_at(&format!("{}_spec", self.swagger_mount))
+                .with_swagger_ui_at(&self.swagger_mount)
+        };
+
+        // If you added the "v3" feature, you can also include
+        // .with_json_spec_v3_at("/api/spec/v3")
+        #[cfg(feature = "openapi_v3")]
+        let app = app.with_json_spec_v3_at(&format!("{}_spec_v3", self.swagger_mount));
+
+        app
+    }
+
+    /// API spec of app routes, to be served on management listener
+    #[cfg(feature = "swagger")]
+    fn api_spec<F>(&self, configure_app: F) -> DefaultApiRaw
+    where
+        F: Fn(&mut web::ServiceConfig) + 'static,
+    {
+        let mut spec = self.swagger_spec.clone();
+        let _ = App::new()
+            .wrap_api_with_spec(self.swagger_spec.clone())
+            .configure(configure_app)
+            .with_raw_json_spec(|app, raw| {
+                match serde_json::from_value(raw) {
+                    Ok(raw) => spec = raw,
+                    Err(err) => {
+                        log::error!("Can't prepare API spec for management listener: {err}")
                     }
-                });
+                }
+                app
+            });
+        spec
+    }
+
+    /// Data shared by all app instances, with switch of configured maintenance mode
+    pub(super) fn shared_data<T>(&self, app_data: T) -> SharedData<T> {
+        SharedData::new(app_data, self.maintenance.clone().unwrap_or_default())
+    }
+
+    fn middlewares_at(&self, position: MiddlewarePosition) -> Condition<CustomMiddlewares> {
+        let selected: Vec<_> = self
+            .middlewares
+            .iter()
+            .filter(|(pos, _)| *pos == position)
+            .map(|(_, middleware)| middleware.clone())
+            .collect();
+        Condition::new(!selected.is_empty(), CustomMiddlewares(selected))
+    }
+}
+
+/// Data shared by all app instances
+pub(super) struct SharedData<T> {
+    pub(super) app_data: web::Data<T>,
+    pub(super) stats: web::Data<BaseStats>,
+    pub(super) shutdown: web::Data<Shutdown>,
+    pub(super) tasks: web::Data<TaskStates>,
+    pub(super) jobs: web::Data<JobStates>,
+    pub(super) maintenance: web::Data<Maintenance>,
+}
+
+impl<T> SharedData<T> {
+    pub(super) fn new(app_data: T, maintenance: Maintenance) -> Self {
+        Self {
+            app_data: web::Data::new(app_data),
+            stats: web::Data::new(BaseStats::default()),
+            shutdown: web::Data::new(Shutdown::default()),
+            tasks: web::Data::new(TaskStates::default()),
+            jobs: web::Data::new(JobStates::default()),
+            maintenance: web::Data::new(maintenance),
+        }
+    }
+}
+
+impl<T> Clone for SharedData<T> {
+    fn clone(&self) -> Self {
+        Self {
+            app_data: self.app_data.clone(),
+            stats: self.stats.clone(),
+            shutdown: self.shutdown.clone(),
+            tasks: self.tasks.clone(),
+            jobs: self.jobs.clone(),
+            maintenance: self.maintenance.clone(),
+        }
+    }
+}
+
+/// Registers internal endpoints: healthcheck, readiness, stats and metrics
+fn internal_routes<T, D>(cfg: &mut actix_web::web::ServiceConfig)
+where
+    D: AppDataWrapper + 'static,
+    T: StatsPresenter<D> + 'static,
+{
+    cfg.route(
+        "_healthcheck",
+        actix_web::web::get().to(default_healthcheck_handler),
+    )
+    .route(
+        "_ready",
+        actix_web::web::get().to(default_readiness_handler::<T, D>),
+    )
+    .route(
+        "_stats",
+        actix_web::web::get().to(default_stats_handler::<T, D>),
+    );
+
+    #[cfg(feature = "prometheus")]
+    cfg.route(
+        "_prometheus",
+        actix_web::web::get().to(super::prometheus::prometheus_stats_handler::<T, D>),
+    );
+
+    #[cfg(feature = "metrics")]
+    cfg.route(
+        "metrics",
+        actix_web::web::get().to(super::metrics::handler::metrics),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_cors::Cors;
+    use actix_web::{Error, HttpResponse, http::StatusCode, test};
+    use std::future::{Future, ready};
+    use std::pin::Pin;
+    use std::time::Duration;
 
-            #[cfg(feature = "tracing")]
-            let app = app.wrap(tracing_actix_web::TracingLogger::<
-                super::tracing::TracingSpanBuilder,
-            >::new());
+    use super::{Maintenance, Serwus, web};
+    use crate::config::{ServerConfig, ServerTuning};
+    use crate::server::stats::StatsPresenter;
 
-            let app = app.wrap(actix_web::middleware::Logger::default());
+    #[derive(Clone)]
+    struct AppData;
 
+    impl StatsPresenter<()> for AppData {
+        fn is_ready(&self) -> Pin<Box<dyn Future<Output = Result<bool, Error>>>> {
+            Box::pin(ready(Ok(true)))
+        }
+
+        fn get_stats(&self) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+            Box::pin(ready(Ok(())))
+        }
+    }
+
+    #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+    async fn hello() -> Result<HttpResponse, Error> {
+        Ok(HttpResponse::Ok().body("hello"))
+    }
+
+    fn configure_app(cfg: &mut web::ServiceConfig) {
+        cfg.route("/hello", web::get().to(hello));
+    }
+
+    #[actix_web::test]
+    async fn applies_tuning_from_config() {
+        let config = ServerConfig {
+            test: true,
+            tuning: ServerTuning {
+                backlog: Some(128),
+                keep_alive: Some(Duration::from_secs(75)),
+                shutdown_timeout: Some(Duration::from_secs(10)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let tuning = Serwus::from_config(&config).tuning;
+        assert_eq!(tuning.workers, Some(2));
+        assert_eq!(tuning.max_blocking_threads, Some(2));
+        assert_eq!(tuning.backlog, Some(128));
+        assert_eq!(tuning.keep_alive, Some(Duration::from_secs(75)));
+        assert_eq!(tuning.shutdown_timeout, Some(Duration::from_secs(10)));
+
+        let config = ServerConfig {
+            tuning: ServerTuning {
+                workers: Some(3),
+                ..Default::default()
+            },
+            ..config
+        };
+        assert_eq!(Serwus::from_config(&config).tuning.workers, Some(3));
+    }
+
+    #[actix_web::test]
+    async fn serves_internal_endpoints_only_on_management_listener() {
+        let serwus = Serwus::default()
+            .enable_log_level_endpoint()
+            .set_management_port(9000);
+        #[cfg(feature = "swagger")]
+        let serwus = Serwus {
+            swagger_spec: serwus.api_spec(configure_app),
+            ..serwus
+        };
+        let shared = serwus.shared_data(AppData);
+
+        let app = test::init_service(serwus.build_app::<(), AppData, _>(
+            &shared,
+            false,
+            configure_app,
+            Cors::default(),
+        ))
+        .await;
+        let management =
+            test::init_service(serwus.build_management_app::<(), AppData>(&shared)).await;
+
+        for path in [
+            "/_healthcheck",
+            "/_ready",
+            "/_stats",
+            "/_loglevel",
             #[cfg(feature = "swagger")]
-            let app = app.build();
+            "/swagger_spec",
+        ] {
+            let req = test::TestRequest::get().uri(path).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
 
-            app
-        })
-        .workers(numthreads)
-        .bind(format!("0.0.0.0:{}", self.app_port))
-        .expect("Can't bind")
-        .run()
-        .await
+            let req = test::TestRequest::get().uri(path).to_request();
+            let res = test::call_service(&management, req).await;
+            assert_eq!(res.status(), StatusCode::OK, "{path}");
+        }
+
+        let req = test::TestRequest::get().uri("/hello").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        #[cfg(feature = "swagger")]
+        {
+            let req = test::TestRequest::get().uri("/swagger_spec").to_request();
+            let spec = test::call_and_read_body(&management, req).await;
+            assert!(String::from_utf8_lossy(&spec).contains("/hello"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn adds_maintenance_endpoint_only_when_enabled() {
+        for (serwus, expected) in [
+            (
+                Serwus::default().set_maintenance(Maintenance::default()),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                Serwus::default()
+                    .set_maintenance(Maintenance::default())
+                    .enable_maintenance_endpoint(),
+                StatusCode::OK,
+            ),
+        ] {
+            #[cfg(feature = "swagger")]
+            let serwus = Serwus {
+                swagger_spec: serwus.api_spec(configure_app),
+                ..serwus
+            };
+            let shared = serwus.shared_data(AppData);
+            let app = test::init_service(serwus.build_app::<(), AppData, _>(
+                &shared,
+                true,
+                configure_app,
+                Cors::default(),
+            ))
+            .await;
+
+            let req = test::TestRequest::get().uri("/_maintenance").to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), expected);
+        }
     }
 }
@@ -0,0 +1,427 @@
+//! Response compression negotiated per request
+//!
+//! [Compression] wraps actix `Compress` middleware, limiting it to chosen algorithms
+//! and skipping bodies smaller than `min_size`. It can be set globally with
+//! [Serwus::set_compression](super::Serwus::set_compression) and overridden per scope:
+//! the innermost [Compression] decides, outer ones leave its response intact.
+//! Every [Compression] negotiates with `Accept-Encoding` sent by client (which reaches
+//! handlers unchanged), so an override can also enable algorithms missing in the global one:
+//!
+//! ```no_run
+//! # use actix_web::http::header::ContentEncoding;
+//! # use serwus::{EmptyStats, server::{Serwus, compression::Compression, default_cors}, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+//! # async fn export() -> &'static str { "" }
+//! # #[actix_web::main]
+//! # async fn main() -> std::io::Result<()> {
+//! Serwus::default()
+//!     .set_compression(Compression::default().encodings(&[ContentEncoding::Gzip]))
+//!     .start(|| AppData, |cfg| {
+//!         cfg.service(
+//!             web::scope("/export")
+//!                 .wrap(Compression::default().encodings(&[ContentEncoding::Zstd]))
+//!                 .route("", web::get().to(export)),
+//!         )
+//!         .service(web::scope("/events").wrap(Compression::disabled()));
+//!     }, default_cors)
+//!     .await
+//! # }
+//! ```
+
+use std::future::{Future, Ready, ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    Error, HttpMessage,
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, ContentEncoding, HeaderValue},
+    middleware::Compress,
+};
+
+/// Actix `Compress` middleware, its type is not exported
+type CompressService<S> = <Compress as Transform<SkipMiddleware<S>, ServiceRequest>>::Transform;
+
+/// Request extension keeping `Accept-Encoding` sent by client while `Compress` negotiates
+/// with filtered one, restored before request reaches handler
+struct OriginalAcceptEncoding(Option<HeaderValue>);
+
+/// Response extension set when compression has been decided by some [Compression]
+struct Negotiated;
+
+/// Response extension set when `Content-Encoding: identity` was added only to stop `Compress`
+struct TemporaryIdentity;
+
+/// Compression middleware, see [module docs](self)
+#[derive(Clone, Debug)]
+pub struct Compression {
+    encodings: Vec<ContentEncoding>,
+    min_size: u64,
+}
+
+impl Default for Compression {
+    /// Brotli, zstd and gzip for bodies of at least 1 KiB
+    fn default() -> Self {
+        Self {
+            encodings: vec![
+                ContentEncoding::Brotli,
+                ContentEncoding::Zstd,
+                ContentEncoding::Gzip,
+            ],
+            min_size: 1024,
+        }
+    }
+}
+
+impl Compression {
+    /// Turns compression off, f. ex. for scope with already compressed or streamed content
+    pub fn disabled() -> Self {
+        Self {
+            encodings: Vec::new(),
+            min_size: 0,
+        }
+    }
+
+    /// Sets allowed algorithms, client preference decides which one is used
+    pub fn encodings(mut self, encodings: &[ContentEncoding]) -> Self {
+        self.encodings = encodings
+            .iter()
+            .copied()
+            .filter(|encoding| *encoding != ContentEncoding::Identity)
+            .collect();
+        self
+    }
+
+    /// Sets minimal size of body to compress, bodies of unknown size (streams) are always compressed
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Leaves only allowed algorithms (and `identity`) of ones accepted by client in `Accept-Encoding`,
+    /// `*` stands for allowed algorithms not listed by client
+    fn filter_accept_encoding(&self, req: &mut ServiceRequest) {
+        let original = req.headers().get(ACCEPT_ENCODING).cloned();
+        req.extensions_mut()
+            .insert(OriginalAcceptEncoding(original.clone()));
+
+        let Some(accepted) = original.as_ref().and_then(|value| value.to_str().ok()) else {
+            return;
+        };
+
+        let codings: Vec<(&str, &str)> = accepted
+            .split(',')
+            .map(str::trim)
+            .map(|item| match item.split_once(';') {
+                Some((coding, params)) => (coding.trim(), params),
+                None => (item, ""),
+            })
+            .collect();
+        let listed = |encoding: &ContentEncoding| {
+            codings
+                .iter()
+                .any(|(coding, _)| coding.eq_ignore_ascii_case(encoding.as_str()))
+        };
+
+        let mut filtered = Vec::new();
+        for (coding, params) in &codings {
+            let params = if params.is_empty() {
+                String::new()
+            } else {
+                format!(";{params}")
+            };
+
+            if *coding == "*" {
+                for encoding in self.encodings.iter().filter(|encoding| !listed(encoding)) {
+                    filtered.push(format!("{}{params}", encoding.as_str()));
+                }
+            } else if coding.eq_ignore_ascii_case("identity")
+                || self
+                    .encodings
+                    .iter()
+                    .any(|encoding| coding.eq_ignore_ascii_case(encoding.as_str()))
+            {
+                filtered.push(format!("{coding}{params}"));
+            }
+        }
+
+        match HeaderValue::from_str(&filtered.join(", ")) {
+            Ok(value) if !filtered.is_empty() => {
+                req.headers_mut().insert(ACCEPT_ENCODING, value);
+            }
+            _ => {
+                req.headers_mut().remove(ACCEPT_ENCODING);
+            }
+        }
+    }
+}
+
+/// Puts back `Accept-Encoding` sent by client, so handlers (f. ex. serving precompressed files)
+/// see it intact
+fn restore_accept_encoding(req: &mut ServiceRequest) {
+    let Some(OriginalAcceptEncoding(original)) =
+        req.extensions_mut().remove::<OriginalAcceptEncoding>()
+    else {
+        return;
+    };
+
+    let headers = req.headers_mut();
+    match original {
+        Some(value) => {
+            headers.insert(ACCEPT_ENCODING, value);
+        }
+        None => {
+            headers.remove(ACCEPT_ENCODING);
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Compression
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = <CompressService<S> as Service<ServiceRequest>>::Response;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CompressionMiddleware<CompressService<S>>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let config = Rc::new(self.clone());
+        let skip = SkipMiddleware {
+            service,
+            config: config.clone(),
+        };
+
+        // Compress transform is always ready
+        let compress = match Compress::default().new_transform(skip).into_inner() {
+            Ok(compress) => compress,
+            Err(_) => unreachable!("Compress can't fail to initialize"),
+        };
+
+        ready(Ok(CompressionMiddleware {
+            service: compress,
+            config,
+        }))
+    }
+}
+
+/// CompressionMiddleware negotiates algorithm and cleans up after [SkipMiddleware]
+pub struct CompressionMiddleware<C> {
+    service: C,
+    config: Rc<Compression>,
+}
+
+impl<C, B> Service<ServiceRequest> for CompressionMiddleware<C>
+where
+    C: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    C::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        self.config.filter_accept_encoding(&mut req);
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+
+            let temporary = res
+                .response_mut()
+                .extensions_mut()
+                .remove::<TemporaryIdentity>()
+                .is_some();
+            if temporary {
+                res.headers_mut().remove(CONTENT_ENCODING);
+            }
+            res.response_mut().extensions_mut().insert(Negotiated);
+
+            Ok(res)
+        })
+    }
+}
+
+/// SkipMiddleware stops `Compress` for small responses and ones handled by inner [Compression]
+pub struct SkipMiddleware<S> {
+    service: S,
+    config: Rc<Compression>,
+}
+
+impl<S, B> Service<ServiceRequest> for SkipMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        restore_accept_encoding(&mut req);
+
+        let min_size = self.config.min_size;
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+
+            let negotiated = res.response().extensions().contains::<Negotiated>();
+            let small =
+                matches!(res.response().body().size(), BodySize::Sized(size) if size < min_size);
+
+            if (negotiated || small) && !res.headers().contains_key(CONTENT_ENCODING) {
+                res.headers_mut()
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static("identity"));
+                res.response_mut()
+                    .extensions_mut()
+                    .insert(TemporaryIdentity);
+            }
+
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        App, HttpRequest, HttpResponse,
+        http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, ContentEncoding},
+        test, web,
+    };
+
+    use super::Compression;
+
+    async fn large() -> HttpResponse {
+        HttpResponse::Ok().body("a".repeat(4096))
+    }
+
+    async fn small() -> HttpResponse {
+        HttpResponse::Ok().body("a")
+    }
+
+    async fn accepted(req: HttpRequest) -> HttpResponse {
+        HttpResponse::Ok()
+            .insert_header(("x-accepted", req.headers().get(ACCEPT_ENCODING).unwrap()))
+            .body("a".repeat(4096))
+    }
+
+    #[actix_web::test]
+    async fn negotiates_allowed_encodings() {
+        let app = test::init_service(
+            App::new()
+                .route("/large", web::get().to(large))
+                .route("/small", web::get().to(small))
+                .service(
+                    web::scope("/plain")
+                        .wrap(Compression::disabled())
+                        .route("", web::get().to(large)),
+                )
+                .wrap(Compression::default().encodings(&[ContentEncoding::Gzip])),
+        )
+        .await;
+
+        let encoding = |uri: &'static str| {
+            let app = &app;
+            async move {
+                let req = test::TestRequest::get()
+                    .uri(uri)
+                    .insert_header((ACCEPT_ENCODING, "br, gzip;q=0.5"))
+                    .to_request();
+                let res = test::call_service(app, req).await;
+                res.headers()
+                    .get(CONTENT_ENCODING)
+                    .map(|value| value.to_str().unwrap().to_string())
+            }
+        };
+
+        assert_eq!(encoding("/large").await.as_deref(), Some("gzip"));
+        assert_eq!(encoding("/small").await, None);
+        assert_eq!(encoding("/plain").await, None);
+    }
+
+    #[actix_web::test]
+    async fn scope_overrides_global_encodings() {
+        let app = test::init_service(
+            App::new()
+                .route("/large", web::get().to(large))
+                .service(
+                    web::scope("/export")
+                        .wrap(Compression::default().encodings(&[ContentEncoding::Brotli]))
+                        .route("", web::get().to(large)),
+                )
+                .service(
+                    web::scope("/gzip")
+                        .wrap(Compression::default().encodings(&[ContentEncoding::Gzip]))
+                        .route("", web::get().to(large)),
+                )
+                .wrap(Compression::default().encodings(&[ContentEncoding::Gzip])),
+        )
+        .await;
+
+        let encoding = |uri: &'static str, accepted: &'static str| {
+            let app = &app;
+            async move {
+                let req = test::TestRequest::get()
+                    .uri(uri)
+                    .insert_header((ACCEPT_ENCODING, accepted))
+                    .to_request();
+                let res = test::call_service(app, req).await;
+                res.headers()
+                    .get(CONTENT_ENCODING)
+                    .map(|value| value.to_str().unwrap().to_string())
+            }
+        };
+
+        // Brotli is enabled only in scope, though global Compression doesn't allow it
+        assert_eq!(
+            encoding("/export", "br, gzip;q=0.5").await.as_deref(),
+            Some("br")
+        );
+        assert_eq!(
+            encoding("/large", "br, gzip;q=0.5").await.as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(encoding("/export", "gzip").await, None);
+        assert_eq!(encoding("/gzip", "gzip").await.as_deref(), Some("gzip"));
+    }
+
+    #[actix_web::test]
+    async fn leaves_accept_encoding_intact_for_handlers() {
+        let app = test::init_service(
+            App::new()
+                .route("/accepted", web::get().to(accepted))
+                .wrap(Compression::default().encodings(&[ContentEncoding::Gzip])),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/accepted")
+            .insert_header((ACCEPT_ENCODING, "br, *;q=0.5"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        // Gzip is chosen thanks to wildcard, handler gets header sent by client
+        assert_eq!(res.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(res.headers().get("x-accepted").unwrap(), "br, *;q=0.5");
+    }
+}
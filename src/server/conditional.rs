@@ -0,0 +1,350 @@
+//! Conditional requests with `ETag` and `Last-Modified`
+//!
+//! [ConditionalGet] middleware adds strong `ETag` (hash of body) to successful JSON responses
+//! of `GET` and `HEAD` requests and answers `If-None-Match` and `If-Modified-Since` with 304,
+//! so clients polling unchanged data don't download it again. It is enabled for whole app
+//! with [Serwus::enable_conditional_get](super::Serwus::enable_conditional_get) or can wrap
+//! chosen scopes.
+//!
+//! When handler sets `ETag` itself (f. ex. from version column with [version_etag]) body
+//! is not hashed. `Last-Modified` set by handler is compared with `If-Modified-Since`:
+//!
+//! ```no_run
+//! # use std::time::SystemTime;
+//! # use actix_web::{HttpResponse, http::header::{ETag, LastModified}};
+//! # use chrono::{DateTime, Utc};
+//! # use serde::Serialize;
+//! # use serwus::{server::conditional::version_etag, web};
+//! # #[derive(Serialize)]
+//! # struct Article { version: i64, updated_at: DateTime<Utc> }
+//! # async fn load_article(_id: i64) -> Article { Article { version: 1, updated_at: Utc::now() } }
+//! async fn get_article(id: web::Path<i64>) -> HttpResponse {
+//!     let article = load_article(id.into_inner()).await;
+//!
+//!     HttpResponse::Ok()
+//!         .insert_header(ETag(version_etag(article.version)))
+//!         .insert_header(LastModified(SystemTime::from(article.updated_at).into()))
+//!         .json(article)
+//! }
+//! ```
+//!
+//! Writes can be guarded against lost updates with [check_if_match], which returns 412
+//! [JsonError] when `If-Match` doesn't match current version.
+
+use std::fmt::Display;
+use std::future::{Future, Ready, ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    Error, HttpRequest, HttpResponse,
+    body::{self, BoxBody, EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        Method, StatusCode,
+        header::{
+            CACHE_CONTROL, CONTENT_LOCATION, CONTENT_TYPE, DATE, ETAG, EXPIRES, EntityTag, Header,
+            HeaderMap, HttpDate, IF_MATCH, IfMatch, IfModifiedSince, IfNoneMatch, LAST_MODIFIED,
+            VARY,
+        },
+    },
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::json_error::{ErrorBuilder, JsonError};
+
+/// Headers kept in 304 response
+const NOT_MODIFIED_HEADERS: [actix_web::http::header::HeaderName; 7] = [
+    ETAG,
+    LAST_MODIFIED,
+    CACHE_CONTROL,
+    CONTENT_LOCATION,
+    DATE,
+    EXPIRES,
+    VARY,
+];
+
+/// Strong ETag of given bytes
+pub fn content_etag(bytes: &[u8]) -> EntityTag {
+    let hash = Sha256::digest(bytes);
+    let hex: String = hash[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    EntityTag::new_strong(hex)
+}
+
+/// Strong ETag of value serialized as JSON, equal to the one [ConditionalGet] adds
+/// to `HttpResponse::Ok().json(value)`
+pub fn json_etag(value: &impl Serialize) -> EntityTag {
+    content_etag(&serde_json::to_vec(value).unwrap_or_default())
+}
+
+/// Strong ETag made from version, f. ex. revision number or update time
+/// (hashed, so any value can be used)
+pub fn version_etag(version: impl Display) -> EntityTag {
+    content_etag(version.to_string().as_bytes())
+}
+
+/// Checks `If-Match` header of request against current ETag of resource.
+///
+/// Passes when header is missing, is `*` or contains matching ETag, otherwise
+/// returns 412 [JsonError] (`PreconditionFailed`).
+#[allow(clippy::result_large_err)] // Returned straight from handlers, like other JsonErrors
+pub fn check_if_match(req: &HttpRequest, current: &EntityTag) -> Result<(), JsonError> {
+    if !req.headers().contains_key(IF_MATCH) {
+        return Ok(());
+    }
+
+    let matches = match IfMatch::parse(req) {
+        Ok(IfMatch::Any) => true,
+        Ok(IfMatch::Items(tags)) => tags.iter().any(|tag| tag.strong_eq(current)),
+        Err(_) => false,
+    };
+
+    match matches {
+        true => Ok(()),
+        false => Err(ErrorBuilder::precondition_failed(format!(
+            "If-Match doesn't match current ETag {current}"
+        ))
+        .finish()),
+    }
+}
+
+/// Validators sent by client
+struct Conditions {
+    if_none_match: Option<IfNoneMatch>,
+    if_modified_since: Option<HttpDate>,
+}
+
+impl Conditions {
+    fn from_request(req: &HttpRequest) -> Self {
+        Self {
+            if_none_match: IfNoneMatch::parse(req).ok().filter(|header| match header {
+                IfNoneMatch::Any => true,
+                IfNoneMatch::Items(tags) => !tags.is_empty(),
+            }),
+            if_modified_since: IfModifiedSince::parse(req).ok().map(|header| header.0),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.if_none_match.is_none() && self.if_modified_since.is_none()
+    }
+
+    /// `If-None-Match` takes precedence, `If-Modified-Since` is used only without it
+    fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            let etag = headers
+                .get(ETAG)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<EntityTag>().ok());
+
+            return match (if_none_match, etag) {
+                (IfNoneMatch::Any, _) => true,
+                (IfNoneMatch::Items(tags), Some(etag)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+                (IfNoneMatch::Items(_), None) => false,
+            };
+        }
+
+        let last_modified = headers
+            .get(LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<HttpDate>().ok());
+
+        match (self.if_modified_since, last_modified) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+fn not_modified_response(headers: &HeaderMap) -> HttpResponse {
+    let mut res = HttpResponse::NotModified();
+    for name in NOT_MODIFIED_HEADERS {
+        for value in headers.get_all(&name) {
+            res.append_header((name.clone(), value.clone()));
+        }
+    }
+    res.finish()
+}
+
+/// Conditional GET middleware, see [module docs](self)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConditionalGet;
+
+impl<S, B> Transform<S, ServiceRequest> for ConditionalGet
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ConditionalGetMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ConditionalGetMiddleware { service }))
+    }
+}
+
+/// ConditionalGetMiddleware adds ETags and responds with 304 to fresh conditional requests
+pub struct ConditionalGetMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ConditionalGetMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let fut = self.service.call(req);
+            return Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) });
+        }
+
+        let conditions = Conditions::from_request(req.request());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+
+            if res.status() != StatusCode::OK {
+                return Ok(res.map_into_left_body());
+            }
+
+            if res.headers().contains_key(ETAG) || !is_json(res.headers()) {
+                if conditions.is_empty() || !conditions.not_modified(res.headers()) {
+                    return Ok(res.map_into_left_body());
+                }
+                let not_modified = not_modified_response(res.headers());
+                return Ok(res.into_response(not_modified).map_into_right_body());
+            }
+
+            let (req, res) = res.into_parts();
+            let (mut res, body) = res.into_parts();
+            let body = body::to_bytes(body)
+                .await
+                .map_err(|err| actix_web::error::ErrorInternalServerError(err.into()))?;
+
+            if let Ok(etag) = content_etag(&body).to_string().parse() {
+                res.headers_mut().insert(ETAG, etag);
+            }
+
+            let res = match conditions.not_modified(res.headers()) {
+                true => not_modified_response(res.headers()),
+                false => res.set_body(BoxBody::new(body)),
+            };
+
+            Ok(ServiceResponse::new(req, res).map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use actix_web::{
+        App, HttpRequest, HttpResponse,
+        http::{
+            StatusCode,
+            header::{self, ETag, HttpDate, LastModified},
+        },
+        test, web,
+    };
+
+    use super::{ConditionalGet, check_if_match, json_etag, version_etag};
+    use crate::server::json_error::JsonError;
+
+    fn modified_at() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    async fn article() -> HttpResponse {
+        HttpResponse::Ok()
+            .insert_header(ETag(version_etag(7)))
+            .insert_header(LastModified(modified_at().into()))
+            .json(["article"])
+    }
+
+    async fn update_article(req: HttpRequest) -> Result<HttpResponse, JsonError> {
+        check_if_match(&req, &version_etag(7))?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    #[actix_web::test]
+    async fn answers_conditional_requests() {
+        let app = test::init_service(
+            App::new()
+                .route(
+                    "/list",
+                    web::get().to(|| async { HttpResponse::Ok().json([1, 2, 3]) }),
+                )
+                .route("/article", web::get().to(article))
+                .route("/article", web::put().to(update_article))
+                .wrap(ConditionalGet),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/list").to_request();
+        let res = test::call_service(&app, req).await;
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        assert_eq!(etag.to_str().unwrap(), json_etag(&[1, 2, 3]).to_string());
+
+        let req = test::TestRequest::get()
+            .uri("/list")
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert!(res.headers().contains_key(header::ETAG));
+        assert!(test::read_body(res).await.is_empty());
+
+        let since = HttpDate::from(modified_at() + Duration::from_secs(60));
+        let req = test::TestRequest::get()
+            .uri("/article")
+            .insert_header((header::IF_MODIFIED_SINCE, since.to_string()))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let req = test::TestRequest::put()
+            .uri("/article")
+            .insert_header((header::IF_MATCH, version_etag(6).to_string()))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["type"], "PreconditionFailed");
+
+        let req = test::TestRequest::put()
+            .uri("/article")
+            .insert_header((header::IF_MATCH, version_etag(7).to_string()))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+}
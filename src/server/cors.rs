@@ -0,0 +1,287 @@
+//! CORS policy from configuration
+//!
+//! [CorsConfig] describes which origins may call the service, either set in code or loaded
+//! with [ServerConfig](crate::config::ServerConfig) from `CORS_*` keys:
+//!
+//! ```toml
+//! [cors]
+//! allowed_origins = ["https://app.example.com"]
+//! allowed_origin_suffixes = [".preview.example.com"]
+//! allowed_origin_regex = "https://pr-[0-9]+\\.example\\.dev"
+//! exposed_headers = ["x-request-id"]
+//! allow_credentials = true
+//! max_age_secs = 600
+//! ```
+//!
+//! Policy is checked when loading config (or in [factory](CorsConfig::factory)), so service
+//! with wildcard origin and credentials or malformed origin doesn't start at all:
+//!
+//! ```no_run
+//! # use serwus::{EmptyStats, config::ServerConfig, server::Serwus, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # fn configure_app(_cfg: &mut web::ServiceConfig) {}
+//! # #[actix_web::main]
+//! # async fn main() -> std::io::Result<()> {
+//! let config = ServerConfig::load()?;
+//! let cors = config.cors.clone().factory()?;
+//!
+//! Serwus::from_config(&config).start(|| AppData, configure_app, cors).await
+//! # }
+//! ```
+//!
+//! By default no cross-origin requests are allowed.
+
+use std::io;
+use std::time::Duration;
+
+use actix_cors::Cors;
+use actix_web::http::{
+    Method, Uri,
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, HeaderName},
+};
+use regex::Regex;
+
+/// Allows any origin when put in [allowed_origins](CorsConfig::allowed_origins)
+pub const ANY_ORIGIN: &str = "*";
+
+/// CORS policy, see [module docs](self)
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    /// Exact origins like `https://app.example.com`, or `*` for any (`CORS_ALLOWED_ORIGINS`)
+    pub allowed_origins: Vec<String>,
+    /// Host suffixes starting with dot, `.example.com` allows `https://app.example.com`
+    /// but not `https://example.com` nor `http://app.example.com` (only `https` origins match,
+    /// list plain http ones in `allowed_origins`) (`CORS_ALLOWED_ORIGIN_SUFFIXES`)
+    pub allowed_origin_suffixes: Vec<String>,
+    /// Pattern matched against whole origin, see [origin_regex] (`CORS_ALLOWED_ORIGIN_REGEX`)
+    pub allowed_origin_regex: Option<Regex>,
+    /// Defaults to `GET`, `POST`, `PUT`, `DELETE`, `OPTIONS` (`CORS_ALLOWED_METHODS`)
+    pub allowed_methods: Vec<Method>,
+    /// Defaults to `Authorization`, `Accept`, `Content-Type` (`CORS_ALLOWED_HEADERS`)
+    pub allowed_headers: Vec<HeaderName>,
+    /// Response headers readable by browser scripts (`CORS_EXPOSED_HEADERS`)
+    pub exposed_headers: Vec<HeaderName>,
+    /// Allows cookies and `Authorization` in cross-origin requests (`CORS_ALLOW_CREDENTIALS`)
+    pub allow_credentials: bool,
+    /// How long preflight response may be cached, defaults to 1 hour (`CORS_MAX_AGE_SECS`)
+    pub max_age: Option<Duration>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_origin_suffixes: Vec::new(),
+            allowed_origin_regex: None,
+            allowed_methods: vec![
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::DELETE,
+                Method::OPTIONS,
+            ],
+            allowed_headers: vec![AUTHORIZATION, ACCEPT, CONTENT_TYPE],
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+
+/// Compiles pattern matching whole origin (implicitly wrapped in `^(?:...)$`)
+pub fn origin_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{pattern})$"))
+}
+
+impl CorsConfig {
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|origin| origin == ANY_ORIGIN)
+    }
+
+    /// Checks if origin matches one of suffixes or regex (exact origins are checked by [Cors])
+    fn matches_pattern(&self, origin: &str) -> bool {
+        if self
+            .allowed_origin_regex
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(origin))
+        {
+            return true;
+        }
+
+        // Suffix covers every subdomain, so only origins served over https are trusted
+        let Some(host) = origin
+            .parse::<Uri>()
+            .ok()
+            .filter(|uri| uri.scheme_str() == Some("https"))
+            .and_then(|uri| uri.host().map(str::to_ascii_lowercase))
+        else {
+            return false;
+        };
+
+        self.allowed_origin_suffixes
+            .iter()
+            .any(|suffix| host.ends_with(&suffix.to_ascii_lowercase()))
+    }
+
+    /// Problems with policy as pairs of config key and message
+    pub(crate) fn problems(&self) -> Vec<(&'static str, String)> {
+        let mut problems = Vec::new();
+
+        if self.allow_credentials && self.allows_any_origin() {
+            problems.push((
+                "CORS_ALLOW_CREDENTIALS",
+                "can't be used with wildcard origin, list allowed origins instead".to_string(),
+            ));
+        }
+
+        for origin in &self.allowed_origins {
+            if origin != ANY_ORIGIN && !is_valid_origin(origin) {
+                problems.push((
+                    "CORS_ALLOWED_ORIGINS",
+                    format!("{origin:?} is not valid origin, expected scheme://host[:port]"),
+                ));
+            }
+        }
+
+        for suffix in &self.allowed_origin_suffixes {
+            if !suffix.starts_with('.') || suffix.len() < 2 {
+                problems.push((
+                    "CORS_ALLOWED_ORIGIN_SUFFIXES",
+                    format!("{suffix:?} has to start with dot, f. ex. \".example.com\""),
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Checks policy, returning all problems in one message
+    pub fn validate(&self) -> Result<(), String> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let messages: Vec<_> = problems
+            .into_iter()
+            .map(|(key, message)| format!("{key}: {message}"))
+            .collect();
+        Err(format!("Invalid CORS policy: {}", messages.join(", ")))
+    }
+
+    /// Builds [Cors] middleware, expects [validated](Self::validate) policy
+    pub fn build(&self) -> Cors {
+        let mut cors = Cors::default()
+            .allowed_methods(self.allowed_methods.clone())
+            .allowed_headers(self.allowed_headers.clone())
+            .max_age(self.max_age.map(|max_age| max_age.as_secs() as usize));
+
+        if !self.exposed_headers.is_empty() {
+            cors = cors.expose_headers(self.exposed_headers.clone());
+        }
+
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+
+        if self.allows_any_origin() {
+            return cors.allow_any_origin().send_wildcard();
+        }
+
+        for origin in &self.allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+
+        if self.allowed_origin_regex.is_some() || !self.allowed_origin_suffixes.is_empty() {
+            let policy = self.clone();
+            cors = cors.allowed_origin_fn(move |origin, _| {
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| policy.matches_pattern(origin))
+            });
+        }
+
+        cors
+    }
+
+    /// Validates policy and returns factory to be passed to [Serwus::start](super::Serwus::start)
+    pub fn factory(self) -> io::Result<impl Fn() -> Cors + Send + Clone + 'static> {
+        self.validate()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(move || self.build())
+    }
+}
+
+fn is_valid_origin(origin: &str) -> bool {
+    origin.parse::<Uri>().is_ok_and(|uri| {
+        uri.scheme().is_some()
+            && uri.host().is_some_and(|host| !host.is_empty())
+            && matches!(uri.path(), "" | "/")
+            && uri.query().is_none()
+            && !origin.ends_with('/')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpResponse, http::header, test, web};
+
+    use super::{ANY_ORIGIN, CorsConfig, origin_regex};
+
+    #[actix_web::test]
+    async fn allows_configured_origins_only() {
+        let cors = CorsConfig {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            allowed_origin_suffixes: vec![".preview.example.com".to_string()],
+            allowed_origin_regex: Some(origin_regex(r"https://pr-\d+\.example\.dev").unwrap()),
+            allow_credentials: true,
+            ..Default::default()
+        };
+        let factory = cors.factory().unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(factory())
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (origin, allowed) in [
+            ("https://app.example.com", true),
+            ("https://pr-1.preview.example.com", true),
+            ("http://pr-1.preview.example.com", false),
+            ("https://pr-42.example.dev", true),
+            ("https://pr-42.example.dev.evil.com", false),
+            ("https://preview.example.com", false),
+            ("https://evil.com", false),
+        ] {
+            let req = test::TestRequest::get()
+                .uri("/")
+                .insert_header((header::ORIGIN, origin))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            let allow_origin = res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN);
+            assert_eq!(
+                allow_origin.is_some_and(|value| value == origin),
+                allowed,
+                "{origin}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_wildcard_with_credentials() {
+        let cors = CorsConfig {
+            allowed_origins: vec![ANY_ORIGIN.to_string(), "example.com".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        };
+
+        let keys: Vec<_> = cors.problems().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["CORS_ALLOW_CREDENTIALS", "CORS_ALLOWED_ORIGINS"]);
+        assert!(cors.factory().is_err());
+    }
+}
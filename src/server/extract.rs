@@ -0,0 +1,387 @@
+//! Extractor configs turning payload errors into structured JsonErrors
+//!
+//! Error handlers are installed by [Serwus](super::Serwus) when [json_errors](super::Serwus::json_errors)
+//! is enabled, size limits are applied always.
+//! Deserialization errors become 400 with `data` describing the problem:
+//!
+//! ```json
+//! {"location": "body", "expected": "a string", "line": 1, "column": 10}
+//! ```
+//!
+//! With `web::Json` `field` and `expected` are taken from serde error message, so they are present
+//! only when the message names them (f. ex. missing or unknown fields, invalid types).
+//! [Json] extractor from this module reports full path of invalid field instead,
+//! f. ex. `items[2].name`. Payloads over the limit become 413.
+
+use std::ops::{Deref, DerefMut};
+
+use actix_web::{
+    Error, FromRequest, HttpRequest,
+    dev::Payload,
+    error::{JsonPayloadError, PathError, QueryPayloadError, UrlencodedError},
+    http::StatusCode,
+    web,
+};
+use futures::future::LocalBoxFuture;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::value::RawValue;
+
+use super::json_error::ErrorBuilder;
+use crate::config::PayloadLimits;
+
+/// Details of extractor error put into JsonError `data`
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ExtractorErrorData {
+    /// Part of request which failed: `body`, `form`, `query` or `path`
+    pub location: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+impl ExtractorErrorData {
+    fn from_message(location: &'static str, message: &str) -> Self {
+        let field = ["missing field `", "unknown field `", "duplicate field `"]
+            .iter()
+            .find_map(|prefix| message.strip_prefix(prefix))
+            .and_then(|rest| rest.split_once('`'))
+            .map(|(field, _)| field.to_string());
+
+        let expected = message
+            .split_once(", expected ")
+            .map(|(_, expected)| expected.to_string());
+
+        Self {
+            location,
+            field,
+            expected,
+            ..Default::default()
+        }
+    }
+}
+
+/// JSON body extractor reporting path of invalid field in JsonError `data`
+///
+/// Works like `web::Json` (same `JsonConfig` limit and error handler), but type errors
+/// and missing fields are reported with `field` pointing into nested structures,
+/// f. ex. `items[2].name`. Such errors are JsonErrors even without
+/// [json_errors](super::Serwus::json_errors).
+///
+/// ```no_run
+/// use serde::Deserialize;
+/// use serwus::{server::extract::Json, web};
+///
+/// #[derive(Deserialize)]
+/// # #[cfg_attr(feature = "swagger", derive(paperclip::actix::Apiv2Schema))]
+/// struct Order {
+///     items: Vec<String>,
+/// }
+///
+/// # #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+/// async fn create(order: Json<Order>) -> web::Json<usize> {
+///     web::Json(order.items.len())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+impl<T> Json<T> {
+    /// Unwraps deserialized value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Json<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        // Limit, content type and syntax are checked by `web::Json`, value is parsed here
+        let raw = web::Json::<Box<RawValue>>::from_request(req, payload);
+
+        Box::pin(async move {
+            let raw = raw.await?.into_inner();
+            let mut deserializer = serde_json::Deserializer::from_str(raw.get());
+
+            serde_path_to_error::deserialize(&mut deserializer)
+                .map(Json)
+                .map_err(|err| {
+                    let path = err.path().to_string();
+                    let path = (path != ".").then_some(path);
+                    deserialize_error(format!("Json deserialize error: {err}"), err.inner(), path)
+                })
+        })
+    }
+}
+
+#[cfg(feature = "swagger")]
+impl<T: paperclip::v2::schema::Apiv2Schema> paperclip::v2::schema::Apiv2Schema for Json<T> {
+    fn name() -> Option<String> {
+        T::name()
+    }
+
+    fn raw_schema() -> paperclip::v2::models::DefaultSchemaRaw {
+        T::raw_schema()
+    }
+}
+
+#[cfg(feature = "swagger")]
+impl<T: paperclip::v2::schema::Apiv2Schema> paperclip::actix::OperationModifier for Json<T> {
+    fn update_parameter(op: &mut paperclip::v2::models::DefaultOperationRaw) {
+        <web::Json<T> as paperclip::actix::OperationModifier>::update_parameter(op);
+    }
+}
+
+/// Registers extractor configs with given limits and, if `json_errors` is set, error handlers
+pub(super) fn configure(cfg: &mut web::ServiceConfig, limits: &PayloadLimits, json_errors: bool) {
+    let mut json = web::JsonConfig::default();
+    let mut form = web::FormConfig::default();
+    let mut payload = web::PayloadConfig::default();
+
+    if let Some(limit) = limits.json {
+        json = json.limit(limit);
+    }
+    if let Some(limit) = limits.form {
+        form = form.limit(limit);
+    }
+    if let Some(limit) = limits.payload {
+        payload = payload.limit(limit);
+    }
+
+    if json_errors {
+        cfg.app_data(json.error_handler(json_error))
+            .app_data(form.error_handler(form_error))
+            .app_data(web::QueryConfig::default().error_handler(query_error))
+            .app_data(web::PathConfig::default().error_handler(path_error));
+    } else {
+        cfg.app_data(json).app_data(form);
+    }
+
+    cfg.app_data(payload);
+}
+
+fn invalid(reason: impl std::fmt::Display, message: &str, data: ExtractorErrorData) -> Error {
+    ErrorBuilder::bad_request(reason)
+        .message(message)
+        .data(data)
+        .finish()
+        .into()
+}
+
+fn too_large(limit: usize) -> Error {
+    ErrorBuilder::payload_too_large(limit).finish().into()
+}
+
+fn unsupported_media_type(reason: impl std::fmt::Display) -> Error {
+    ErrorBuilder::bad_request(reason)
+        .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        .finish()
+        .into()
+}
+
+fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> Error {
+    match err {
+        JsonPayloadError::OverflowKnownLength { limit, .. }
+        | JsonPayloadError::Overflow { limit } => too_large(limit),
+        JsonPayloadError::ContentType => unsupported_media_type(err),
+        JsonPayloadError::Deserialize(ref json_err) => deserialize_error(&err, json_err, None),
+        err => ErrorBuilder::bad_request(err).finish().into(),
+    }
+}
+
+/// Describes JSON deserialization error, `path` leads to value which failed (if known)
+fn deserialize_error(
+    reason: impl std::fmt::Display,
+    json_err: &serde_json::Error,
+    path: Option<String>,
+) -> Error {
+    let full = json_err.to_string();
+    // Position is reported in separate fields
+    let message = match full.rsplit_once(" at line ") {
+        Some((message, _)) if json_err.line() > 0 => message,
+        _ => &full,
+    };
+
+    let mut data = ExtractorErrorData {
+        line: Some(json_err.line()),
+        column: Some(json_err.column()),
+        ..ExtractorErrorData::from_message("body", message)
+    };
+
+    // Missing and unknown fields are named by message relative to their parent
+    if let Some(path) = path {
+        data.field = Some(match data.field {
+            Some(field) => format!("{path}.{field}"),
+            None => path,
+        });
+    }
+
+    invalid(reason, "Invalid JSON body", data)
+}
+
+fn form_error(err: UrlencodedError, _req: &HttpRequest) -> Error {
+    match err {
+        UrlencodedError::Overflow { limit, .. } => too_large(limit),
+        UrlencodedError::ContentType => unsupported_media_type(err),
+        UrlencodedError::Parse(ref parse_err) => {
+            let data = ExtractorErrorData::from_message("form", &parse_err.to_string());
+            invalid(&err, "Invalid form data", data)
+        }
+        err => ErrorBuilder::bad_request(err).finish().into(),
+    }
+}
+
+fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> Error {
+    let data = match &err {
+        QueryPayloadError::Deserialize(de_err) => {
+            ExtractorErrorData::from_message("query", &de_err.to_string())
+        }
+        _ => ExtractorErrorData {
+            location: "query",
+            ..Default::default()
+        },
+    };
+    invalid(&err, "Invalid query parameters", data)
+}
+
+fn path_error(err: PathError, _req: &HttpRequest) -> Error {
+    let data = match &err {
+        PathError::Deserialize(de_err) => {
+            ExtractorErrorData::from_message("path", &de_err.to_string())
+        }
+        _ => ExtractorErrorData {
+            location: "path",
+            ..Default::default()
+        },
+    };
+    invalid(&err, "Invalid path parameters", data)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpResponse, http::StatusCode, test, web};
+    use serde::Deserialize;
+
+    use super::{Json, configure};
+    use crate::config::PayloadLimits;
+
+    #[derive(Deserialize)]
+    struct Item {
+        #[allow(dead_code)]
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Order {
+        #[allow(dead_code)]
+        items: Vec<Item>,
+    }
+
+    async fn create(_item: Json<Item>) -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    async fn order(_order: Json<Order>) -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    async fn legacy(_item: web::Json<Item>) -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn describes_json_errors() {
+        let limits = PayloadLimits {
+            json: Some(64),
+            ..Default::default()
+        };
+
+        let app = test::init_service(
+            App::new()
+                .configure(|cfg| configure(cfg, &limits, true))
+                .route("/", web::post().to(create))
+                .route("/orders", web::post().to(order))
+                .route("/legacy", web::post().to(legacy)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"name": 5}"#)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["data"]["location"], "body");
+        assert_eq!(body["data"]["field"], "name");
+        assert_eq!(body["data"]["expected"], "a string");
+        assert_eq!(body["data"]["line"], 1);
+        assert_eq!(body["data"]["column"], 10);
+
+        let req = test::TestRequest::post()
+            .insert_header(("content-type", "application/json"))
+            .set_payload("{}")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["data"]["field"], "name");
+
+        let req = test::TestRequest::post()
+            .uri("/orders")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"items": [{"name": "a"}, {"name": "b"}, {"name": 5}]}"#)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["data"]["field"], "items[2].name");
+        assert_eq!(body["data"]["expected"], "a string");
+        assert_eq!(body["data"]["column"], 51);
+
+        let req = test::TestRequest::post()
+            .uri("/orders")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"items": [{"name": "a"}, {}]}"#)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["data"]["field"], "items[1].name");
+
+        // `web::Json` knows only field named in serde message
+        let req = test::TestRequest::post()
+            .uri("/legacy")
+            .insert_header(("content-type", "application/json"))
+            .set_payload("{}")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["data"]["field"], "name");
+
+        let req = test::TestRequest::post()
+            .insert_header(("content-type", "application/json"))
+            .set_payload(format!(r#"{{"name": "{}"}}"#, "x".repeat(128)))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
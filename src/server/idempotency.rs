@@ -0,0 +1,773 @@
+//! `Idempotency-Key` support for unsafe requests
+//!
+//! [Idempotency] middleware makes `POST`, `PUT` and `PATCH` requests carrying `Idempotency-Key`
+//! header safe to retry: first response (status, headers and body) is stored and sent again
+//! for every retry with the same key, with `Idempotent-Replayed: true` header. Retry arriving
+//! while first request is still handled gets 409 [JsonError](super::json_error::JsonError).
+//!
+//! Keys are scoped by method and path (and optionally by client, see [Idempotency::scope]).
+//! Retry with the same key but different body gets 422 [JsonError](super::json_error::JsonError).
+//!
+//! Server errors (5xx), transient client errors (408, 409, 425 and 429), errors returned by
+//! handler (instead of response) and requests dropped before completion are not stored,
+//! so they can be retried with the same key. Other client errors (f. ex. 400 or 422) are
+//! replayed like successful responses. Both request and response bodies are buffered,
+//! so don't wrap streaming endpoints.
+//!
+//! Responses are kept in [IdempotencyStore]: [MemoryStore] (per process) or, with `pgsql`
+//! feature, [DieselStore] (shared by all instances). Like [RateLimit](super::rate_limit::RateLimit)
+//! create middleware once and clone it into every worker:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use serwus::{EmptyStats, server::{Serwus, default_cors, idempotency::Idempotency}, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+//! # async fn create_payment() -> &'static str { "{}" }
+//! # #[actix_web::main]
+//! # async fn main() -> std::io::Result<()> {
+//! let idempotency = Idempotency::in_memory(Duration::from_secs(24 * 3600));
+//!
+//! Serwus::default().start(|| AppData, move |cfg| {
+//!     cfg.service(web::scope("/payments").wrap(idempotency.clone()).route("", web::post().to(create_payment)));
+//! }, default_cors).await
+//! # }
+//! ```
+
+use std::future::{Future, Ready, ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    Error, HttpResponse, ResponseError,
+    body::{self, BoxBody, EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        Method, StatusCode,
+        header::{HeaderName, HeaderValue},
+    },
+};
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
+use log::error;
+use sha2::{Digest, Sha256};
+
+use super::json_error::{ErrorBuilder, JsonError};
+use crate::containers::cache_map::CacheMap;
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// Longest accepted key
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Client errors which may pass on retry (timeout, conflict, too early, too many requests),
+/// so their responses are not stored
+const TRANSIENT_ERRORS: [u16; 4] = [408, 409, 425, 429];
+
+type KeyExtractor = dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync;
+
+/// Response stored for replaying
+#[derive(Clone, Debug)]
+pub struct StoredResponse {
+    /// Hash of request body, to detect key reused for different request
+    pub request_hash: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl StoredResponse {
+    fn new<B>(request_hash: String, res: &HttpResponse<B>, body: Bytes) -> Self {
+        Self {
+            request_hash,
+            status: res.status().as_u16(),
+            headers: res
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    value
+                        .to_str()
+                        .ok()
+                        .map(|value| (name.to_string(), value.to_string()))
+                })
+                .collect(),
+            body,
+        }
+    }
+
+    fn to_response(&self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        let mut res = HttpResponse::build(status);
+
+        for (name, value) in &self.headers {
+            res.append_header((name.as_str(), value.as_str()));
+        }
+        res.insert_header((IDEMPOTENT_REPLAYED, HeaderValue::from_static("true")));
+
+        res.body(self.body.clone())
+    }
+}
+
+/// State of key returned by [IdempotencyStore::begin]
+#[derive(Clone, Debug)]
+pub enum Reservation {
+    /// Key was not known, it is now reserved for this request
+    New,
+    /// Request with this key is still being handled
+    InProgress,
+    /// Request with this key was handled, replay its response
+    Completed(StoredResponse),
+}
+
+/// Storage of idempotency keys and responses
+pub trait IdempotencyStore: Send + Sync {
+    /// Reserves key if it's not known (or expired), otherwise returns its state
+    fn begin(&self, key: String) -> LocalBoxFuture<'_, Result<Reservation, String>>;
+
+    /// Stores response of request for which key was reserved
+    fn complete(
+        &self,
+        key: String,
+        response: StoredResponse,
+    ) -> LocalBoxFuture<'_, Result<(), String>>;
+
+    /// Releases reserved key without storing response, so request can be retried
+    fn abort(&self, key: String) -> LocalBoxFuture<'_, Result<(), String>>;
+}
+
+enum MemoryEntry {
+    InProgress,
+    Completed(StoredResponse),
+}
+
+/// In-process store based on [CacheMap], keys expire after ttl (rounded down to seconds, at least 1)
+pub struct MemoryStore {
+    ttl: Duration,
+    inner: Mutex<(CacheMap<String, MemoryEntry>, Instant)>,
+}
+
+impl MemoryStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            inner: Mutex::new((CacheMap::new(ttl.as_secs().max(1)), Instant::now())),
+        }
+    }
+}
+
+impl IdempotencyStore for MemoryStore {
+    fn begin(&self, key: String) -> LocalBoxFuture<'_, Result<Reservation, String>> {
+        let result = self
+            .inner
+            .lock()
+            .map_err(|err| err.to_string())
+            .map(|mut inner| {
+                let (entries, cleaned) = &mut *inner;
+
+                if cleaned.elapsed() >= self.ttl {
+                    entries.remove_expired();
+                    *cleaned = Instant::now();
+                }
+
+                if entries.should_retain(&key) == Some(true) {
+                    match entries.get(&key) {
+                        Some(MemoryEntry::InProgress) => return Reservation::InProgress,
+                        Some(MemoryEntry::Completed(res)) => {
+                            return Reservation::Completed(res.clone());
+                        }
+                        None => {}
+                    }
+                }
+
+                entries.insert(key, MemoryEntry::InProgress);
+                Reservation::New
+            });
+
+        Box::pin(ready(result))
+    }
+
+    fn complete(
+        &self,
+        key: String,
+        response: StoredResponse,
+    ) -> LocalBoxFuture<'_, Result<(), String>> {
+        let result = self
+            .inner
+            .lock()
+            .map_err(|err| err.to_string())
+            .map(|mut inner| {
+                inner.0.insert(key, MemoryEntry::Completed(response));
+            });
+
+        Box::pin(ready(result))
+    }
+
+    fn abort(&self, key: String) -> LocalBoxFuture<'_, Result<(), String>> {
+        let result = self
+            .inner
+            .lock()
+            .map_err(|err| err.to_string())
+            .map(|mut inner| {
+                inner.0.remove(&key);
+            });
+
+        Box::pin(ready(result))
+    }
+}
+
+#[cfg(feature = "pgsql")]
+pub use diesel_store::DieselStore;
+
+#[cfg(feature = "pgsql")]
+mod diesel_store {
+    use std::time::Duration;
+
+    use actix_web::web;
+    use diesel::{
+        OptionalExtension, QueryableByName, RunQueryDsl,
+        sql_types::{Binary, Double, Nullable, SmallInt, Text},
+    };
+    use futures::future::LocalBoxFuture;
+
+    use super::{IdempotencyStore, Reservation, StoredResponse};
+    use crate::db_pool::{DbConnection, Pool};
+
+    /// Store keeping keys in database table, created f. ex. with:
+    ///
+    /// ```sql
+    /// CREATE TABLE idempotency_keys (
+    ///     key TEXT PRIMARY KEY,
+    ///     created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ///     request_hash TEXT,
+    ///     status SMALLINT,
+    ///     headers TEXT,
+    ///     body BYTEA
+    /// );
+    /// ```
+    ///
+    /// Expired keys are replaced when reused, remove old rows periodically
+    /// (f. ex. with [Job](crate::server::Job)) to keep table small.
+    pub struct DieselStore {
+        pool: Pool,
+        table: String,
+        ttl: Duration,
+    }
+
+    #[derive(QueryableByName)]
+    struct StoredRow {
+        #[diesel(sql_type = Nullable<Text>)]
+        request_hash: Option<String>,
+        #[diesel(sql_type = Nullable<SmallInt>)]
+        status: Option<i16>,
+        #[diesel(sql_type = Nullable<Text>)]
+        headers: Option<String>,
+        #[diesel(sql_type = Nullable<Binary>)]
+        body: Option<Vec<u8>>,
+    }
+
+    impl DieselStore {
+        /// Uses `idempotency_keys` table
+        pub fn new(pool: Pool, ttl: Duration) -> Self {
+            Self {
+                pool,
+                table: "idempotency_keys".to_string(),
+                ttl,
+            }
+        }
+
+        /// Uses given table, optionally with schema (`schema.table`).
+        ///
+        /// Name is put into queries as is, so only plain identifiers are accepted.
+        pub fn with_table(
+            pool: Pool,
+            table: impl Into<String>,
+            ttl: Duration,
+        ) -> Result<Self, String> {
+            let table = table.into();
+            if !is_table_name(&table) {
+                return Err(format!("Invalid idempotency table name: {table:?}"));
+            }
+
+            Ok(Self { pool, table, ttl })
+        }
+
+        async fn query<F, I>(&self, query: F) -> Result<I, String>
+        where
+            F: FnOnce(&mut DbConnection, &str) -> Result<I, diesel::result::Error> + Send + 'static,
+            I: Send + 'static,
+        {
+            let pool = self.pool.clone();
+            let table = self.table.clone();
+
+            web::block(move || {
+                let mut conn = pool.get().map_err(|err| err.to_string())?;
+                query(&mut conn, &table).map_err(|err| err.to_string())
+            })
+            .await
+            .map_err(|err| err.to_string())?
+        }
+    }
+
+    /// Identifier (or two, with schema) of letters, digits and underscores, not starting with digit
+    pub(super) fn is_table_name(name: &str) -> bool {
+        let mut parts = 0;
+
+        name.split('.').all(|part| {
+            parts += 1;
+            part.len() <= 63
+                && part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }) && parts <= 2
+    }
+
+    impl IdempotencyStore for DieselStore {
+        fn begin(&self, key: String) -> LocalBoxFuture<'_, Result<Reservation, String>> {
+            let ttl = self.ttl.as_secs_f64();
+
+            Box::pin(self.query(move |conn, table| {
+                diesel::sql_query(format!(
+                    "DELETE FROM {table} WHERE key = $1 AND created_at < now() - make_interval(secs => $2)"
+                ))
+                .bind::<Text, _>(&key)
+                .bind::<Double, _>(ttl)
+                .execute(conn)?;
+
+                let inserted = diesel::sql_query(format!(
+                    "INSERT INTO {table} (key) VALUES ($1) ON CONFLICT (key) DO NOTHING"
+                ))
+                .bind::<Text, _>(&key)
+                .execute(conn)?;
+
+                if inserted == 1 {
+                    return Ok(Reservation::New);
+                }
+
+                let row = diesel::sql_query(format!(
+                    "SELECT request_hash, status, headers, body FROM {table} WHERE key = $1"
+                ))
+                .bind::<Text, _>(&key)
+                .get_result::<StoredRow>(conn)
+                .optional()?;
+
+                // Row removed in the meantime means first request has just failed
+                let reservation = match row {
+                    Some(StoredRow {
+                        request_hash,
+                        status: Some(status),
+                        headers,
+                        body,
+                    }) => Reservation::Completed(StoredResponse {
+                        request_hash: request_hash.unwrap_or_default(),
+                        status: status as u16,
+                        headers: headers
+                            .and_then(|headers| serde_json::from_str(&headers).ok())
+                            .unwrap_or_default(),
+                        body: body.unwrap_or_default().into(),
+                    }),
+                    _ => Reservation::InProgress,
+                };
+
+                Ok(reservation)
+            }))
+        }
+
+        fn complete(
+            &self,
+            key: String,
+            response: StoredResponse,
+        ) -> LocalBoxFuture<'_, Result<(), String>> {
+            let headers = serde_json::to_string(&response.headers).unwrap_or_default();
+
+            Box::pin(self.query(move |conn, table| {
+                diesel::sql_query(format!(
+                    "UPDATE {table} SET request_hash = $2, status = $3, headers = $4, body = $5 WHERE key = $1"
+                ))
+                .bind::<Text, _>(&key)
+                .bind::<Text, _>(&response.request_hash)
+                .bind::<SmallInt, _>(response.status as i16)
+                .bind::<Text, _>(&headers)
+                .bind::<Binary, _>(response.body.to_vec())
+                .execute(conn)
+                .map(|_| ())
+            }))
+        }
+
+        fn abort(&self, key: String) -> LocalBoxFuture<'_, Result<(), String>> {
+            Box::pin(self.query(move |conn, table| {
+                diesel::sql_query(format!("DELETE FROM {table} WHERE key = $1"))
+                    .bind::<Text, _>(&key)
+                    .execute(conn)
+                    .map(|_| ())
+            }))
+        }
+    }
+}
+
+/// Idempotency middleware, see [module docs](self)
+#[derive(Clone)]
+pub struct Idempotency {
+    store: Arc<dyn IdempotencyStore>,
+    scope: Option<Arc<KeyExtractor>>,
+}
+
+impl Idempotency {
+    pub fn new(store: impl IdempotencyStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            scope: None,
+        }
+    }
+
+    /// Keeps responses in memory of this process for `ttl`
+    pub fn in_memory(ttl: Duration) -> Self {
+        Self::new(MemoryStore::new(ttl))
+    }
+
+    /// Separates keys of different clients, f. ex. by JWT subject, so they can't replay
+    /// responses of each other
+    pub fn scope(
+        mut self,
+        scope: impl Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.scope = Some(Arc::new(scope));
+        self
+    }
+
+    /// Full key of request, `None` if request is not covered, error for invalid header
+    fn key(&self, req: &ServiceRequest) -> Option<Result<String, JsonError>> {
+        if ![Method::POST, Method::PUT, Method::PATCH].contains(req.method()) {
+            return None;
+        }
+
+        let key = match req.headers().get(IDEMPOTENCY_KEY)?.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key,
+            _ => {
+                return Some(Err(ErrorBuilder::bad_request(format!(
+                    "Idempotency-Key must be 1 to {MAX_KEY_LENGTH} visible ASCII characters"
+                ))
+                .finish()));
+            }
+        };
+
+        let scope = self
+            .scope
+            .as_ref()
+            .and_then(|scope| scope(req))
+            .unwrap_or_default();
+
+        Some(Ok(format!("{scope}:{} {}:{key}", req.method(), req.path())))
+    }
+}
+
+/// Hex encoded SHA-256 of request body
+fn request_hash(body: &[u8]) -> String {
+    Sha256::digest(body)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Reserved key, released in background if request doesn't complete
+struct ReservedKey {
+    store: Arc<dyn IdempotencyStore>,
+    key: Option<String>,
+}
+
+impl ReservedKey {
+    async fn complete(mut self, response: StoredResponse) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+
+        if let Err(err) = self.store.complete(key.clone(), response).await {
+            error!("Can't store response for idempotency key {key}: {err}");
+            if let Err(err) = self.store.abort(key).await {
+                error!("Can't release idempotency key: {err}");
+            }
+        }
+    }
+
+    async fn abort(mut self) {
+        if let Some(key) = self.key.take()
+            && let Err(err) = self.store.abort(key).await
+        {
+            error!("Can't release idempotency key: {err}");
+        }
+    }
+}
+
+impl Drop for ReservedKey {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let store = self.store.clone();
+            actix_web::rt::spawn(async move {
+                if let Err(err) = store.abort(key).await {
+                    error!("Can't release idempotency key: {err}");
+                }
+            });
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Idempotency
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IdempotencyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyMiddleware {
+            service: Rc::new(service),
+            config: self.clone(),
+        }))
+    }
+}
+
+/// IdempotencyMiddleware replays stored responses for repeated keys
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<S>,
+    config: Idempotency,
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotencyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let key = match self.config.key(&req) {
+            None => {
+                let fut = self.service.call(req);
+                return Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) });
+            }
+            Some(Err(error)) => {
+                let res = req.into_response(error.error_response());
+                return Box::pin(ready(Ok(res.map_into_right_body())));
+            }
+            Some(Ok(key)) => key,
+        };
+
+        let store = self.config.store.clone();
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            // Body is read (within app's PayloadConfig limit) and put back for handler
+            let request_body = match req.extract::<Bytes>().await {
+                Ok(body) => body,
+                Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+            };
+            let hash = request_hash(&request_body);
+            req.set_payload(request_body.into());
+
+            let reservation = store.begin(key.clone()).await;
+
+            let rejection = match reservation {
+                Ok(Reservation::New) => None,
+                Ok(Reservation::InProgress) => Some(
+                    ErrorBuilder::conflict("Request with this Idempotency-Key is in progress")
+                        .message("Request is already being processed. Try again later")
+                        .finish()
+                        .error_response(),
+                ),
+                Ok(Reservation::Completed(stored)) if stored.request_hash != hash => Some(
+                    ErrorBuilder::validation_fail(
+                        "Idempotency-Key was already used for request with different body",
+                    )
+                    .message("Idempotency-Key was already used for another request")
+                    .finish()
+                    .error_response(),
+                ),
+                Ok(Reservation::Completed(stored)) => Some(stored.to_response()),
+                Err(err) => {
+                    error!("Can't check idempotency key {key}: {err}");
+                    Some(ErrorBuilder::internal(err).finish().error_response())
+                }
+            };
+
+            if let Some(res) = rejection {
+                return Ok(req.into_response(res).map_into_right_body());
+            }
+
+            let reserved = ReservedKey {
+                store,
+                key: Some(key),
+            };
+
+            let res = service.call(req).await?;
+
+            if res.status().is_server_error() || TRANSIENT_ERRORS.contains(&res.status().as_u16()) {
+                reserved.abort().await;
+                return Ok(res.map_into_left_body());
+            }
+
+            let (req, res) = res.into_parts();
+            let (res, body) = res.into_parts();
+            let body = body::to_bytes(body)
+                .await
+                .map_err(|err| actix_web::error::ErrorInternalServerError(err.into()))?;
+
+            reserved
+                .complete(StoredResponse::new(hash, &res, body.clone()))
+                .await;
+
+            let res = res.set_body(BoxBody::new(body));
+            Ok(ServiceResponse::new(req, res).map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use actix_web::{App, HttpResponse, http::StatusCode, test, web};
+
+    use super::{Idempotency, IdempotencyStore, MemoryStore, Reservation};
+
+    #[actix_web::test]
+    async fn replays_completed_and_rejects_running_requests() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+
+        let app = test::init_service(
+            App::new()
+                .route(
+                    "/pay",
+                    web::post().to(move || {
+                        let counter = counter.clone();
+                        async move {
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                            HttpResponse::Created()
+                                .insert_header(("x-call", call.to_string()))
+                                .body(format!("paid {call}"))
+                        }
+                    }),
+                )
+                .wrap(Idempotency::in_memory(Duration::from_secs(60))),
+        )
+        .await;
+
+        let pay = |key: &str| {
+            test::TestRequest::post()
+                .uri("/pay")
+                .insert_header(("idempotency-key", key.to_string()))
+                .to_request()
+        };
+
+        let (first, retry) = futures::join!(
+            test::call_service(&app, pay("a")),
+            test::call_service(&app, pay("a"))
+        );
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = test::read_body_json(retry).await;
+        assert_eq!(body["type"], "Conflict");
+
+        let res = test::call_service(&app, pay("a")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers().get("idempotent-replayed").unwrap(), "true");
+        assert_eq!(res.headers().get("x-call").unwrap(), "1");
+        assert_eq!(test::read_body(res).await, "paid 1");
+
+        let res = test::call_service(&app, pay("b")).await;
+        assert_eq!(test::read_body(res).await, "paid 2");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn retries_transient_errors_and_rejects_changed_body() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+
+        let app = test::init_service(
+            App::new()
+                .route(
+                    "/pay",
+                    web::post().to(move |body: String| {
+                        let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                        async move {
+                            if call == 1 {
+                                HttpResponse::TooManyRequests().finish()
+                            } else {
+                                HttpResponse::Created().body(format!("paid {body}"))
+                            }
+                        }
+                    }),
+                )
+                .wrap(Idempotency::in_memory(Duration::from_secs(60))),
+        )
+        .await;
+
+        let pay = |body: &'static str| {
+            test::TestRequest::post()
+                .uri("/pay")
+                .insert_header(("idempotency-key", "a"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let res = test::call_service(&app, pay("10")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let res = test::call_service(&app, pay("10")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(test::read_body(res).await, "paid 10");
+
+        let res = test::call_service(&app, pay("10")).await;
+        assert_eq!(res.headers().get("idempotent-replayed").unwrap(), "true");
+
+        let res = test::call_service(&app, pay("20")).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn keeps_keys_with_subsecond_ttl() {
+        let store = MemoryStore::new(Duration::from_millis(500));
+
+        let reservation = store.begin("key".to_string()).await.unwrap();
+        assert!(matches!(reservation, Reservation::New));
+        let reservation = store.begin("key".to_string()).await.unwrap();
+        assert!(matches!(reservation, Reservation::InProgress));
+    }
+
+    #[cfg(feature = "pgsql")]
+    #[actix_web::test]
+    async fn accepts_only_plain_table_names() {
+        use super::diesel_store::is_table_name;
+
+        assert!(is_table_name("idempotency_keys"));
+        assert!(is_table_name("api._keys2"));
+        assert!(!is_table_name(""));
+        assert!(!is_table_name("2keys"));
+        assert!(!is_table_name("a.b.c"));
+        assert!(!is_table_name("keys; DROP TABLE users"));
+        assert!(!is_table_name("\"keys\""));
+        assert!(!is_table_name(&"k".repeat(64)));
+    }
+}
@@ -1,6 +1,6 @@
 use actix_http::body::MessageBody;
 use actix_web::{
-    HttpResponse, ResponseError, Result,
+    HttpMessage, HttpResponse, ResponseError, Result,
     dev::ServiceResponse,
     http::{StatusCode, header},
     middleware::ErrorHandlerResponse,
@@ -8,10 +8,13 @@ use actix_web::{
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Display};
 
+use super::request_id::{self, RequestId};
+
 pub use serwus_derive::ResponseFromBuilder;
 
-#[derive(Debug, derive_more::Display, Deserialize, Serialize)]
+#[derive(Debug, derive_more::Display, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(feature = "swagger", derive(paperclip::actix::Apiv2Schema))]
+#[non_exhaustive]
 pub enum JsonErrorType {
     BadRequest,
     NotFound,
@@ -20,6 +23,12 @@ pub enum JsonErrorType {
     Database,
     ValidationFail,
     InvalidParams,
+    TooManyRequests,
+    Timeout,
+    PayloadTooLarge,
+    Conflict,
+    PreconditionFailed,
+    ServiceUnavailable,
     Custom(String),
 }
 
@@ -27,6 +36,18 @@ impl From<StatusCode> for JsonErrorType {
     fn from(value: StatusCode) -> Self {
         if value == StatusCode::NOT_FOUND {
             Self::NotFound
+        } else if value == StatusCode::TOO_MANY_REQUESTS {
+            Self::TooManyRequests
+        } else if value == StatusCode::PAYLOAD_TOO_LARGE {
+            Self::PayloadTooLarge
+        } else if value == StatusCode::GATEWAY_TIMEOUT {
+            Self::Timeout
+        } else if value == StatusCode::CONFLICT {
+            Self::Conflict
+        } else if value == StatusCode::PRECONDITION_FAILED {
+            Self::PreconditionFailed
+        } else if value == StatusCode::SERVICE_UNAVAILABLE {
+            Self::ServiceUnavailable
         } else if value.is_client_error() {
             Self::BadRequest
         } else if value.is_server_error() {
@@ -57,6 +78,17 @@ pub struct JsonError {
     pub reason: String,
     /// Any additional data, needs to be used when presenting error to the user (f. ex. validation errors)
     pub data: Option<serde_json::Value>,
+    /// ID of request which caused the error, to match it with log lines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl JsonError {
+    /// Restores status code, which is not deserialized
+    pub(super) fn with_status_code(mut self, status_code: StatusCode) -> Self {
+        self.status_code = status_code;
+        self
+    }
 }
 
 pub const GENERIC_MESSAGE: &str = "Something went wrong. Try again later";
@@ -84,6 +116,7 @@ impl ErrorBuilder {
                 debug: None,
                 reason,
                 data: None,
+                request_id: request_id::current().map(|id| id.to_string()),
             },
         }
     }
@@ -133,6 +166,47 @@ impl ErrorBuilder {
         Self::validation_fail(message.clone()).message(message)
     }
 
+    pub fn too_many_requests() -> Self {
+        let status_code = StatusCode::TOO_MANY_REQUESTS;
+        Self::new(
+            status_code,
+            JsonErrorType::TooManyRequests,
+            "Rate limit exceeded",
+        )
+        .message("Too many requests. Try again later")
+    }
+
+    pub fn payload_too_large(limit: usize) -> Self {
+        let status_code = StatusCode::PAYLOAD_TOO_LARGE;
+        Self::new(
+            status_code,
+            JsonErrorType::PayloadTooLarge,
+            format!("Payload is larger than {limit} bytes"),
+        )
+        .message("Request is too large")
+    }
+
+    pub fn timeout() -> Self {
+        let status_code = StatusCode::GATEWAY_TIMEOUT;
+        Self::new(status_code, JsonErrorType::Timeout, "Request timed out")
+    }
+
+    pub fn conflict(reason: impl Display) -> Self {
+        let status_code = StatusCode::CONFLICT;
+        Self::new(status_code, JsonErrorType::Conflict, reason)
+    }
+
+    pub fn precondition_failed(reason: impl Display) -> Self {
+        let status_code = StatusCode::PRECONDITION_FAILED;
+        Self::new(status_code, JsonErrorType::PreconditionFailed, reason)
+            .message("Resource was modified in the meantime. Reload it and try again")
+    }
+
+    pub fn service_unavailable(reason: impl Display) -> Self {
+        let status_code = StatusCode::SERVICE_UNAVAILABLE;
+        Self::new(status_code, JsonErrorType::ServiceUnavailable, reason)
+    }
+
     pub fn custom(sub_type: impl Display, reason: impl Display) -> Self {
         let status_code = StatusCode::INTERNAL_SERVER_ERROR;
         Self::new(
@@ -190,9 +264,18 @@ impl ResponseError for JsonError {
         if self.status_code > StatusCode::INTERNAL_SERVER_ERROR {
             log::error!("{} (reason: {})", self, self.reason);
         }
-        HttpResponse::build(self.status_code)
-            .content_type("application/json; charset=utf-8")
-            .json(self)
+        let mut response = HttpResponse::build(self.status_code);
+        response.content_type("application/json; charset=utf-8");
+
+        // Error could have been built outside of request, f. ex. in spawned task
+        match (&self.request_id, request_id::current()) {
+            (None, Some(id)) => {
+                let mut body = serde_json::to_value(self).unwrap_or_default();
+                body["request_id"] = serde_json::Value::String(id.to_string());
+                response.json(body)
+            }
+            _ => response.json(self),
+        }
     }
 
     fn status_code(&self) -> StatusCode {
@@ -238,6 +321,7 @@ pub fn default_error_handler<B: MessageBody + 'static>(
             debug: Some(debug),
             reason: "".to_string(),
             data: None,
+            request_id: req.extensions().get::<RequestId>().map(|id| id.to_string()),
         };
 
         // Overwrite response content-type
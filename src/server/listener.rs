@@ -0,0 +1,120 @@
+//! Addresses and sockets the server listens on
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// Single listener added to server with one of `Serwus::listen*` methods
+#[derive(Clone, Debug)]
+pub enum Listener {
+    /// Plain HTTP on TCP address, f. ex. `0.0.0.0:8000`, `[::]:8000` or `10.1.2.3:8000`
+    Tcp(String),
+    /// Plain HTTP on Unix domain socket
+    #[cfg(unix)]
+    Uds(PathBuf),
+    /// HTTPS (rustls) on TCP address with certificate chain and private key from PEM files
+    #[cfg(feature = "tls")]
+    Rustls {
+        addr: String,
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    },
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "http://{addr}"),
+            #[cfg(unix)]
+            Listener::Uds(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(feature = "tls")]
+            Listener::Rustls { addr, .. } => write!(f, "https://{addr}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::Error;
+    use std::future::{Future, ready};
+    use std::pin::Pin;
+
+    #[cfg(not(feature = "swagger"))]
+    use actix_web::web;
+
+    #[cfg(feature = "swagger")]
+    use paperclip::actix::web;
+
+    use crate::server::{Serwus, default_cors, stats::StatsPresenter};
+
+    #[derive(Clone)]
+    struct AppData;
+
+    impl StatsPresenter<()> for AppData {
+        fn is_ready(&self) -> Pin<Box<dyn Future<Output = Result<bool, Error>>>> {
+            Box::pin(ready(Ok(true)))
+        }
+
+        fn get_stats(&self) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+            Box::pin(ready(Ok(())))
+        }
+    }
+
+    fn configure_app(_cfg: &mut web::ServiceConfig) {}
+
+    #[actix_web::test]
+    async fn returns_bind_error() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+
+        let err = Serwus::default()
+            .set_workers(1)
+            .listen(addr.clone())
+            .start(|| AppData, configure_app, default_cors)
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        assert!(err.to_string().contains(&addr), "{err}");
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn serves_on_unix_socket() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join(format!("serwus-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let server = actix_web::rt::spawn(
+            Serwus::default()
+                .set_workers(1)
+                .listen_uds(path.clone())
+                .start(|| AppData, configure_app, default_cors),
+        );
+
+        for _ in 0..100 {
+            if path.exists() {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /_ready HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("OK"), "{response}");
+
+        server.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,167 @@
+//! Changing log filter at runtime
+//!
+//! With [Serwus::enable_log_level_endpoint](super::Serwus::enable_log_level_endpoint) internal
+//! endpoint `/_loglevel` is added (on management listener, if set):
+//!
+//! * `GET` returns current filter and time of its automatic revert
+//! * `PUT` with `{"filter": "info,my_app::db=debug", "revert_after_mins": 15}` sets filter,
+//!   `revert_after_mins` is optional
+//! * `DELETE` restores filter from configuration
+//!
+//! With [Serwus::reload_log_filter_on_sighup](super::Serwus::reload_log_filter_on_sighup)
+//! `LOGGER_LEVEL` is read again from configuration files and environment on `SIGHUP`
+//! and replaces filter set at startup (and any temporary one).
+//!
+//! Filter applies to both console logger and tracing subscriber, see [LogFilter].
+
+use std::time::Duration;
+
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::json_error::{ErrorBuilder, JsonError};
+use crate::logger::{self, LogFilter};
+
+#[derive(Deserialize)]
+pub struct SetLogFilter {
+    /// Directives, f. ex. `warn,my_app=debug`
+    pub filter: String,
+    /// Restore filter from configuration after given number of minutes
+    pub revert_after_mins: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct LogFilterState {
+    pub filter: String,
+    pub revert_at: Option<DateTime<Utc>>,
+}
+
+fn current_state() -> HttpResponse {
+    let (filter, revert_at) = logger::log_filter();
+
+    HttpResponse::Ok().json(LogFilterState {
+        filter: filter.to_string(),
+        revert_at,
+    })
+}
+
+pub(super) fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("_loglevel", web::get().to(get_log_filter))
+        .route("_loglevel", web::put().to(set_log_filter))
+        .route("_loglevel", web::delete().to(reset_log_filter));
+}
+
+async fn get_log_filter() -> HttpResponse {
+    current_state()
+}
+
+async fn set_log_filter(body: web::Json<SetLogFilter>) -> Result<HttpResponse, JsonError> {
+    let filter: LogFilter = body
+        .filter
+        .parse()
+        .map_err(|err| ErrorBuilder::bad_request(err).finish())?;
+
+    let revert_after =
+        match body.revert_after_mins {
+            Some(mins) => Some(revert_after(mins).ok_or_else(|| {
+                ErrorBuilder::bad_request("revert_after_mins is too large").finish()
+            })?),
+            None => None,
+        };
+    logger::set_log_filter(filter, revert_after);
+
+    Ok(current_state())
+}
+
+/// Converts minutes to duration, `None` if time of revert would be out of range
+fn revert_after(mins: u64) -> Option<Duration> {
+    let after = Duration::from_secs(mins.checked_mul(60)?);
+    Utc::now().checked_add_signed(TimeDelta::from_std(after).ok()?)?;
+    Some(after)
+}
+
+async fn reset_log_filter() -> HttpResponse {
+    logger::reset_log_filter();
+    current_state()
+}
+
+/// Reloads `LOGGER_LEVEL` with given loader on every `SIGHUP`
+#[cfg(unix)]
+pub(super) async fn reload_on_sighup(loader: crate::config::ConfigLoader) {
+    use actix_web::rt::signal::unix::{SignalKind, signal};
+
+    let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+        log::warn!("Can't register SIGHUP handler, log filter won't be reloaded");
+        return;
+    };
+
+    while hangup.recv().await.is_some() {
+        log::info!("SIGHUP received, reloading log filter");
+
+        match loader.load() {
+            Ok(config) => match config.logger_level.parse() {
+                Ok(filter) => logger::replace_base_log_filter(filter),
+                Err(err) => log::error!("Can't reload log filter: {err}"),
+            },
+            Err(err) => log::error!("Can't reload log filter: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{App, http::StatusCode, test};
+    use serde_json::json;
+
+    use super::routes;
+    use crate::logger;
+
+    /// Restores base filter even if test fails, logger is shared by all tests
+    struct ResetFilter;
+
+    impl Drop for ResetFilter {
+        fn drop(&mut self) {
+            logger::reset_log_filter();
+        }
+    }
+
+    #[actix_web::test]
+    async fn sets_and_reverts_filter() {
+        let _reset = ResetFilter;
+        let app = test::init_service(App::new().configure(routes)).await;
+
+        let req = test::TestRequest::put()
+            .uri("/_loglevel")
+            .set_json(json!({"filter": "debug", "revert_after_mins": u64::MAX}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::put()
+            .uri("/_loglevel")
+            .set_json(json!({"filter": "debug", "revert_after_mins": 5}))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["filter"], "debug");
+        assert!(body["revert_at"].is_string());
+
+        tokio::time::pause();
+
+        logger::set_log_filter("debug".parse().unwrap(), Some(Duration::from_secs(60)));
+        tokio::time::advance(Duration::from_secs(61)).await;
+        tokio::task::yield_now().await;
+        let (filter, revert_at) = logger::log_filter();
+        assert_ne!(filter.to_string(), "debug");
+        assert_eq!(revert_at, None);
+
+        // Revert of replaced filter is cancelled
+        logger::set_log_filter("debug".parse().unwrap(), Some(Duration::from_secs(60)));
+        logger::set_log_filter("warn".parse().unwrap(), None);
+        tokio::time::advance(Duration::from_secs(61)).await;
+        tokio::task::yield_now().await;
+        assert_eq!(logger::log_filter().0.to_string(), "warn");
+    }
+}
@@ -0,0 +1,428 @@
+//! Maintenance mode
+//!
+//! When maintenance is on, requests to app routes are rejected with 503 [JsonError](super::json_error::JsonError)
+//! (`ServiceUnavailable`) with configured message and `Retry-After` header, so f. ex.
+//! database migrations don't end with random `Database` errors. Internal endpoints keep working
+//! and `/_ready` stays 200, reporting maintenance in its body, so instances are not restarted.
+//!
+//! It's set up with [Serwus::set_maintenance](super::Serwus::set_maintenance):
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use serde::Deserialize;
+//! # use serwus::{EmptyStats, containers::role::Role, server::{Maintenance, Serwus, default_cors}, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # #[derive(Clone, Deserialize)]
+//! # struct AccessToken { role: Role }
+//! # #[cfg(feature = "auth")]
+//! # impl serwus::auth::jwt::KnowSecret for AccessToken { fn get_secret() -> Vec<u8> { b"secret".to_vec() } }
+//! # fn configure_app(_cfg: &mut web::ServiceConfig) {}
+//! # #[actix_web::main]
+//! # async fn main() -> std::io::Result<()> {
+//! let maintenance = Maintenance::default()
+//!     .message("We are upgrading the database, back in a few minutes")
+//!     .retry_after(Duration::from_secs(300))
+//!     .allow_path("/api/status")
+//!     .flag_file("/run/my-service/maintenance");
+//!
+//! // With `auth` feature
+//! # #[cfg(feature = "auth")]
+//! let maintenance = maintenance.allow_roles::<AccessToken>(&[Role::Admin], |token| token.role.clone());
+//!
+//! Serwus::default()
+//!     .set_maintenance(maintenance.clone())
+//!     .enable_maintenance_endpoint()
+//!     .start(|| AppData, configure_app, default_cors)
+//!     .await
+//! # }
+//! ```
+//!
+//! and switched on and off:
+//!
+//! * with internal endpoint `/_maintenance`, added with
+//!   [enable_maintenance_endpoint](super::Serwus::enable_maintenance_endpoint) (it has no authorization,
+//!   so keep it on management listener): `GET` returns current state, `PUT` with optional
+//!   `{"message": "...", "retry_after_secs": 600}` turns it on, `DELETE` turns it off
+//! * with `SIGUSR1`, which toggles it
+//! * by creating and removing flag file, its content (if any) is used as message
+//! * from code, with [enable](Maintenance::enable) and [disable](Maintenance::disable) on clone
+//!   of [Maintenance] or on `web::Data<Maintenance>` taken by handler
+//!
+//! Rejected requests are counted as `maintenance_rejected` in base stats.
+
+use std::future::{Future, Ready, ready};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    Error, HttpResponse, ResponseError,
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderValue, RETRY_AFTER},
+    web,
+};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::json_error::ErrorBuilder;
+use super::stats::BaseStats;
+
+/// How often flag file is checked
+const FLAG_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+type RequestFilter = dyn Fn(&ServiceRequest) -> bool + Send + Sync;
+
+/// Current maintenance, as returned by `/_maintenance`
+#[derive(Clone, Debug, Serialize)]
+pub struct MaintenanceState {
+    pub message: String,
+    pub retry_after_secs: u64,
+    pub since: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct EnableMaintenance {
+    /// Overrides configured message
+    pub message: Option<String>,
+    /// Overrides configured `Retry-After`
+    pub retry_after_secs: Option<u64>,
+}
+
+/// Maintenance switch and middleware rejecting requests while it's on, see [module docs](self).
+///
+/// Clones share the switch.
+#[derive(Clone)]
+pub struct Maintenance {
+    state: Arc<RwLock<Option<MaintenanceState>>>,
+    message: String,
+    retry_after: Duration,
+    allowed_paths: Arc<Vec<String>>,
+    allowed_requests: Vec<Arc<RequestFilter>>,
+    flag_file: Option<PathBuf>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(RwLock::new(None)),
+            message: "Service is under maintenance. Try again later".to_string(),
+            retry_after: Duration::from_secs(60),
+            allowed_paths: Arc::new(Vec::new()),
+            allowed_requests: Vec::new(),
+            flag_file: None,
+        }
+    }
+}
+
+impl Maintenance {
+    /// Sets message shown to users (can be overridden when turning maintenance on)
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.message = message.into();
+        self
+    }
+
+    /// Sets `Retry-After` of rejected requests, rounded to seconds (default: 60 s)
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Lets through requests to paths starting with `prefix`
+    pub fn allow_path(mut self, prefix: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.allowed_paths).push(prefix.into());
+        self
+    }
+
+    /// Lets through requests for which `filter` returns `true`
+    pub fn allow_request(
+        mut self,
+        filter: impl Fn(&ServiceRequest) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.allowed_requests.push(Arc::new(filter));
+        self
+    }
+
+    /// Lets through requests with valid JWT of one of given roles, as returned by `role`
+    /// from decoded token
+    #[cfg(feature = "auth")]
+    pub fn allow_roles<T>(
+        self,
+        roles: &[crate::containers::role::Role],
+        role: impl Fn(&T) -> crate::containers::role::Role + Send + Sync + 'static,
+    ) -> Self
+    where
+        T: crate::auth::jwt::FromEncoded,
+    {
+        let roles = roles.to_vec();
+        self.allow_request(move |req| {
+            crate::auth::jwt::from_request::<T>(req.request())
+                .is_ok_and(|token| roles.contains(&role(&token)))
+        })
+    }
+
+    /// Turns maintenance on while given file exists
+    pub fn flag_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.flag_file = Some(path.into());
+        self
+    }
+
+    /// Turns maintenance on, `None` means configured message and `Retry-After`
+    pub fn enable(&self, message: Option<String>, retry_after: Option<Duration>) {
+        let state = MaintenanceState {
+            message: message.unwrap_or_else(|| self.message.clone()),
+            retry_after_secs: retry_after.unwrap_or(self.retry_after).as_secs(),
+            since: Utc::now(),
+        };
+        info!("Maintenance mode on: {}", state.message);
+
+        if let Ok(mut current) = self.state.write() {
+            *current = Some(state);
+        }
+    }
+
+    /// Turns maintenance off
+    pub fn disable(&self) {
+        if let Ok(mut current) = self.state.write()
+            && current.take().is_some()
+        {
+            info!("Maintenance mode off");
+        }
+    }
+
+    /// Current maintenance, `None` when it's off
+    pub fn state(&self) -> Option<MaintenanceState> {
+        self.state.read().ok().and_then(|state| state.clone())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.read().is_ok_and(|state| state.is_some())
+    }
+
+    fn is_allowed(&self, req: &ServiceRequest) -> bool {
+        super::INTERNAL_PATHS.contains(&req.path())
+            || self
+                .allowed_paths
+                .iter()
+                .any(|prefix| req.path().starts_with(prefix.as_str()))
+            || self.allowed_requests.iter().any(|filter| filter(req))
+    }
+}
+
+pub(super) fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("_maintenance", web::get().to(get_maintenance))
+        .route("_maintenance", web::put().to(enable_maintenance))
+        .route("_maintenance", web::delete().to(disable_maintenance));
+}
+
+async fn get_maintenance(maintenance: web::Data<Maintenance>) -> HttpResponse {
+    HttpResponse::Ok().json(maintenance.state())
+}
+
+async fn enable_maintenance(
+    maintenance: web::Data<Maintenance>,
+    body: Option<web::Json<EnableMaintenance>>,
+) -> HttpResponse {
+    let (message, retry_after) = body
+        .map(|body| {
+            let body = body.into_inner();
+            (body.message, body.retry_after_secs.map(Duration::from_secs))
+        })
+        .unwrap_or_default();
+
+    maintenance.enable(message, retry_after);
+    HttpResponse::Ok().json(maintenance.state())
+}
+
+async fn disable_maintenance(maintenance: web::Data<Maintenance>) -> HttpResponse {
+    maintenance.disable();
+    HttpResponse::Ok().json(maintenance.state())
+}
+
+/// Starts switching maintenance with `SIGUSR1` and flag file (if set)
+pub(super) fn spawn_switches(maintenance: &Maintenance) {
+    #[cfg(unix)]
+    actix_web::rt::spawn(toggle_on_sigusr1(maintenance.clone()));
+
+    if let Some(path) = maintenance.flag_file.clone() {
+        actix_web::rt::spawn(watch_flag_file(maintenance.clone(), path));
+    }
+}
+
+#[cfg(unix)]
+async fn toggle_on_sigusr1(maintenance: Maintenance) {
+    use actix_web::rt::signal::unix::{SignalKind, signal};
+
+    let Ok(mut usr1) = signal(SignalKind::user_defined1()) else {
+        warn!("Can't register SIGUSR1 handler, maintenance mode won't be toggled by signal");
+        return;
+    };
+
+    while usr1.recv().await.is_some() {
+        info!("SIGUSR1 received, toggling maintenance mode");
+
+        match maintenance.is_enabled() {
+            true => maintenance.disable(),
+            false => maintenance.enable(None, None),
+        }
+    }
+}
+
+/// Follows changes of flag file only, so switching with other means still works
+async fn watch_flag_file(maintenance: Maintenance, path: PathBuf) {
+    let mut interval = tokio::time::interval(FLAG_FILE_CHECK_INTERVAL);
+    let mut existed = false;
+
+    loop {
+        interval.tick().await;
+
+        let content = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || std::fs::read_to_string(path).ok()
+        })
+        .await
+        .ok()
+        .flatten();
+
+        match (content, existed) {
+            (Some(content), false) => {
+                info!("Maintenance flag file {} found", path.display());
+                let message =
+                    Some(content.trim().to_string()).filter(|message| !message.is_empty());
+                maintenance.enable(message, None);
+                existed = true;
+            }
+            (None, true) => {
+                info!("Maintenance flag file {} removed", path.display());
+                maintenance.disable();
+                existed = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Maintenance
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MaintenanceMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MaintenanceMiddleware {
+            service,
+            maintenance: self.clone(),
+        }))
+    }
+}
+
+/// MaintenanceMiddleware rejects requests while maintenance is on
+pub struct MaintenanceMiddleware<S> {
+    service: S,
+    maintenance: Maintenance,
+}
+
+impl<S, B> Service<ServiceRequest> for MaintenanceMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let state = match self.maintenance.state() {
+            Some(state) if !self.maintenance.is_allowed(&req) => state,
+            _ => {
+                let fut = self.service.call(req);
+                return Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) });
+            }
+        };
+
+        if let Some(stats) = req.app_data::<web::Data<BaseStats>>() {
+            stats.increment(|stats| &stats.maintenance_rejected);
+        }
+
+        let error = ErrorBuilder::service_unavailable("Maintenance mode")
+            .message(state.message)
+            .finish();
+        let mut res = req.into_response(error.error_response());
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(state.retry_after_secs));
+        Box::pin(ready(Ok(res.map_into_right_body())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{
+        App, HttpResponse,
+        http::{StatusCode, header},
+        test, web,
+    };
+
+    use super::Maintenance;
+
+    #[actix_web::test]
+    async fn rejects_requests_while_enabled() {
+        let maintenance = Maintenance::default()
+            .retry_after(Duration::from_secs(120))
+            .allow_path("/status");
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(maintenance.clone()))
+                .configure(super::routes)
+                .route("/orders", web::get().to(HttpResponse::Ok))
+                .route("/status", web::get().to(HttpResponse::Ok))
+                .wrap(maintenance.clone()),
+        )
+        .await;
+
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        let res = test::call_service(&app, get("/orders")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = test::TestRequest::put()
+            .uri("/_maintenance")
+            .set_json(serde_json::json!({"message": "Migrating"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = test::call_service(&app, get("/orders")).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "120");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["type"], "ServiceUnavailable");
+        assert_eq!(body["message"], "Migrating");
+
+        let res = test::call_service(&app, get("/status")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        maintenance.disable();
+        let res = test::call_service(&app, get("/orders")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
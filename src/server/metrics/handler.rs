@@ -1,11 +1,31 @@
+use std::sync::OnceLock;
+
 use actix_web::HttpRequest;
 use lazy_static::lazy_static;
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use super::middleware::REQUEST_DURATION;
+
+/// Buckets of request duration histogram, set by [Metrics](super::middleware::Metrics)
+/// before recorder is installed (when empty duration is exported as summary)
+pub(super) static DURATION_BUCKETS: OnceLock<Vec<f64>> = OnceLock::new();
 
 lazy_static! {
-    pub static ref PROM_HANDLER: PrometheusHandle = PrometheusBuilder::new()
-        .install_recorder()
-        .expect("failed to install recorder");
+    pub static ref PROM_HANDLER: PrometheusHandle = {
+        // Empty when not set before, so buckets set later are not reported as used
+        let buckets = DURATION_BUCKETS.get_or_init(Vec::new);
+        let builder = if buckets.is_empty() {
+            PrometheusBuilder::new()
+        } else {
+            PrometheusBuilder::new()
+                .set_buckets_for_metric(Matcher::Full(REQUEST_DURATION.to_string()), buckets)
+                .expect("invalid duration buckets")
+        };
+
+        builder
+            .install_recorder()
+            .expect("failed to install recorder")
+    };
 }
 
 pub async fn metrics(_: HttpRequest) -> String {
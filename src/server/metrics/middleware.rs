@@ -1,5 +1,7 @@
 use std::{
+    collections::HashSet,
     future::{Ready, ready},
+    sync::Arc,
     time::Instant,
 };
 
@@ -8,9 +10,85 @@ use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
 };
 use futures_util::future::LocalBoxFuture;
+use metrics::Label;
 
-#[derive(Debug, Clone)]
-pub struct Metrics;
+use super::handler::{DURATION_BUCKETS, PROM_HANDLER};
+use crate::server::{
+    HUSHED_PATHS,
+    stats::{UNMATCHED_ROUTE, method_name},
+};
+
+pub const REQUESTS_TOTAL: &str = "http_requests_total";
+pub const REQUEST_DURATION: &str = "http_requests_duration_seconds";
+
+type LabelExtractor = dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync;
+
+/// Records number and duration of requests labeled with method (`OTHER` for non-standard ones),
+/// route pattern (f. ex. `/users/{id}`, or `unmatched`, so IDs don't create new series) and status.
+///
+/// Paths from [HUSHED_PATHS] are not recorded by default.
+#[derive(Clone)]
+pub struct Metrics {
+    excludes: Arc<HashSet<String>>,
+    labels: Vec<(&'static str, Arc<LabelExtractor>)>,
+    buckets: Option<Vec<f64>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            excludes: Arc::new(HUSHED_PATHS.iter().map(|path| path.to_string()).collect()),
+            labels: Vec::new(),
+            buckets: None,
+        }
+    }
+}
+
+impl Metrics {
+    /// Doesn't record requests to given path or route pattern
+    pub fn exclude(mut self, path: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.excludes).insert(path.into());
+        self
+    }
+
+    /// Adds label with value returned by `value` (empty if `None`), f. ex. API version from header.
+    ///
+    /// Keep number of possible values small, every one creates new series.
+    pub fn label(
+        mut self,
+        name: &'static str,
+        value: impl Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.labels.push((name, Arc::new(value)));
+        self
+    }
+
+    /// Adds label with value returned by `value` from decoded JWT, f. ex. role
+    /// (empty for requests without valid token)
+    #[cfg(feature = "auth")]
+    pub fn jwt_label<T>(
+        self,
+        name: &'static str,
+        value: impl Fn(&T) -> String + Send + Sync + 'static,
+    ) -> Self
+    where
+        T: crate::auth::jwt::FromEncoded,
+    {
+        self.label(name, move |req| {
+            crate::auth::jwt::from_request::<T>(req.request())
+                .ok()
+                .map(|token| value(&token))
+        })
+    }
+
+    /// Exports request duration as histogram with given buckets (in seconds) instead of summary.
+    ///
+    /// Buckets are global, set by first middleware created.
+    pub fn buckets(mut self, buckets: &[f64]) -> Self {
+        self.buckets = Some(buckets.to_vec()).filter(|buckets| !buckets.is_empty());
+        self
+    }
+}
 
 impl<S, B> Transform<S, ServiceRequest> for Metrics
 where
@@ -25,12 +103,22 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(MetricsMiddleware { service }))
+        if let Some(buckets) = &self.buckets {
+            let _ = DURATION_BUCKETS.set(buckets.clone());
+        }
+        // Installs recorder, so requests before first scrape are recorded too
+        lazy_static::initialize(&PROM_HANDLER);
+
+        ready(Ok(MetricsMiddleware {
+            service,
+            config: self.clone(),
+        }))
     }
 }
 
 pub struct MetricsMiddleware<S> {
     service: S,
+    config: Metrics,
 }
 
 impl<S, B> Service<ServiceRequest> for MetricsMiddleware<S>
@@ -46,9 +134,27 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
+        let pattern = req.match_pattern();
+        let excluded = self.config.excludes.contains(req.path())
+            || pattern
+                .as_ref()
+                .is_some_and(|pattern| self.config.excludes.contains(pattern));
+
+        if excluded {
+            return Box::pin(self.service.call(req));
+        }
+
         let start = Instant::now();
-        let path = req.path().to_owned();
-        let method = req.method().to_string();
+        let mut labels = vec![
+            Label::new("method", method_name(req.method()).to_string()),
+            Label::new(
+                "path",
+                pattern.unwrap_or_else(|| UNMATCHED_ROUTE.to_string()),
+            ),
+        ];
+        for (name, value) in &self.config.labels {
+            labels.push(Label::new(*name, value(&req).unwrap_or_default()));
+        }
 
         let fut = self.service.call(req);
 
@@ -63,12 +169,68 @@ where
                 .as_u16()
                 .to_string();
 
-            let labels = [("method", method), ("path", path), ("status", status)];
+            labels.push(Label::new("status", status));
 
-            metrics::counter!("http_requests_total", &labels).increment(1);
-            metrics::histogram!("http_requests_duration_seconds", &labels).record(latency);
+            metrics::counter!(REQUESTS_TOTAL, labels.clone()).increment(1);
+            metrics::histogram!(REQUEST_DURATION, labels).record(latency);
 
             response
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpResponse, http::Method, test, web};
+
+    use super::Metrics;
+    use crate::server::metrics::handler::{DURATION_BUCKETS, PROM_HANDLER};
+
+    #[actix_web::test]
+    async fn labels_requests_with_route_pattern() {
+        let metrics = Metrics::default()
+            .buckets(&[0.1, 1.0])
+            .label("version", |req| {
+                req.headers()
+                    .get("x-api-version")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            });
+
+        let app = test::init_service(
+            App::new()
+                .route("/orders/{id}", web::get().to(HttpResponse::Ok))
+                .route("/_ready", web::get().to(HttpResponse::Ok))
+                .wrap(metrics),
+        )
+        .await;
+
+        for uri in ["/orders/1", "/orders/2", "/_ready", "/nope"] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("x-api-version", "2"))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let req = test::TestRequest::default()
+            .method(Method::from_bytes(b"PURGE").unwrap())
+            .uri("/orders/1")
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let rendered = PROM_HANDLER.render();
+        assert!(rendered.contains(
+            r#"http_requests_total{method="GET",path="/orders/{id}",version="2",status="200"} 2"#
+        ));
+        assert!(rendered.contains(r#"path="unmatched""#));
+        assert!(rendered.contains(r#"method="OTHER""#) && !rendered.contains("PURGE"));
+        // Recorder is global, so buckets are used only if no other test installed it before
+        if DURATION_BUCKETS
+            .get()
+            .is_some_and(|buckets| buckets == &[0.1, 1.0])
+        {
+            assert!(rendered.contains("http_requests_duration_seconds_bucket"));
+        }
+        assert!(!rendered.contains("/orders/1") && !rendered.contains("/_ready"));
+    }
+}
@@ -0,0 +1,146 @@
+//! Custom app-level middleware registered with [Serwus::add_middleware](super::Serwus::add_middleware)
+//!
+//! Built-in middleware wraps the app in this order (from innermost):
+//! CORS, `StatsWrapper`, `ErrorHandlers`, `SecurityHeaders` and `Compression` (if set),
+//! `TracingLogger` (with `tracing` feature), `Logger` and `RequestIdWrapper`.
+//! [MiddlewarePosition] selects where in this chain custom middleware goes.
+
+use std::sync::Arc;
+
+use actix_service::{
+    Service, ServiceExt, Transform,
+    boxed::{self, BoxService},
+};
+use actix_web::{
+    Error,
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+};
+use futures::future::LocalBoxFuture;
+
+/// Place of custom middleware relative to built-in ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MiddlewarePosition {
+    /// Closest to routes, inside CORS
+    InsideCors,
+    /// Between CORS and `StatsWrapper`
+    OutsideCors,
+    /// Between `StatsWrapper` and `ErrorHandlers`, so errors returned by middleware are converted to JSON
+    OutsideStats,
+    /// Between `ErrorHandlers` (with security headers and compression) and `TracingLogger`,
+    /// so middleware sees final responses
+    OutsideErrorHandlers,
+    /// Outside `Logger` and `RequestIdWrapper`, sees requests before anything else
+    Outermost,
+}
+
+/// Service wrapped by custom middleware
+pub type BoxedService = BoxService<ServiceRequest, ServiceResponse<BoxBody>, Error>;
+
+/// Type-erased middleware factory, called once per worker
+pub(super) type MiddlewareFn =
+    Arc<dyn Fn(BoxedService) -> LocalBoxFuture<'static, Result<BoxedService, ()>> + Send + Sync>;
+
+/// Erases middleware type, so middlewares of different types can be stored together
+pub(super) fn middleware_fn<F, M, B>(factory: F) -> MiddlewareFn
+where
+    F: Fn() -> M + Send + Sync + 'static,
+    M: Transform<
+            BoxedService,
+            ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = Error,
+            InitError = (),
+        > + 'static,
+    M::Transform: 'static,
+    B: MessageBody + 'static,
+{
+    Arc::new(move |service| {
+        let init = factory().new_transform(service);
+        Box::pin(async move {
+            let service = init.await?;
+            Ok(boxed::service(
+                service.map(ServiceResponse::map_into_boxed_body),
+            ))
+        })
+    })
+}
+
+/// Applies all custom middlewares registered for one position.
+///
+/// Middlewares are applied in registration order, so the last one is the outermost.
+pub(super) struct CustomMiddlewares(pub(super) Vec<MiddlewareFn>);
+
+impl<S, B> Transform<S, ServiceRequest> for CustomMiddlewares
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = BoxedService;
+    type InitError = ();
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let middlewares = self.0.clone();
+        Box::pin(async move {
+            let mut service = boxed::service(service.map(ServiceResponse::map_into_boxed_body));
+            for middleware in middlewares {
+                service = middleware(service).await?;
+            }
+            Ok(service)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        App, HttpResponse,
+        body::MessageBody,
+        dev::{ServiceRequest, ServiceResponse},
+        http::header::{HeaderName, HeaderValue},
+        middleware::{Next, from_fn},
+        test, web,
+    };
+
+    use super::{CustomMiddlewares, middleware_fn};
+
+    async fn append_trace(
+        req: ServiceRequest,
+        next: Next<impl MessageBody>,
+        tag: &'static str,
+    ) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+        let mut res = next.call(req).await?;
+        let trace = res
+            .headers()
+            .get("x-trace")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| format!("{value},{tag}"))
+            .unwrap_or_else(|| tag.to_string());
+        res.headers_mut().insert(
+            HeaderName::from_static("x-trace"),
+            HeaderValue::from_str(&trace).unwrap(),
+        );
+        Ok(res)
+    }
+
+    #[actix_web::test]
+    async fn last_registered_is_outermost() {
+        let middlewares = CustomMiddlewares(vec![
+            middleware_fn(|| from_fn(|req, next| append_trace(req, next, "first"))),
+            middleware_fn(|| from_fn(|req, next| append_trace(req, next, "second"))),
+        ]);
+
+        let app = test::init_service(
+            App::new()
+                .route("/", web::get().to(HttpResponse::Ok))
+                .wrap(middlewares),
+        )
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(res.headers().get("x-trace").unwrap(), "first,second");
+    }
+}
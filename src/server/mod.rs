@@ -1,15 +1,41 @@
 //! Few helpers for spawning and configuring service in actix ecosystem.
 
+pub mod access_log;
 pub mod app_data;
 mod builder;
+pub mod compression;
+pub mod conditional;
+pub mod cors;
+pub mod extract;
+pub mod idempotency;
 pub mod json_error;
+pub mod listener;
+pub mod log_level;
+pub mod maintenance;
 #[cfg(feature = "metrics")]
 pub mod metrics;
+pub mod middleware;
 #[cfg(feature = "prometheus")]
 pub mod prometheus;
+pub mod rate_limit;
+pub mod request_id;
+pub mod response_cache;
+pub mod scheduler;
+pub mod security_headers;
+pub mod shutdown;
+pub mod sse;
+#[cfg(feature = "static_files")]
+pub mod static_files;
 pub mod stats;
+pub mod tasks;
+pub mod test_server;
+pub mod timeout;
+#[cfg(feature = "tls")]
+pub mod tls;
 #[cfg(feature = "tracing")]
 pub mod tracing;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 use actix_cors::Cors;
 use actix_http::Request;
@@ -26,8 +52,40 @@ use super::threads;
 
 pub use app_data::{DefaultAppData, default_app_data};
 pub use builder::Serwus;
+pub use cors::CorsConfig;
+pub use maintenance::Maintenance;
+pub use middleware::MiddlewarePosition;
+pub use scheduler::Job;
+pub use tasks::{BackgroundTask, TaskContext};
+pub use test_server::TestServer;
 
-/// Use this Cors builder if you want to send wildcard and allow default set ot http methods and headers
+/// Paths of internal endpoints (whether enabled or not): never affected by maintenance mode
+/// and excluded from stats by default
+pub const INTERNAL_PATHS: [&str; 7] = [
+    "/_healthcheck",
+    "/_ready",
+    "/_stats",
+    "/_prometheus",
+    "/metrics",
+    "/_loglevel",
+    "/_maintenance",
+];
+
+/// Internal paths and Swagger, logged at debug level by tracing and not recorded by
+/// [Metrics](metrics::middleware::Metrics) by default
+pub const HUSHED_PATHS: [&str; INTERNAL_PATHS.len() + 1] = {
+    let mut paths = ["/swagger"; INTERNAL_PATHS.len() + 1];
+    let mut i = 0;
+    while i < INTERNAL_PATHS.len() {
+        paths[i] = INTERNAL_PATHS[i];
+        i += 1;
+    }
+    paths
+};
+
+/// Use this Cors builder if you want to send wildcard and allow default set ot http methods and headers.
+///
+/// For explicit policy loaded from configuration use [CorsConfig].
 pub fn default_cors() -> Cors {
     Cors::default()
         .send_wildcard()
@@ -40,6 +98,8 @@ pub fn default_cors() -> Cors {
         .max_age(3600)
 }
 
+/// Builds bare app without middlewares and internal endpoints
+#[deprecated(note = "use TestServer, which builds app the same way as Serwus::start")]
 pub async fn test_init<T, F>(
     prepare_app_data: impl Fn() -> T,
     configure_app: F,
@@ -4,22 +4,28 @@ use actix_web::{HttpResponse, error::Error, web};
 use futures::future::{TryFutureExt, ok as fut_ok};
 use serde::Serialize;
 
+use super::scheduler::{JobStates, JobStatus};
 use super::stats::{AppDataWrapper, BaseStats, BaseStatsInner, StatsOutput, StatsPresenter};
+use super::tasks::{TaskState, TaskStates, TaskStatus};
 
 // Prometheus stats handler
 pub async fn prometheus_stats_handler<S, D>(
     base_data: web::Data<BaseStats>,
     service_data: web::Data<S>,
+    tasks: Option<web::Data<TaskStates>>,
+    jobs: Option<web::Data<JobStates>>,
 ) -> Result<HttpResponse<BoxBody>, Error>
 where
     D: AppDataWrapper,
     S: StatsPresenter<D>,
 {
     let fut_res = service_data.get_stats().and_then(move |service_stats| {
-        if let Ok(base_stats) = base_data.0.read() {
+        if let Some(base_stats) = base_data.snapshot() {
             #[allow(clippy::unit_arg)]
             let output = StatsOutput {
-                base: base_stats.clone(),
+                base: base_stats,
+                tasks: tasks.map(|tasks| tasks.all()).unwrap_or_default(),
+                jobs: jobs.map(|jobs| jobs.all()).unwrap_or_default(),
                 service: Some(service_stats),
             };
 
@@ -42,19 +48,53 @@ pub trait AsPrometheus {
 impl AsPrometheus for BaseStatsInner {
     fn as_prometheus(&self) -> Vec<String> {
         let mut out = vec![
+            format!("start_time_seconds {}", self.started_at.timestamp()),
+            format!("uptime_seconds {}", self.uptime_secs),
             format!("request_started {}", self.request_started),
             format!("request_finished {}", self.request_finished),
+            format!("in_flight {}", self.in_flight),
+            format!("rate_limited {}", self.rate_limited),
+            format!("maintenance_rejected {}", self.maintenance_rejected),
+            format!("timeouts {}", self.timeouts),
+            format!("cache_hits {}", self.cache_hits),
+            format!("cache_misses {}", self.cache_misses),
+            format!("websocket_sessions {}", self.websocket_sessions),
+            format!("sse_streams {}", self.sse_streams),
         ];
         for (code, value) in &self.status_codes {
             out.push(format!("status_codes{{code=\"{code}\"}} {value}"));
         }
+        for (route, stats) in &self.routes {
+            let (method, pattern) = route.split_once(' ').unwrap_or(("", route));
+            let labels = format!("method=\"{method}\",route=\"{pattern}\"");
+
+            out.push(format!("route_requests{{{labels}}} {}", stats.requests));
+            out.push(format!("route_in_flight{{{labels}}} {}", stats.in_flight));
+            for (code, value) in &stats.status_codes {
+                out.push(format!(
+                    "route_status_codes{{{labels},code=\"{code}\"}} {value}"
+                ));
+            }
+            if let Some(latency) = stats.latency_ms {
+                for (quantile, value) in [
+                    ("0.5", latency.p50),
+                    ("0.9", latency.p90),
+                    ("0.99", latency.p99),
+                    ("1", latency.max),
+                ] {
+                    out.push(format!(
+                        "route_latency_ms{{{labels},quantile=\"{quantile}\"}} {value}"
+                    ));
+                }
+            }
+        }
         out
     }
 }
 
 impl AsPrometheus for BaseStats {
     fn as_prometheus(&self) -> Vec<String> {
-        if let Ok(inner) = self.0.read() {
+        if let Some(inner) = self.snapshot() {
             inner.as_prometheus()
         } else {
             Vec::new()
@@ -76,6 +116,14 @@ where
             out.push(format!("base_{stat}"));
         }
 
+        for stat in self.tasks.as_prometheus() {
+            out.push(format!("task_{stat}"));
+        }
+
+        for stat in self.jobs.as_prometheus() {
+            out.push(format!("job_{stat}"));
+        }
+
         for stat in service_stats {
             out.push(format!("service_{stat}"));
         }
@@ -83,6 +131,59 @@ where
     }
 }
 
+impl AsPrometheus for Vec<TaskStatus> {
+    fn as_prometheus(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        for task in self {
+            let running = u8::from(task.state == TaskState::Running);
+            out.push(format!("running{{name=\"{}\"}} {running}", task.name));
+            out.push(format!(
+                "restarts{{name=\"{}\"}} {}",
+                task.name, task.restarts
+            ));
+        }
+        out
+    }
+}
+
+impl AsPrometheus for Vec<JobStatus> {
+    fn as_prometheus(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        for job in self {
+            let name = &job.name;
+            out.push(format!(
+                "running{{name=\"{name}\"}} {}",
+                u8::from(job.running)
+            ));
+            out.push(format!("runs{{name=\"{name}\"}} {}", job.runs));
+            out.push(format!("failures{{name=\"{name}\"}} {}", job.failures));
+            out.push(format!("skipped{{name=\"{name}\"}} {}", job.skipped));
+            if let Some(duration) = job.last_duration_ms {
+                out.push(format!("last_duration_ms{{name=\"{name}\"}} {duration}"));
+            }
+            if let Some(last_run) = job.last_run {
+                out.push(format!(
+                    "last_run_timestamp{{name=\"{name}\"}} {}",
+                    last_run.timestamp()
+                ));
+            }
+            if let Some(last_success) = job.last_success {
+                out.push(format!(
+                    "last_success_timestamp{{name=\"{name}\"}} {}",
+                    last_success.timestamp()
+                ));
+            }
+            if let Some(next_run) = job.next_run {
+                out.push(format!(
+                    "next_run_timestamp{{name=\"{name}\"}} {}",
+                    next_run.timestamp()
+                ));
+            }
+        }
+        out
+    }
+}
+
 impl<T> AsPrometheus for Option<T>
 where
     T: AsPrometheus,
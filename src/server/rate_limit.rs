@@ -0,0 +1,371 @@
+//! Token bucket rate limiting middleware
+//!
+//! Every key (client IP, JWT subject or custom value) has its own bucket holding up to `burst`
+//! tokens, refilled at `limit` tokens per `period`. Each request takes one token; requests
+//! without tokens left are rejected with 429 [JsonError](super::json_error::JsonError).
+//!
+//! Buckets are shared by clones of [RateLimit], so create it once (outside of app factory)
+//! and clone it into every worker, otherwise each worker counts separately.
+//! Wrap a scope to get per-scope limits:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use serwus::{EmptyStats, server::{Serwus, default_cors, rate_limit::RateLimit}, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+//! # async fn list_orders() -> &'static str { "[]" }
+//! # #[actix_web::main]
+//! # async fn main() -> std::io::Result<()> {
+//! let limit = RateLimit::by_ip(10, Duration::from_secs(1));
+//!
+//! Serwus::default().start(|| AppData, move |cfg| {
+//!     cfg.service(web::scope("/api").wrap(limit.clone()).route("/orders", web::get().to(list_orders)));
+//! }, default_cors).await
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::future::{Future, Ready, ready};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    Error, ResponseError,
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
+    web,
+};
+
+use super::json_error::ErrorBuilder;
+use super::stats::BaseStats;
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+type KeyExtractor = dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync;
+
+/// Rate limiting middleware, see [module docs](self)
+#[derive(Clone)]
+pub struct RateLimit {
+    limit: u32,
+    burst: u32,
+    period: Duration,
+    key: Arc<KeyExtractor>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimit {
+    /// Limits requests by key returned from `key` to `limit` (at least 1) per `period`,
+    /// requests without key are not limited
+    pub fn by_key(
+        limit: u32,
+        period: Duration,
+        key: impl Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        let limit = limit.max(1);
+        Self {
+            limit,
+            burst: limit,
+            period,
+            key: Arc::new(key),
+            buckets: Arc::new(Mutex::new(Buckets::default())),
+        }
+    }
+
+    /// Limits requests by peer IP address
+    pub fn by_ip(limit: u32, period: Duration) -> Self {
+        Self::by_key(limit, period, peer_ip)
+    }
+
+    /// Limits requests by client IP address taken from `Forwarded`/`X-Forwarded-For` headers.
+    ///
+    /// Use only behind proxy which sets these headers, otherwise clients can choose their key.
+    pub fn by_forwarded_ip(limit: u32, period: Duration) -> Self {
+        Self::by_key(limit, period, |req| {
+            req.connection_info()
+                .realip_remote_addr()
+                .map(|addr| format!("ip:{addr}"))
+        })
+    }
+
+    /// Limits requests by JWT subject, as returned by `subject` from decoded token.
+    ///
+    /// Requests without valid token are limited by peer IP address.
+    #[cfg(feature = "auth")]
+    pub fn by_jwt<T>(
+        limit: u32,
+        period: Duration,
+        subject: impl Fn(&T) -> String + Send + Sync + 'static,
+    ) -> Self
+    where
+        T: crate::auth::jwt::FromEncoded,
+    {
+        Self::by_key(
+            limit,
+            period,
+            move |req| match crate::auth::jwt::from_request::<T>(req.request()) {
+                Ok(token) => Some(format!("sub:{}", subject(&token))),
+                Err(_) => peer_ip(req),
+            },
+        )
+    }
+
+    /// Sets bucket size, i.e. how many requests can be made at once (default: `limit`)
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    fn check(&self, key: String) -> Decision {
+        let rate = f64::from(self.limit) / self.period.as_secs_f64();
+        let capacity = f64::from(self.burst);
+
+        match self.buckets.lock() {
+            Ok(mut buckets) => buckets.take(key, rate, capacity, Instant::now()),
+            // Better to let requests through than to reject all of them
+            Err(_) => Decision::allowed(capacity, capacity, rate),
+        }
+    }
+}
+
+fn peer_ip(req: &ServiceRequest) -> Option<String> {
+    req.peer_addr().map(|addr| format!("ip:{}", addr.ip()))
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, capacity: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.updated = now;
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    cleaned: Option<Instant>,
+}
+
+impl Buckets {
+    fn take(&mut self, key: String, rate: f64, capacity: f64, now: Instant) -> Decision {
+        self.clean(rate, capacity, now);
+
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.refill(rate, capacity, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Decision::allowed(bucket.tokens, capacity, rate)
+        } else {
+            Decision {
+                allowed: false,
+                remaining: 0,
+                reset: secs_ceil(capacity - bucket.tokens, rate),
+                retry_after: Some(secs_ceil(1.0 - bucket.tokens, rate)),
+            }
+        }
+    }
+
+    /// Removes buckets which would be full by now, as they are equal to new ones
+    fn clean(&mut self, rate: f64, capacity: f64, now: Instant) {
+        let full_after = Duration::try_from_secs_f64(capacity / rate).unwrap_or(Duration::MAX);
+
+        if self
+            .cleaned
+            .is_some_and(|cleaned| now.saturating_duration_since(cleaned) < full_after)
+        {
+            return;
+        }
+
+        self.buckets.retain(|_, bucket| {
+            bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate
+                < capacity
+        });
+        self.cleaned = Some(now);
+    }
+}
+
+struct Decision {
+    allowed: bool,
+    remaining: u64,
+    /// Seconds until bucket is full again
+    reset: u64,
+    retry_after: Option<u64>,
+}
+
+impl Decision {
+    fn allowed(tokens: f64, capacity: f64, rate: f64) -> Self {
+        Self {
+            allowed: true,
+            remaining: tokens.floor() as u64,
+            reset: secs_ceil(capacity - tokens, rate),
+            retry_after: None,
+        }
+    }
+
+    fn insert_headers(&self, limit: u32, headers: &mut HeaderMap) {
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(self.reset));
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+    }
+}
+
+fn secs_ceil(tokens: f64, rate: f64) -> u64 {
+    (tokens.max(0.0) / rate).ceil() as u64
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service,
+            config: self.clone(),
+        }))
+    }
+}
+
+/// RateLimitMiddleware rejects requests exceeding the limit
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    config: RateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let Some(key) = (self.config.key)(&req) else {
+            let fut = self.service.call(req);
+            return Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) });
+        };
+
+        let decision = self.config.check(key);
+        let limit = self.config.limit;
+
+        if !decision.allowed {
+            if let Some(stats) = req.app_data::<web::Data<BaseStats>>() {
+                stats.increment(|stats| &stats.rate_limited);
+            }
+
+            let error = ErrorBuilder::too_many_requests().finish();
+            let mut res = req.into_response(error.error_response());
+            decision.insert_headers(limit, res.headers_mut());
+            return Box::pin(ready(Ok(res.map_into_right_body())));
+        }
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            decision.insert_headers(limit, res.headers_mut());
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{App, HttpResponse, http::StatusCode, test, web};
+
+    use super::RateLimit;
+
+    #[actix_web::test]
+    async fn rejects_when_bucket_is_empty() {
+        let limit = RateLimit::by_key(2, Duration::from_secs(60), |req| {
+            req.headers()
+                .get("x-client")
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        });
+
+        let app = test::init_service(
+            App::new()
+                .route("/", web::get().to(HttpResponse::Ok))
+                .wrap(limit),
+        )
+        .await;
+
+        for remaining in ["1", "0"] {
+            let req = test::TestRequest::get()
+                .insert_header(("x-client", "a"))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), remaining);
+        }
+
+        let req = test::TestRequest::get()
+            .insert_header(("x-client", "a"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("retry-after").unwrap(), "30");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["type"], "TooManyRequests");
+
+        // Other keys have their own buckets
+        let req = test::TestRequest::get()
+            .insert_header(("x-client", "b"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn treats_zero_limit_as_one() {
+        let limit = RateLimit::by_key(0, Duration::from_secs(60), |_| Some("all".to_string()));
+
+        let app = test::init_service(
+            App::new()
+                .route("/", web::get().to(HttpResponse::Ok))
+                .wrap(limit),
+        )
+        .await;
+
+        for status in [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS] {
+            let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(res.status(), status);
+        }
+    }
+}
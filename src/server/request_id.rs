@@ -0,0 +1,181 @@
+//! Request ID generation and propagation
+//!
+//! [RequestIdWrapper] takes ID from incoming `X-Request-Id` header (if it looks sane) or generates
+//! new one, stores it in request extensions and echoes it in response header.
+//! While request is handled, ID is also available with [current], which is used to put it into
+//! [JsonError](super::json_error::JsonError) bodies and log lines.
+
+use std::fmt;
+use std::future::{Future, Ready, ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    Error, FromRequest, HttpMessage, HttpRequest,
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+};
+use uuid::Uuid;
+
+use super::json_error::{ErrorBuilder, JsonError};
+
+/// Header used to pass request ID
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longer incoming IDs are replaced with generated ones
+const MAX_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// ID of currently handled request, available as extractor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// Accepts printable ASCII of reasonable length, so ID can be safely logged and echoed
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_LENGTH
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| Self(value.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = JsonError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions().get::<RequestId>().cloned().ok_or_else(|| {
+                ErrorBuilder::internal("RequestIdWrapper is not installed").finish()
+            }),
+        )
+    }
+}
+
+/// Returns ID of request handled by current task, if any
+pub fn current() -> Option<RequestId> {
+    CURRENT.try_with(|id| id.clone()).ok()
+}
+
+/// Wraps Service with RequestIdMiddleware
+#[derive(Default)]
+pub struct RequestIdWrapper;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdWrapper
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+/// RequestIdMiddleware assigns ID to every request and echoes it in response
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+
+        req.extensions_mut().insert(id.clone());
+
+        let fut = CURRENT.sync_scope(id.clone(), || self.service.call(req));
+
+        Box::pin(CURRENT.scope(id.clone(), async move {
+            let mut res = fut.await?;
+
+            if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+
+            Ok(res)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, test, web};
+
+    use super::{REQUEST_ID_HEADER, RequestIdWrapper};
+    use crate::server::json_error::{ErrorBuilder, JsonError};
+
+    async fn failing() -> Result<&'static str, JsonError> {
+        Err(ErrorBuilder::bad_request("Nope").finish())
+    }
+
+    #[actix_web::test]
+    async fn propagates_request_id() {
+        let app = test::init_service(
+            App::new()
+                .route("/", web::get().to(failing))
+                .wrap(RequestIdWrapper),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .insert_header((REQUEST_ID_HEADER, "abc-123"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-123");
+
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["request_id"], "abc-123");
+
+        let req = test::TestRequest::get()
+            .insert_header((REQUEST_ID_HEADER, "bad id"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let generated = res.headers().get(REQUEST_ID_HEADER).unwrap();
+        assert_eq!(generated.len(), 36);
+    }
+}
@@ -0,0 +1,443 @@
+//! Server-side cache of GET responses
+//!
+//! Wrap scope or resource with [ResponseCache] to serve its successful `GET` responses
+//! from memory for `ttl`. Cache key is made of path, query params chosen with
+//! [query_params](ResponseCache::query_params) (others are ignored) and, if set,
+//! client subject (f. ex. from JWT, see [by_subject](ResponseCache::by_subject)).
+//!
+//! Entries are shared by clones of [ResponseCache], so create it once (outside of app factory),
+//! clone it into every worker and keep a clone to purge entries after data changes:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use serwus::{EmptyStats, server::{Serwus, default_cors, response_cache::ResponseCache}, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+//! # async fn list_articles() -> &'static str { "[]" }
+//! #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+//! async fn update_article(cache: web::Data<ResponseCache>) -> &'static str {
+//!     // ...
+//!     cache.purge("/articles");
+//!     "{}"
+//! }
+//!
+//! # #[actix_web::main]
+//! # async fn main() -> std::io::Result<()> {
+//! let cache = ResponseCache::new(Duration::from_secs(30)).query_params(&["page", "limit"]);
+//!
+//! Serwus::default().start(|| AppData, move |cfg| {
+//!     cfg.app_data(web::Data::new(cache.clone())).service(
+//!         web::scope("/articles")
+//!             .wrap(cache.clone())
+//!             .route("", web::get().to(list_articles))
+//!             .route("/{id}", web::put().to(update_article)),
+//!     );
+//! }, default_cors).await
+//! # }
+//! ```
+//!
+//! Without subject requests with `Authorization` or `Cookie` header are not cached, so responses
+//! of one user are never served to another. Responses other than 200 and ones setting cookies,
+//! having `Vary` header (key doesn't cover request headers) or `Cache-Control: no-store`/`private`
+//! are not cached either.
+//! Served responses have `X-Cache: HIT` or `MISS` header, and are counted as
+//! `cache_hits`/`cache_misses` in base stats.
+
+use std::future::{Future, Ready, ready};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    Error, HttpResponse,
+    body::{self, BoxBody, EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        Method, StatusCode,
+        header::{
+            AUTHORIZATION, CACHE_CONTROL, COOKIE, HeaderMap, HeaderName, HeaderValue, SET_COOKIE,
+            VARY,
+        },
+    },
+    web,
+};
+use bytes::Bytes;
+
+use super::stats::BaseStats;
+use crate::containers::cache_map::SharedCacheMap;
+
+pub const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+type KeyExtractor = dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    path: String,
+    query: String,
+    subject: String,
+}
+
+#[derive(Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl CachedResponse {
+    fn to_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status);
+        for (name, value) in &self.headers {
+            res.append_header((name.clone(), value.clone()));
+        }
+        res.insert_header((X_CACHE, HeaderValue::from_static("HIT")));
+        res.body(self.body.clone())
+    }
+}
+
+/// Response caching middleware, see [module docs](self)
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: SharedCacheMap<CacheKey, CachedResponse>,
+    query_params: Arc<Vec<String>>,
+    subject: Option<Arc<KeyExtractor>>,
+    max_entries: usize,
+}
+
+impl ResponseCache {
+    /// Caches responses for `ttl` (rounded to seconds, at least one)
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: SharedCacheMap::new(ttl.as_secs().max(1)),
+            query_params: Arc::new(Vec::new()),
+            subject: None,
+            max_entries: 10_000,
+        }
+    }
+
+    /// Sets query params which change response, f. ex. `page`, other params are not part of the key
+    pub fn query_params(mut self, params: &[&str]) -> Self {
+        let mut params: Vec<_> = params.iter().map(|param| param.to_string()).collect();
+        params.sort();
+        self.query_params = Arc::new(params);
+        self
+    }
+
+    /// Caches responses separately for every subject returned by `subject`
+    /// (requests without subject share one entry)
+    pub fn by_subject(
+        mut self,
+        subject: impl Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.subject = Some(Arc::new(subject));
+        self
+    }
+
+    /// Caches responses separately for every JWT subject, as returned by `subject` from decoded token
+    #[cfg(feature = "auth")]
+    pub fn by_jwt<T>(self, subject: impl Fn(&T) -> String + Send + Sync + 'static) -> Self
+    where
+        T: crate::auth::jwt::FromEncoded,
+    {
+        self.by_subject(move |req| {
+            crate::auth::jwt::from_request::<T>(req.request())
+                .ok()
+                .map(|token| subject(&token))
+        })
+    }
+
+    /// Sets maximal number of cached responses (default: 10000), when it's reached
+    /// new responses are not cached until old ones expire
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Removes entries of `path_prefix` and paths below it (`/articles` covers `/articles/1`,
+    /// but not `/articles-archive`), returns number of removed ones
+    pub fn purge(&self, path_prefix: &str) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|key| !is_under_path(&key.path, path_prefix));
+        before.saturating_sub(self.entries.len())
+    }
+
+    /// Removes all entries
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
+    /// Key of request, `None` if request is not cacheable
+    fn key(&self, req: &ServiceRequest) -> Option<CacheKey> {
+        if req.method() != Method::GET {
+            return None;
+        }
+
+        let subject = match &self.subject {
+            Some(subject) => subject(req).unwrap_or_default(),
+            None if req.headers().contains_key(AUTHORIZATION)
+                || req.headers().contains_key(COOKIE) =>
+            {
+                return None;
+            }
+            None => String::new(),
+        };
+
+        let query = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+            .map(|query| query.into_inner())
+            .unwrap_or_default();
+        let mut selected: Vec<_> = query
+            .into_iter()
+            .filter(|(name, _)| self.query_params.contains(name))
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        selected.sort();
+
+        Some(CacheKey {
+            path: req.path().to_string(),
+            query: selected.join("&"),
+            subject,
+        })
+    }
+
+    fn store(&self, key: CacheKey, response: CachedResponse) {
+        if self.entries.len() >= self.max_entries {
+            self.entries.remove_expired();
+            if self.entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        self.entries.insert(key, response);
+    }
+}
+
+/// Whether `path` is `prefix` or lies below it, matching whole path segments
+fn is_under_path(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+fn is_cacheable(status: StatusCode, headers: &HeaderMap) -> bool {
+    let forbidden = headers
+        .get_all(CACHE_CONTROL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|directive| {
+            directive.eq_ignore_ascii_case("no-store") || directive.eq_ignore_ascii_case("private")
+        });
+
+    status == StatusCode::OK
+        && !forbidden
+        && !headers.contains_key(SET_COOKIE)
+        && !headers.contains_key(VARY)
+}
+
+fn count(req: &ServiceRequest, hit: bool) {
+    if let Some(stats) = req.app_data::<web::Data<BaseStats>>() {
+        match hit {
+            true => stats.increment(|stats| &stats.cache_hits),
+            false => stats.increment(|stats| &stats.cache_misses),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ResponseCache
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ResponseCacheMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ResponseCacheMiddleware {
+            service,
+            cache: self.clone(),
+        }))
+    }
+}
+
+/// ResponseCacheMiddleware serves cached responses and stores new ones
+pub struct ResponseCacheMiddleware<S> {
+    service: S,
+    cache: ResponseCache,
+}
+
+impl<S, B> Service<ServiceRequest> for ResponseCacheMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let Some(key) = self.cache.key(&req) else {
+            let fut = self.service.call(req);
+            return Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) });
+        };
+
+        if let Some(cached) = self.cache.entries.get_fresh(&key) {
+            count(&req, true);
+            let res = req.into_response(cached.to_response());
+            return Box::pin(ready(Ok(res.map_into_right_body())));
+        }
+
+        count(&req, false);
+        let cache = self.cache.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            res.headers_mut()
+                .insert(X_CACHE, HeaderValue::from_static("MISS"));
+
+            if !is_cacheable(res.status(), res.headers()) {
+                return Ok(res.map_into_left_body());
+            }
+
+            let (req, res) = res.into_parts();
+            let (res, body) = res.into_parts();
+            let body = body::to_bytes(body)
+                .await
+                .map_err(|err| actix_web::error::ErrorInternalServerError(err.into()))?;
+
+            let mut headers = res.headers().clone();
+            headers.remove(X_CACHE);
+            cache.store(
+                key,
+                CachedResponse {
+                    status: res.status(),
+                    headers,
+                    body: body.clone(),
+                },
+            );
+
+            let res = res.set_body(BoxBody::new(body));
+            Ok(ServiceResponse::new(req, res).map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use actix_web::{App, HttpResponse, dev::ServiceResponse, test, web};
+
+    use super::{ResponseCache, is_under_path};
+    use crate::server::stats::BaseStats;
+
+    #[actix_web::test]
+    async fn serves_cached_responses_until_purged() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let cache = ResponseCache::new(Duration::from_secs(60)).query_params(&["page"]);
+        let stats = BaseStats::default();
+
+        let app = test::init_service(App::new().app_data(web::Data::new(stats.clone())).service(
+            web::scope("/items").wrap(cache.clone()).route(
+                "",
+                web::get().to(move || {
+                    let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                    async move { HttpResponse::Ok().body(format!("call {call}")) }
+                }),
+            ),
+        ))
+        .await;
+
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+        let x_cache = |res: &ServiceResponse| res.headers().get("x-cache").cloned();
+
+        let res = test::call_service(&app, get("/items?page=1")).await;
+        assert_eq!(x_cache(&res).unwrap(), "MISS");
+        assert_eq!(test::read_body(res).await, "call 1");
+
+        let res = test::call_service(&app, get("/items?debug=1&page=1")).await;
+        assert_eq!(x_cache(&res).unwrap(), "HIT");
+        assert_eq!(test::read_body(res).await, "call 1");
+
+        let res = test::call_service(&app, get("/items?page=2")).await;
+        assert_eq!(test::read_body(res).await, "call 2");
+
+        // Without subject extractor authorized requests are not cached
+        let req = test::TestRequest::get()
+            .uri("/items?page=1")
+            .insert_header(("authorization", "Bearer token"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(x_cache(&res).is_none());
+        assert_eq!(test::read_body(res).await, "call 3");
+
+        // Nor ones with cookies
+        let req = test::TestRequest::get()
+            .uri("/items?page=1")
+            .insert_header(("cookie", "session=1"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(x_cache(&res).is_none());
+        assert_eq!(test::read_body(res).await, "call 4");
+
+        assert_eq!(cache.purge("/item"), 0);
+        assert_eq!(cache.purge("/items"), 2);
+        let res = test::call_service(&app, get("/items?page=1")).await;
+        assert_eq!(x_cache(&res).unwrap(), "MISS");
+        assert_eq!(test::read_body(res).await, "call 5");
+
+        let stats = stats.snapshot().unwrap();
+        assert_eq!(stats.cache_hits, 1);
+        assert_eq!(stats.cache_misses, 3);
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[actix_web::test]
+    async fn skips_varying_responses() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+
+        let app = test::init_service(App::new().service(
+            web::scope("/items").wrap(cache.clone()).route(
+                "",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .insert_header(("vary", "accept-language"))
+                        .body("items")
+                }),
+            ),
+        ))
+        .await;
+
+        for _ in 0..2 {
+            let req = test::TestRequest::get().uri("/items").to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.headers().get("x-cache").unwrap(), "MISS");
+        }
+    }
+
+    #[actix_web::test]
+    async fn purges_whole_path_segments() {
+        assert!(is_under_path("/items", "/items"));
+        assert!(is_under_path("/items/1", "/items"));
+        assert!(is_under_path("/items/1", "/items/"));
+        assert!(!is_under_path("/items-archive", "/items"));
+        assert!(!is_under_path("/item", "/items"));
+    }
+}
@@ -0,0 +1,381 @@
+//! Periodic jobs
+//!
+//! Jobs added with [Serwus::add_job](super::Serwus::add_job) run on fixed interval or,
+//! with `cron` feature, on cron schedule (UTC). Runs of the same job never overlap:
+//! ticks missed while job was running are skipped and counted.
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use serwus::{EmptyStats, server::{Job, Serwus, default_cors, tasks::TaskContext}, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # async fn delete_expired(_app_data: web::Data<AppData>) -> Result<(), String> { Ok(()) }
+//! # async fn send_report(_ctx: TaskContext) -> Result<(), String> { Ok(()) }
+//! # fn configure_app(_cfg: &mut web::ServiceConfig) {}
+//! # #[actix_web::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let serwus = Serwus::default().add_job(Job::every("cleanup", Duration::from_secs(60), |ctx| async move {
+//!     delete_expired(ctx.app_data::<AppData>().unwrap()).await
+//! }));
+//!
+//! // With `cron` feature
+//! # #[cfg(feature = "cron")]
+//! let serwus =
+//!     serwus.add_job(Job::cron("report", "0 0 6 * * Mon-Fri", send_report)?.timeout(Duration::from_secs(300)));
+//!
+//! serwus.start(|| AppData, configure_app, default_cors).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Jobs get the same [TaskContext] as background tasks. Last run, duration, error and next run
+//! of every job are reported by `/_stats` and `/_prometheus`.
+
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::FutureExt;
+use log::{error, info, warn};
+use serde::Serialize;
+
+use super::tasks::{TaskContext, panic_message};
+
+/// Shortest interval of [Job::every], shorter ones (including zero) are raised to it
+pub const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+type JobFn =
+    Box<dyn Fn(TaskContext) -> Pin<Box<dyn Future<Output = Result<(), String>>>> + Send + Sync>;
+
+/// When job runs
+pub enum Schedule {
+    Every(Duration),
+    /// Cron expression with seconds, f. ex. `0 */5 * * * *`
+    #[cfg(feature = "cron")]
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// First run after given time
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Every(interval) => TimeDelta::from_std(*interval)
+                .ok()
+                .and_then(|interval| after.checked_add_signed(interval)),
+            #[cfg(feature = "cron")]
+            Self::Cron(schedule) => schedule.after(&after).next(),
+        }
+    }
+
+    /// First run after `now` following run planned at `planned`, with number of skipped runs
+    fn next_skipping_missed(
+        &self,
+        planned: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> (Option<DateTime<Utc>>, usize) {
+        let mut skipped = 0;
+        let mut next = self.next_after(planned);
+
+        if let (Self::Every(interval), Some(at)) = (self, next)
+            && at <= now
+            && !interval.is_zero()
+        {
+            // Jump over all missed intervals at once
+            let missed = ((now - at).to_std().unwrap_or_default().as_nanos() / interval.as_nanos())
+                as usize
+                + 1;
+            let jump = interval.saturating_mul(missed as u32);
+            return (
+                TimeDelta::from_std(jump)
+                    .ok()
+                    .and_then(|jump| at.checked_add_signed(jump)),
+                missed,
+            );
+        }
+
+        while let Some(at) = next
+            && at <= now
+        {
+            skipped += 1;
+            next = self.next_after(at);
+        }
+
+        (next, skipped)
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Every(interval) => write!(f, "every {interval:?}"),
+            #[cfg(feature = "cron")]
+            Self::Cron(schedule) => write!(f, "{}", schedule.source()),
+        }
+    }
+}
+
+/// Periodic job, see [module docs](self)
+pub struct Job {
+    name: String,
+    schedule: Schedule,
+    factory: JobFn,
+    timeout: Option<Duration>,
+}
+
+impl Job {
+    /// Job running with given schedule, `factory` is called for every run
+    pub fn new<F, Fut, E>(name: impl Into<String>, mut schedule: Schedule, factory: F) -> Self
+    where
+        F: Fn(TaskContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + 'static,
+        E: Display,
+    {
+        match &mut schedule {
+            Schedule::Every(interval) => *interval = (*interval).max(MIN_INTERVAL),
+            #[cfg(feature = "cron")]
+            Schedule::Cron(_) => {}
+        }
+
+        Self {
+            name: name.into(),
+            schedule,
+            factory: Box::new(move |ctx| {
+                let fut = factory(ctx);
+                Box::pin(async move { fut.await.map_err(|err| err.to_string()) })
+            }),
+            timeout: None,
+        }
+    }
+
+    /// Job running every `interval` (at least [MIN_INTERVAL]), first time `interval` after server start
+    pub fn every<F, Fut, E>(name: impl Into<String>, interval: Duration, factory: F) -> Self
+    where
+        F: Fn(TaskContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + 'static,
+        E: Display,
+    {
+        Self::new(name, Schedule::Every(interval), factory)
+    }
+
+    /// Job running on cron schedule with seconds field, f. ex. `0 30 2 * * *` (every day at 2:30 UTC)
+    #[cfg(feature = "cron")]
+    pub fn cron<F, Fut, E>(
+        name: impl Into<String>,
+        expression: &str,
+        factory: F,
+    ) -> Result<Self, cron::error::Error>
+    where
+        F: Fn(TaskContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + 'static,
+        E: Display,
+    {
+        let schedule = expression.parse::<cron::Schedule>()?;
+        Ok(Self::new(name, Schedule::Cron(Box::new(schedule)), factory))
+    }
+
+    /// Fails run not finished in given time
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub schedule: String,
+    pub running: bool,
+    pub runs: usize,
+    pub failures: usize,
+    /// Runs skipped because previous one was still running
+    pub skipped: usize,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_duration_ms: Option<u64>,
+    pub last_success: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+/// States of all jobs, available to handlers as `web::Data<JobStates>`
+#[derive(Clone, Default)]
+pub struct JobStates(Arc<RwLock<Vec<JobStatus>>>);
+
+impl JobStates {
+    pub fn all(&self) -> Vec<JobStatus> {
+        self.0.read().map(|jobs| jobs.clone()).unwrap_or_default()
+    }
+
+    fn register(&self, job: &Job) -> usize {
+        let mut jobs = self.0.write().unwrap_or_else(|err| err.into_inner());
+        jobs.push(JobStatus {
+            name: job.name.clone(),
+            schedule: job.schedule.to_string(),
+            running: false,
+            runs: 0,
+            failures: 0,
+            skipped: 0,
+            last_run: None,
+            last_duration_ms: None,
+            last_success: None,
+            last_error: None,
+            next_run: None,
+        });
+        jobs.len() - 1
+    }
+
+    fn update(&self, index: usize, f: impl FnOnce(&mut JobStatus)) {
+        if let Ok(mut jobs) = self.0.write()
+            && let Some(job) = jobs.get_mut(index)
+        {
+            f(job);
+        }
+    }
+}
+
+/// Spawns scheduler of every job on current runtime
+pub(super) fn spawn_jobs(jobs: Vec<Job>, ctx: TaskContext, states: JobStates) {
+    for job in jobs {
+        let index = states.register(&job);
+        actix_web::rt::spawn(schedule_job(job, ctx.clone(), states.clone(), index));
+    }
+}
+
+async fn schedule_job(job: Job, ctx: TaskContext, states: JobStates, index: usize) {
+    let shutdown = ctx.shutdown().clone();
+    let mut next = job.schedule.next_after(Utc::now());
+
+    while let Some(planned) = next {
+        states.update(index, |status| status.next_run = Some(planned));
+
+        let wait = (planned - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown.wait() => return,
+        }
+
+        let started_at = Utc::now();
+        states.update(index, |status| {
+            status.running = true;
+            status.last_run = Some(started_at);
+        });
+
+        let started = Instant::now();
+        let run = AssertUnwindSafe((job.factory)(ctx.clone())).catch_unwind();
+        let outcome = match job.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run)
+                .await
+                .unwrap_or_else(|_| Ok(Err(format!("timed out after {timeout:?}")))),
+            None => run.await,
+        };
+        let error = match outcome {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err),
+            Err(panic) => Some(panic_message(panic)),
+        };
+        let duration = started.elapsed();
+
+        let (following, skipped) = job.schedule.next_skipping_missed(planned, Utc::now());
+        if skipped > 0 {
+            warn!(
+                "Job {} took {duration:?}, skipped {skipped} run(s)",
+                job.name
+            );
+        }
+
+        match &error {
+            Some(err) => error!("Job {} failed after {duration:?}: {err}", job.name),
+            None => info!("Job {} finished in {duration:?}", job.name),
+        }
+
+        states.update(index, |status| {
+            status.running = false;
+            status.runs += 1;
+            status.skipped += skipped;
+            status.last_duration_ms = Some(duration.as_millis() as u64);
+            match error {
+                Some(err) => {
+                    status.failures += 1;
+                    status.last_error = Some(err);
+                }
+                None => status.last_success = Some(started_at),
+            }
+            status.next_run = following;
+        });
+
+        if shutdown.is_draining() {
+            return;
+        }
+        next = following;
+    }
+
+    info!("Job {} has no more runs scheduled", job.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::web;
+
+    use super::{Job, JobStates, Schedule, spawn_jobs};
+    use crate::server::{shutdown::Shutdown, tasks::TaskContext};
+
+    #[test]
+    fn skips_missed_intervals() {
+        let schedule = Schedule::Every(Duration::from_secs(10));
+        let planned = chrono::Utc::now();
+
+        let (next, skipped) = schedule.next_skipping_missed(planned, planned);
+        assert_eq!(next, Some(planned + chrono::TimeDelta::seconds(10)));
+        assert_eq!(skipped, 0);
+
+        let now = planned + chrono::TimeDelta::seconds(25);
+        let (next, skipped) = schedule.next_skipping_missed(planned, now);
+        assert_eq!(next, Some(planned + chrono::TimeDelta::seconds(30)));
+        assert_eq!(skipped, 2);
+    }
+
+    #[test]
+    fn raises_zero_interval() {
+        let job = Job::every("busy", Duration::ZERO, |_ctx| async { Ok::<_, String>(()) });
+        assert_eq!(job.schedule.to_string(), "every 1ms");
+
+        let planned = chrono::Utc::now();
+        let now = planned + chrono::TimeDelta::milliseconds(5);
+        let (next, skipped) = job.schedule.next_skipping_missed(planned, now);
+        assert_eq!(next, Some(planned + chrono::TimeDelta::milliseconds(6)));
+        assert_eq!(skipped, 5);
+    }
+
+    #[actix_web::test]
+    async fn reports_runs_and_timeouts() {
+        let ctx = TaskContext::new(web::Data::new(()), Shutdown::default());
+        let states = JobStates::default();
+
+        let job = Job::every("slow", Duration::from_millis(20), |_ctx| async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok::<_, String>(())
+        })
+        .timeout(Duration::from_millis(5));
+
+        spawn_jobs(vec![job], ctx, states.clone());
+
+        for _ in 0..100 {
+            if states.all()[0].runs > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let status = &states.all()[0];
+        assert_eq!(status.schedule, "every 20ms");
+        assert!(status.failures > 0);
+        assert_eq!(status.last_error.as_deref(), Some("timed out after 5ms"));
+        assert!(status.next_run.is_some());
+    }
+}
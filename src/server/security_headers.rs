@@ -0,0 +1,197 @@
+//! Security headers presets
+//!
+//! [SecurityHeaders] adds headers missing in response, so handlers can still set their own.
+//! It can be set globally with [Serwus::set_security_headers](super::Serwus::set_security_headers)
+//! and overridden per scope: the innermost [SecurityHeaders] decides, outer ones leave
+//! its response intact.
+//!
+//! ```no_run
+//! # use actix_web::http::header::CONTENT_SECURITY_POLICY;
+//! # use serwus::{EmptyStats, server::{Serwus, default_cors, security_headers::SecurityHeaders}, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+//! # async fn docs() -> &'static str { "" }
+//! # #[actix_web::main]
+//! # async fn main() -> std::io::Result<()> {
+//! Serwus::default()
+//!     .set_security_headers(SecurityHeaders::strict())
+//!     .start(|| AppData, |cfg| {
+//!         cfg.service(
+//!             web::scope("/docs")
+//!                 .wrap(SecurityHeaders::relaxed().remove(CONTENT_SECURITY_POLICY))
+//!                 .route("", web::get().to(docs)),
+//!         );
+//!     }, default_cors)
+//!     .await
+//! # }
+//! ```
+
+use std::future::{Future, Ready, ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    Error,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{
+        CONTENT_SECURITY_POLICY, HeaderName, HeaderValue, REFERRER_POLICY,
+        STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    },
+};
+
+/// Response extension set when headers have been added by some [SecurityHeaders]
+struct Applied;
+
+/// Security headers middleware, see [module docs](self)
+#[derive(Clone, Debug, Default)]
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl SecurityHeaders {
+    /// No headers, use [set](Self::set) to add them
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// For JSON APIs: HSTS (2 years with subdomains), `nosniff`, `DENY` framing, `no-referrer`
+    /// and CSP disallowing any content.
+    ///
+    /// Breaks HTML pages, like swagger UI, use [relaxed](Self::relaxed) for them.
+    pub fn strict() -> Self {
+        Self::empty()
+            .hsts(Duration::from_secs(63_072_000), true)
+            .set(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"))
+            .set(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"))
+            .set(REFERRER_POLICY, HeaderValue::from_static("no-referrer"))
+            .set(
+                CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static("default-src 'none'; frame-ancestors 'none'"),
+            )
+    }
+
+    /// For services serving HTML too: HSTS (1 year), `nosniff`, `SAMEORIGIN` framing,
+    /// `strict-origin-when-cross-origin` and CSP allowing content from the same origin.
+    pub fn relaxed() -> Self {
+        Self::empty()
+            .hsts(Duration::from_secs(31_536_000), false)
+            .set(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"))
+            .set(X_FRAME_OPTIONS, HeaderValue::from_static("SAMEORIGIN"))
+            .set(
+                REFERRER_POLICY,
+                HeaderValue::from_static("strict-origin-when-cross-origin"),
+            )
+            .set(
+                CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static(
+                    "default-src 'self'; img-src 'self' data:; style-src 'self' 'unsafe-inline'; frame-ancestors 'self'",
+                ),
+            )
+    }
+
+    /// Adds or replaces header
+    pub fn set(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.retain(|(existing, _)| *existing != name);
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Removes header from preset
+    pub fn remove(mut self, name: HeaderName) -> Self {
+        self.headers.retain(|(existing, _)| *existing != name);
+        self
+    }
+
+    /// Sets `Strict-Transport-Security` header
+    pub fn hsts(self, max_age: Duration, include_subdomains: bool) -> Self {
+        let mut value = format!("max-age={}", max_age.as_secs());
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        match HeaderValue::from_str(&value) {
+            Ok(value) => self.set(STRICT_TRANSPORT_SECURITY, value),
+            Err(_) => self,
+        }
+    }
+
+    /// Sets `Content-Security-Policy` header, invalid values are ignored
+    pub fn csp(self, policy: &str) -> Self {
+        match HeaderValue::from_str(policy) {
+            Ok(value) => self.set(CONTENT_SECURITY_POLICY, value),
+            Err(_) => {
+                log::error!("Invalid Content-Security-Policy: {policy}");
+                self
+            }
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SecurityHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SecurityHeadersMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SecurityHeadersMiddleware {
+            service,
+            config: Rc::new(self.clone()),
+        }))
+    }
+}
+
+/// SecurityHeadersMiddleware adds configured headers to every response
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+    config: Rc<SecurityHeaders>,
+}
+
+impl<S, B> Service<ServiceRequest> for SecurityHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let config = self.config.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+
+            if res.response().extensions().contains::<Applied>() {
+                return Ok(res);
+            }
+
+            let headers = res.headers_mut();
+            for (name, value) in &config.headers {
+                if !headers.contains_key(name) {
+                    headers.insert(name.clone(), value.clone());
+                }
+            }
+            res.response_mut().extensions_mut().insert(Applied);
+
+            Ok(res)
+        })
+    }
+}
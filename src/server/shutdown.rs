@@ -0,0 +1,180 @@
+//! Graceful shutdown with readiness drain
+//!
+//! On `SIGTERM` the server flips `/_ready` to 503 at once, keeps serving requests for the drain
+//! period (so load balancers have time to notice) and only then stops `HttpServer` gracefully, letting
+//! in-flight requests finish within shutdown timeout. `SIGINT` and `SIGQUIT` stop the server immediately.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+use actix_web::rt::time::sleep;
+use log::{info, warn};
+use tokio::sync::watch;
+
+use super::stats::BaseStats;
+
+/// Shared shutdown state, available to handlers as `web::Data<Shutdown>`
+#[derive(Clone)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+}
+
+impl Shutdown {
+    /// Returns `true` if server received stop signal and is draining
+    pub fn is_draining(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Marks server as draining and wakes up all waiting futures
+    pub fn begin(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Resolves when shutdown begins (immediately if it already began)
+    pub async fn wait(&self) {
+        let mut receiver = self.0.subscribe();
+        // Sender is kept alive by self, so error is not possible here
+        let _ = receiver.wait_for(|draining| *draining).await;
+    }
+}
+
+/// Waits for shutdown when server has one (app data from request), forever otherwise
+pub(super) async fn wait_for_shutdown(shutdown: Option<Shutdown>) {
+    match shutdown {
+        Some(shutdown) => shutdown.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+enum StopSignal {
+    Graceful,
+    Immediate,
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> StopSignal {
+    use actix_web::rt::signal::unix::{SignalKind, signal};
+
+    let (Ok(mut term), Ok(mut int), Ok(mut quit)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::quit()),
+    ) else {
+        warn!("Can't register signal handlers, falling back to Ctrl-C");
+        let _ = actix_web::rt::signal::ctrl_c().await;
+        return StopSignal::Immediate;
+    };
+
+    tokio::select! {
+        _ = term.recv() => {
+            info!("SIGTERM received, starting graceful shutdown");
+            StopSignal::Graceful
+        }
+        _ = int.recv() => {
+            info!("SIGINT received, stopping");
+            StopSignal::Immediate
+        }
+        _ = quit.recv() => {
+            info!("SIGQUIT received, stopping");
+            StopSignal::Immediate
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> StopSignal {
+    let _ = actix_web::rt::signal::ctrl_c().await;
+    info!("Ctrl-C received, stopping");
+    StopSignal::Immediate
+}
+
+/// Waits for stop signal, then drains and stops servers
+///
+/// Waiting for in-flight requests is left to graceful stop of `HttpServer`, so whole shutdown
+/// takes at most drain period plus shutdown timeout.
+pub(super) async fn handle_signals(
+    handles: Vec<ServerHandle>,
+    shutdown: Shutdown,
+    stats: BaseStats,
+    drain: Duration,
+) {
+    match wait_for_signal().await {
+        StopSignal::Graceful => {
+            shutdown.begin();
+
+            if !drain.is_zero() {
+                info!("Readiness switched off, draining for {drain:?}");
+                sleep(drain).await;
+            }
+
+            let in_flight = stats.in_flight();
+            if in_flight > 0 {
+                info!("Stopping, waiting for {in_flight} request(s) in flight");
+            }
+
+            stop_all(&handles, true).await;
+        }
+        StopSignal::Immediate => {
+            shutdown.begin();
+            stop_all(&handles, false).await;
+        }
+    }
+}
+
+async fn stop_all(handles: &[ServerHandle], graceful: bool) {
+    futures::future::join_all(handles.iter().map(|handle| handle.stop(graceful))).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, Error, http::StatusCode, test, web};
+    use std::future::{Future, ready};
+    use std::pin::Pin;
+
+    use super::Shutdown;
+    use crate::server::stats::{StatsPresenter, default_readiness_handler};
+
+    struct AppData;
+
+    impl StatsPresenter<()> for AppData {
+        fn is_ready(&self) -> Pin<Box<dyn Future<Output = Result<bool, Error>>>> {
+            Box::pin(ready(Ok(true)))
+        }
+
+        fn get_stats(&self) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+            Box::pin(ready(Ok(())))
+        }
+    }
+
+    #[actix_web::test]
+    async fn readiness_fails_when_draining() {
+        let shutdown = Shutdown::default();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppData))
+                .app_data(web::Data::new(shutdown.clone()))
+                .route(
+                    "/_ready",
+                    web::get().to(default_readiness_handler::<AppData, ()>),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/_ready").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        shutdown.begin();
+        shutdown.wait().await;
+
+        let req = test::TestRequest::get().uri("/_ready").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
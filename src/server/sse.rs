@@ -0,0 +1,371 @@
+//! Server-Sent Events (`text/event-stream`) responses
+//!
+//! [Sse] turns any `Stream` of [SseEvent]s into response, sending keep-alive comments when
+//! stream is quiet and ending it when graceful shutdown begins, so clients reconnect to other
+//! instance. Events are written only as fast as client reads them (stream is polled when
+//! socket is ready), and when client disconnects the stream is dropped, so producers notice it
+//! by closed channel. Number of open streams is reported as `sse_streams` in base stats.
+//!
+//! [SseBroadcast] sends events to all subscribers and keeps last ones in bounded buffer,
+//! so reconnecting clients get what they missed according to `Last-Event-ID` header:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use actix_web::{HttpRequest, Responder, web};
+//! # use serde::Serialize;
+//! # use serwus::server::sse::{Sse, SseBroadcast, last_event_id};
+//! # #[derive(Clone, Serialize)]
+//! # struct Notification { text: String }
+//! # impl Notification {
+//! #     fn new(text: &str) -> Self { Self { text: text.to_string() } }
+//! # }
+//! async fn notifications(req: HttpRequest, feed: web::Data<SseBroadcast<Notification>>) -> impl Responder {
+//!     Sse::new(feed.subscribe(last_event_id(&req))).retry(Duration::from_secs(3))
+//! }
+//!
+//! // anywhere else
+//! # let feed = web::Data::new(SseBroadcast::new(100));
+//! feed.send(Notification::new("Hello"));
+//! ```
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    body::BoxBody,
+    http::header::{CACHE_CONTROL, CONTENT_TYPE, HeaderName, HeaderValue},
+    web,
+};
+use bytes::Bytes;
+use futures::{Stream, StreamExt, stream};
+use log::{error, warn};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{Instant, Interval, MissedTickBehavior, interval_at};
+
+use super::shutdown::{Shutdown, wait_for_shutdown};
+use super::stats::{BaseStats, StatsGauge};
+
+pub const LAST_EVENT_ID: HeaderName = HeaderName::from_static("last-event-id");
+const X_ACCEL_BUFFERING: HeaderName = HeaderName::from_static("x-accel-buffering");
+
+/// Single event with optional id and name (`message` is used by browsers without name)
+#[derive(Clone, Debug)]
+pub struct SseEvent<T> {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: T,
+}
+
+impl<T: Serialize> SseEvent<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            id: None,
+            event: None,
+            data,
+        }
+    }
+
+    /// Sets id, which client sends back in `Last-Event-ID` when reconnecting
+    pub fn id(mut self, id: impl ToString) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Sets name of event, to be handled with `addEventListener(name, ...)` in browser
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Event in wire format, data serialized as JSON
+    fn to_bytes(&self) -> Result<Bytes, serde_json::Error> {
+        let data = serde_json::to_string(&self.data)?;
+
+        let mut buf = String::with_capacity(data.len() + 32);
+        if let Some(id) = &self.id {
+            push_field(&mut buf, "id", id);
+        }
+        if let Some(event) = &self.event {
+            push_field(&mut buf, "event", event);
+        }
+        push_field(&mut buf, "data", &data);
+        buf.push('\n');
+
+        Ok(Bytes::from(buf))
+    }
+}
+
+/// Writes field line, dropping line breaks which would end it prematurely
+fn push_field(buf: &mut String, name: &str, value: &str) {
+    buf.push_str(name);
+    buf.push_str(": ");
+    buf.extend(value.chars().filter(|ch| *ch != '\n' && *ch != '\r'));
+    buf.push('\n');
+}
+
+/// Numeric `Last-Event-ID` sent by reconnecting client, as used by [SseBroadcast]
+pub fn last_event_id(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Streaming response of events, see [module docs](self)
+pub struct Sse<S> {
+    events: S,
+    keep_alive: Duration,
+    retry: Option<Duration>,
+}
+
+impl<S, T> Sse<S>
+where
+    S: Stream<Item = SseEvent<T>> + 'static,
+    T: Serialize,
+{
+    pub fn new(events: S) -> Self {
+        Self {
+            events,
+            keep_alive: Duration::from_secs(15),
+            retry: None,
+        }
+    }
+
+    /// Sets interval of keep-alive comments sent when there are no events,
+    /// so proxies don't close idle connection (default: 15 s)
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    /// Sets time after which client should reconnect when connection is lost
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+/// State of response body, dropped (with gauge) when stream ends or client disconnects
+struct SseBody<S> {
+    events: Pin<Box<S>>,
+    keep_alive: Interval,
+    shutdown: Option<Shutdown>,
+    retry: Option<Duration>,
+    _gauge: StatsGauge,
+}
+
+impl<S, T> SseBody<S>
+where
+    S: Stream<Item = SseEvent<T>>,
+    T: Serialize,
+{
+    async fn next_chunk(&mut self) -> Option<Bytes> {
+        if let Some(retry) = self.retry.take() {
+            return Some(Bytes::from(format!("retry: {}\n\n", retry.as_millis())));
+        }
+
+        loop {
+            let shutdown = self.shutdown.clone();
+
+            tokio::select! {
+                biased;
+
+                event = self.events.next() => {
+                    match event?.to_bytes() {
+                        Ok(bytes) => return Some(bytes),
+                        Err(err) => error!("Can't serialize SSE event: {err}"),
+                    }
+                }
+                _ = self.keep_alive.tick() => return Some(Bytes::from_static(b": keep-alive\n\n")),
+                _ = wait_for_shutdown(shutdown) => return None,
+            }
+        }
+    }
+}
+
+impl<S, T> Responder for Sse<S>
+where
+    S: Stream<Item = SseEvent<T>> + 'static,
+    T: Serialize,
+{
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let mut keep_alive = interval_at(Instant::now() + self.keep_alive, self.keep_alive);
+        keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let body = SseBody {
+            events: Box::pin(self.events),
+            keep_alive,
+            shutdown: req
+                .app_data::<web::Data<Shutdown>>()
+                .map(|shutdown| shutdown.get_ref().clone()),
+            retry: self.retry,
+            _gauge: StatsGauge::new(req.app_data::<web::Data<BaseStats>>().cloned(), |stats| {
+                &stats.sse_streams
+            }),
+        };
+
+        let chunks = stream::unfold(body, |mut body| async move {
+            let chunk = body.next_chunk().await?;
+            Some((Ok::<_, Infallible>(chunk), body))
+        });
+
+        HttpResponse::Ok()
+            .insert_header((CONTENT_TYPE, HeaderValue::from_static("text/event-stream")))
+            .insert_header((CACHE_CONTROL, HeaderValue::from_static("no-cache")))
+            .insert_header((X_ACCEL_BUFFERING, HeaderValue::from_static("no")))
+            .streaming(chunks)
+    }
+}
+
+struct Replay<T> {
+    buffer: VecDeque<(u64, T)>,
+    next_id: u64,
+}
+
+/// Sends events to all subscribed streams and keeps last `capacity` of them for replay.
+///
+/// Clones share subscribers, so keep it in app data. Subscriber lagging more than `capacity`
+/// events behind is disconnected, to resume from buffer after reconnect.
+#[derive(Clone)]
+pub struct SseBroadcast<T> {
+    replay: Arc<Mutex<Replay<T>>>,
+    sender: broadcast::Sender<(u64, T)>,
+    capacity: usize,
+}
+
+impl<T> SseBroadcast<T>
+where
+    T: Clone + Serialize + Send + 'static,
+{
+    /// Creates broadcast keeping last `capacity` events (at least one)
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            replay: Arc::new(Mutex::new(Replay {
+                buffer: VecDeque::with_capacity(capacity),
+                next_id: 1,
+            })),
+            sender: broadcast::Sender::new(capacity),
+            capacity,
+        }
+    }
+
+    /// Sends event to all subscribers, returns its id
+    pub fn send(&self, data: T) -> u64 {
+        let mut replay = self.replay.lock().unwrap_or_else(|err| err.into_inner());
+
+        let id = replay.next_id;
+        replay.next_id += 1;
+
+        if replay.buffer.len() >= self.capacity {
+            replay.buffer.pop_front();
+        }
+        replay.buffer.push_back((id, data.clone()));
+
+        // No subscribers is not an error here
+        let _ = self.sender.send((id, data));
+        id
+    }
+
+    /// Stream of events sent from now on, preceded by buffered ones newer than `last_event_id`
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> impl Stream<Item = SseEvent<T>> + use<T> {
+        let replay = self.replay.lock().unwrap_or_else(|err| err.into_inner());
+
+        let missed: Vec<_> = match last_event_id {
+            Some(last) => replay
+                .buffer
+                .iter()
+                .filter(|(id, _)| *id > last)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        let receiver = self.sender.subscribe();
+        drop(replay);
+
+        let live = stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((event, receiver)),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("SSE subscriber lagged {skipped} events behind, closing stream");
+                    None
+                }
+                Err(RecvError::Closed) => None,
+            }
+        });
+
+        stream::iter(missed)
+            .chain(live)
+            .map(|(id, data)| SseEvent::new(data).id(id))
+    }
+
+    /// Number of currently subscribed streams
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpRequest, Responder, test, web};
+
+    use super::{Sse, SseBroadcast, last_event_id};
+    use crate::server::{shutdown::Shutdown, stats::BaseStats};
+
+    async fn events(req: HttpRequest, feed: web::Data<SseBroadcast<u32>>) -> impl Responder {
+        Sse::new(feed.subscribe(last_event_id(&req)))
+    }
+
+    #[actix_web::test]
+    async fn replays_missed_events_and_ends_on_shutdown() {
+        let feed = SseBroadcast::<u32>::new(2);
+        let shutdown = Shutdown::default();
+        let stats = BaseStats::default();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(feed.clone()))
+                .app_data(web::Data::new(shutdown.clone()))
+                .app_data(web::Data::new(stats.clone()))
+                .route("/events", web::get().to(events)),
+        )
+        .await;
+
+        for data in [10, 20, 30] {
+            feed.send(data);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/events")
+            .insert_header(("last-event-id", "1"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+        assert_eq!(stats.snapshot().unwrap().sse_streams, 1);
+        assert_eq!(feed.subscribers(), 1);
+
+        feed.send(40);
+        shutdown.begin();
+
+        let body = test::read_body(res).await;
+        assert_eq!(
+            body,
+            "id: 2\ndata: 20\n\nid: 3\ndata: 30\n\nid: 4\ndata: 40\n\n"
+        );
+        assert_eq!(stats.snapshot().unwrap().sse_streams, 0);
+        assert_eq!(feed.subscribers(), 0);
+    }
+}
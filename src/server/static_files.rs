@@ -0,0 +1,373 @@
+//! Static assets served next to the API
+//!
+//! [StaticFiles] mounts a directory under a path with [Serwus::add_static_files](super::Serwus::add_static_files).
+//! Files are looked up only for requests not matched by any route, so app and internal
+//! endpoints always win. Requests which can't be served get plain 404, turned into JSON
+//! by [default_error_handler](super::json_error::default_error_handler) when
+//! [json_errors](super::Serwus::json_errors) are enabled.
+//!
+//! ```no_run
+//! # use serwus::{EmptyStats, server::{Serwus, default_cors, static_files::StaticFiles}, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # fn configure_app(_cfg: &mut web::ServiceConfig) {}
+//! # #[actix_web::main]
+//! # async fn main() -> std::io::Result<()> {
+//! Serwus::default()
+//!     .add_static_files(
+//!         StaticFiles::new("/", "./frontend/dist")
+//!             .spa_fallback("index.html")
+//!             .api_prefix("/api")
+//!             .cache_control(&["js", "css", "woff2"], "public, max-age=31536000, immutable")
+//!             .cache_control(&["html"], "no-cache"),
+//!     )
+//!     .start(|| AppData, configure_app, default_cors)
+//!     .await
+//! # }
+//! ```
+//!
+//! Files are served with `ETag` and `Last-Modified`, and conditional and range requests
+//! are handled. If client accepts it, precompressed `{file}.br` or `{file}.gz` is sent
+//! instead of the file.
+
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use actix_files::{NamedFile, PathBufWrap, file_extension_to_mime};
+use actix_service::{ServiceFactory, fn_service};
+use actix_web::{
+    Error, HttpRequest, HttpResponse,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        Method,
+        header::{ACCEPT, ACCEPT_ENCODING, CACHE_CONTROL, ContentEncoding, HeaderValue, VARY},
+    },
+};
+
+/// Directory mounted under path, see [module docs](self)
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    mount: String,
+    dir: PathBuf,
+    spa_index: Option<String>,
+    api_prefixes: Vec<String>,
+    precompressed: bool,
+    cache_rules: Vec<(Vec<String>, HeaderValue)>,
+    default_cache: Option<HeaderValue>,
+}
+
+impl StaticFiles {
+    /// Serves files from `dir` under `mount`, f. ex. `/admin`, with precompressed variants
+    pub fn new(mount: impl Into<String>, dir: impl Into<PathBuf>) -> Self {
+        let mount = mount.into();
+        Self {
+            mount: mount.trim_end_matches('/').to_string(),
+            dir: dir.into(),
+            spa_index: None,
+            api_prefixes: Vec::new(),
+            precompressed: true,
+            cache_rules: Vec::new(),
+            default_cache: None,
+        }
+    }
+
+    /// Serves given file (relative to directory) for unknown paths of single page application.
+    ///
+    /// Fallback is used only for paths without extension or requests accepting `text/html`,
+    /// so missing assets are still 404.
+    pub fn spa_fallback(mut self, index: impl Into<String>) -> Self {
+        self.spa_index = Some(index.into());
+        self
+    }
+
+    /// Excludes paths starting with prefix, f. ex. `/api`, from serving files and SPA fallback,
+    /// they get 404 (JSON one with [json_errors](super::Serwus::json_errors))
+    pub fn api_prefix(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        self.api_prefixes
+            .push(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Enables or disables looking for `.br` and `.gz` variants (default: enabled)
+    pub fn precompressed(mut self, precompressed: bool) -> Self {
+        self.precompressed = precompressed;
+        self
+    }
+
+    /// Sets `Cache-Control` for files with given extensions, invalid values are ignored
+    pub fn cache_control(mut self, extensions: &[&str], value: &str) -> Self {
+        match HeaderValue::from_str(value) {
+            Ok(value) => {
+                let extensions = extensions
+                    .iter()
+                    .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+                    .collect();
+                self.cache_rules.push((extensions, value));
+            }
+            Err(_) => log::error!("Invalid Cache-Control: {value}"),
+        }
+        self
+    }
+
+    /// Sets `Cache-Control` for files not matched by any [cache_control](Self::cache_control) rule
+    pub fn default_cache_control(mut self, value: &str) -> Self {
+        match HeaderValue::from_str(value) {
+            Ok(value) => self.default_cache = Some(value),
+            Err(_) => log::error!("Invalid Cache-Control: {value}"),
+        }
+        self
+    }
+
+    /// Part of path after mount, if path is under mount and not under any API prefix
+    fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let is_under = |path: &str, prefix: &str| {
+            path.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+
+        if self
+            .api_prefixes
+            .iter()
+            .any(|prefix| is_under(path, prefix))
+        {
+            return None;
+        }
+
+        if is_under(path, &self.mount) {
+            Some(&path[self.mount.len()..])
+        } else {
+            None
+        }
+    }
+
+    fn cache_control_for(&self, path: &Path) -> Option<HeaderValue> {
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        self.cache_rules
+            .iter()
+            .find(|(extensions, _)| extensions.contains(&ext))
+            .map(|(_, value)| value.clone())
+            .or_else(|| self.default_cache.clone())
+    }
+
+    fn accepts_fallback(&self, req: &HttpRequest, relative: &str) -> bool {
+        let no_extension = !relative.rsplit('/').next().unwrap_or("").contains('.');
+        let accepts_html = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"));
+
+        no_extension || accepts_html
+    }
+
+    async fn serve(&self, req: &HttpRequest, relative: &str) -> Option<HttpResponse> {
+        let file_path = PathBufWrap::parse_path(relative, false).ok()?;
+        let path = self.dir.join(file_path);
+
+        if let Some(res) = self.serve_file(req, &path).await {
+            return Some(res);
+        }
+
+        let index = self.spa_index.as_ref()?;
+        if !self.accepts_fallback(req, relative) {
+            return None;
+        }
+        self.serve_file(req, &self.dir.join(index)).await
+    }
+
+    async fn serve_file(&self, req: &HttpRequest, path: &Path) -> Option<HttpResponse> {
+        let file = NamedFile::open_async(path).await.ok()?;
+        let (file, path) = if file.metadata().is_dir() {
+            let path = path.join("index.html");
+            (NamedFile::open_async(&path).await.ok()?, path)
+        } else {
+            (file, path.to_path_buf())
+        };
+
+        let file = match self.precompressed {
+            true => self.precompressed_variant(req, &path).await.unwrap_or(file),
+            false => file,
+        };
+
+        let mut res = file.disable_content_disposition().into_response(req);
+
+        if let Some(value) = self.cache_control_for(&path) {
+            res.headers_mut().insert(CACHE_CONTROL, value);
+        }
+        if self.precompressed {
+            res.headers_mut()
+                .insert(VARY, HeaderValue::from_static("accept-encoding"));
+        }
+
+        Some(res)
+    }
+
+    /// Brotli or gzip variant of file if client accepts it and it exists
+    async fn precompressed_variant(&self, req: &HttpRequest, path: &Path) -> Option<NamedFile> {
+        let accepted = req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())?;
+
+        let accepts = |coding: &str| {
+            accepted.split(',').any(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                parts
+                    .next()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(coding))
+                    && !parts.any(|param| {
+                        param
+                            .strip_prefix("q=")
+                            .and_then(|q| q.parse::<f32>().ok())
+                            .is_some_and(|q| q == 0.0)
+                    })
+            })
+        };
+
+        let content_type = file_extension_to_mime(
+            &path
+                .extension()
+                .map(|ext| ext.to_string_lossy())
+                .unwrap_or_default(),
+        );
+
+        for (encoding, suffix) in [
+            (ContentEncoding::Brotli, "br"),
+            (ContentEncoding::Gzip, "gz"),
+        ] {
+            if !accepts(encoding.as_str()) {
+                continue;
+            }
+
+            let mut variant = path.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(suffix);
+
+            if let Ok(file) = NamedFile::open_async(&variant).await {
+                return Some(
+                    file.set_content_type(content_type)
+                        .set_content_encoding(encoding),
+                );
+            }
+        }
+
+        None
+    }
+}
+
+/// Service serving files of first mount accepting request, used as app default service
+pub(super) fn default_service(
+    mounts: Vec<StaticFiles>,
+) -> impl ServiceFactory<
+    ServiceRequest,
+    Config = (),
+    Response = ServiceResponse,
+    Error = Error,
+    InitError = (),
+> {
+    let mounts = Rc::new(mounts);
+
+    fn_service(move |req: ServiceRequest| {
+        let mounts = mounts.clone();
+        async move {
+            let (req, _) = req.into_parts();
+
+            if req.method() == Method::GET || req.method() == Method::HEAD {
+                for mount in mounts.iter() {
+                    let Some(relative) = mount.relative_path(req.path()) else {
+                        continue;
+                    };
+                    if let Some(res) = mount.serve(&req, relative).await {
+                        return Ok(ServiceResponse::new(req, res));
+                    }
+                }
+            }
+
+            Ok(ServiceResponse::new(req, HttpResponse::NotFound().finish()))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        App, HttpResponse,
+        http::{
+            StatusCode,
+            header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, ETAG},
+        },
+        middleware::ErrorHandlers,
+        test, web,
+    };
+    use std::fs;
+
+    use super::{StaticFiles, default_service};
+    use crate::server::json_error::default_error_handler;
+
+    #[actix_web::test]
+    async fn serves_files_with_spa_fallback() {
+        let dir = std::env::temp_dir().join(format!("serwus-static-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("index.html"), "<html>app</html>").unwrap();
+        fs::write(dir.join("assets/app.js"), "plain").unwrap();
+        fs::write(dir.join("assets/app.js.br"), "brotli").unwrap();
+
+        let files = StaticFiles::new("/admin", &dir)
+            .spa_fallback("index.html")
+            .api_prefix("/admin/api")
+            .cache_control(&["js"], "public, max-age=31536000, immutable");
+
+        let app = test::init_service(
+            App::new()
+                .route("/admin/api/ok", web::get().to(HttpResponse::Ok))
+                .default_service(default_service(vec![files]))
+                .wrap(ErrorHandlers::new().default_handler(default_error_handler)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/admin/assets/app.js")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key(ETAG));
+        assert_eq!(
+            res.headers().get(CACHE_CONTROL).unwrap(),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(test::read_body(res).await, "plain");
+
+        let req = test::TestRequest::get()
+            .uri("/admin/assets/app.js")
+            .insert_header((ACCEPT_ENCODING, "gzip, br"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(CONTENT_ENCODING).unwrap(), "br");
+        assert_eq!(test::read_body(res).await, "brotli");
+
+        let req = test::TestRequest::get().uri("/admin/users/5").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(test::read_body(res).await, "<html>app</html>");
+
+        let req = test::TestRequest::get()
+            .uri("/admin/assets/missing.js")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/api/missing")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["type"], "NotFound");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
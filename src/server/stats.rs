@@ -1,18 +1,21 @@
 //! Request counter and other stats middleware
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::pin::Pin;
 use std::rc::Rc;
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use actix_service::{Service, Transform};
+use chrono::{DateTime, Utc};
 use futures::future::{Future, FutureExt, Ready, TryFutureExt, ok as fut_ok};
 use log::{debug, warn};
 
 use actix_web::body::MessageBody;
 use actix_web::error::Error;
-use actix_web::http::StatusCode;
+use actix_web::http::{Method, StatusCode};
 use actix_web::{
     HttpResponse,
     dev::{ServiceRequest, ServiceResponse},
@@ -29,25 +32,287 @@ use serde::Serialize;
 #[cfg(feature = "prometheus")]
 pub use super::prometheus::AsPrometheus;
 
-/// BaseStats contains BaseStatsInner singleton
+use super::maintenance::Maintenance;
+use super::scheduler::{JobStates, JobStatus};
+use super::shutdown::Shutdown;
+use super::tasks::{TaskStates, TaskStatus};
+
+/// Paths excluded from stats by default (internal endpoints)
+pub const DEFAULT_STATS_EXCLUDES: &[&str] = &super::INTERNAL_PATHS;
+
+/// Route of requests not matched by any resource
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Method of requests with non-standard methods, which would make number of routes unbounded
+pub const OTHER_METHOD: &str = "OTHER";
+
+/// Name of standard method, [OTHER_METHOD] for extension ones
+pub fn method_name(method: &Method) -> &str {
+    match *method {
+        Method::GET
+        | Method::POST
+        | Method::PUT
+        | Method::PATCH
+        | Method::DELETE
+        | Method::HEAD
+        | Method::OPTIONS
+        | Method::CONNECT
+        | Method::TRACE => method.as_str(),
+        _ => OTHER_METHOD,
+    }
+}
+
+/// Number of latest requests of every route used to compute latency percentiles
+const LATENCY_SAMPLES: usize = 1000;
+
+/// BaseStats contains live counters singleton, use [snapshot](BaseStats::snapshot) to read them
 #[derive(Clone)]
-pub struct BaseStats(pub(super) Arc<RwLock<BaseStatsInner>>);
+pub struct BaseStats(pub(super) Arc<Counters>);
+
+/// Live counters, updated without locking except for first request of a route
+/// and per-route lock of finished requests
+pub(super) struct Counters {
+    started_at: DateTime<Utc>,
+    request_started: AtomicUsize,
+    request_finished: AtomicUsize,
+    routes: RwLock<HashMap<String, Arc<RouteCounters>>>,
+    pub(super) rate_limited: AtomicUsize,
+    pub(super) maintenance_rejected: AtomicUsize,
+    pub(super) timeouts: AtomicUsize,
+    pub(super) cache_hits: AtomicUsize,
+    pub(super) cache_misses: AtomicUsize,
+    pub(super) websocket_sessions: AtomicUsize,
+    pub(super) sse_streams: AtomicUsize,
+}
+
+impl Counters {
+    /// Counters of given route, created on first request
+    fn route(&self, route: &str) -> Option<Arc<RouteCounters>> {
+        if let Some(counters) = self.routes.read().ok()?.get(route) {
+            return Some(counters.clone());
+        }
+
+        let mut routes = self.routes.write().ok()?;
+        Some(routes.entry(route.to_string()).or_default().clone())
+    }
+}
 
 /// BaseStatsInner are common serwus statistics not tied to any special functionality
 #[derive(Clone, Serialize)]
 pub struct BaseStatsInner {
+    pub(super) started_at: DateTime<Utc>,
+    pub(super) uptime_secs: i64,
     pub(super) request_started: usize,
     pub(super) request_finished: usize,
+    pub(super) in_flight: usize,
     pub(super) status_codes: HashMap<u16, usize>,
+    /// Stats of every route, by method and matched pattern, f. ex. `GET /users/{id}`
+    pub(super) routes: BTreeMap<String, RouteStats>,
+    /// Requests rejected by [RateLimit](super::rate_limit::RateLimit)
+    pub(super) rate_limited: usize,
+    /// Requests rejected in [maintenance mode](super::maintenance)
+    pub(super) maintenance_rejected: usize,
+    /// Requests aborted by [Timeout](super::timeout::Timeout)
+    pub(super) timeouts: usize,
+    /// Responses served by [ResponseCache](super::response_cache::ResponseCache)
+    pub(super) cache_hits: usize,
+    /// Cacheable requests not found in [ResponseCache](super::response_cache::ResponseCache)
+    pub(super) cache_misses: usize,
+    /// Open [WebSocket](super::websocket) sessions
+    pub(super) websocket_sessions: usize,
+    /// Open [Server-Sent Events](super::sse) streams
+    pub(super) sse_streams: usize,
 }
 
 impl Default for BaseStats {
     fn default() -> Self {
-        Self(Arc::new(RwLock::new(BaseStatsInner {
-            request_started: 0,
-            request_finished: 0,
-            status_codes: HashMap::new(),
-        })))
+        Self(Arc::new(Counters {
+            started_at: Utc::now(),
+            request_started: AtomicUsize::new(0),
+            request_finished: AtomicUsize::new(0),
+            routes: RwLock::new(HashMap::new()),
+            rate_limited: AtomicUsize::new(0),
+            maintenance_rejected: AtomicUsize::new(0),
+            timeouts: AtomicUsize::new(0),
+            cache_hits: AtomicUsize::new(0),
+            cache_misses: AtomicUsize::new(0),
+            websocket_sessions: AtomicUsize::new(0),
+            sse_streams: AtomicUsize::new(0),
+        }))
+    }
+}
+
+impl BaseStats {
+    /// Number of counted requests which started but have not finished yet
+    pub fn in_flight(&self) -> usize {
+        let finished = self.0.request_finished.load(Ordering::Relaxed);
+        self.0
+            .request_started
+            .load(Ordering::Relaxed)
+            .saturating_sub(finished)
+    }
+
+    /// Increments one of counters, f. ex. `stats.increment(|stats| &stats.timeouts)`
+    pub(super) fn increment(&self, counter: fn(&Counters) -> &AtomicUsize) {
+        counter(&self.0).fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current stats with computed values (uptime, in-flight requests, latency percentiles)
+    pub(super) fn snapshot(&self) -> Option<BaseStatsInner> {
+        let counters = &self.0;
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+
+        let mut status_codes = HashMap::new();
+        let mut routes = BTreeMap::new();
+        for (name, route) in counters.routes.read().ok()?.iter() {
+            // Copy under route lock, percentiles are computed after releasing it
+            let (requests, route_codes, latencies) = {
+                let finished = route.finished.lock().ok()?;
+                (
+                    finished.requests,
+                    finished.status_codes.clone(),
+                    finished.latencies.clone(),
+                )
+            };
+
+            for (code, count) in &route_codes {
+                *status_codes.entry(*code).or_insert(0) += count;
+            }
+            routes.insert(
+                name.clone(),
+                RouteStats {
+                    requests,
+                    in_flight: load(&route.in_flight),
+                    status_codes: route_codes,
+                    latency_ms: latencies.percentiles(),
+                },
+            );
+        }
+
+        let request_finished = load(&counters.request_finished);
+        let request_started = load(&counters.request_started);
+
+        Some(BaseStatsInner {
+            started_at: counters.started_at,
+            uptime_secs: (Utc::now() - counters.started_at).num_seconds(),
+            request_started,
+            request_finished,
+            in_flight: request_started.saturating_sub(request_finished),
+            status_codes,
+            routes,
+            rate_limited: load(&counters.rate_limited),
+            maintenance_rejected: load(&counters.maintenance_rejected),
+            timeouts: load(&counters.timeouts),
+            cache_hits: load(&counters.cache_hits),
+            cache_misses: load(&counters.cache_misses),
+            websocket_sessions: load(&counters.websocket_sessions),
+            sse_streams: load(&counters.sse_streams),
+        })
+    }
+}
+
+/// Decrements counter, stopping at zero
+fn decrement(counter: &AtomicUsize) {
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+        value.checked_sub(1)
+    });
+}
+
+/// Stats of single route
+#[derive(Clone, Default, Serialize)]
+pub struct RouteStats {
+    /// Finished requests
+    pub(super) requests: usize,
+    pub(super) in_flight: usize,
+    pub(super) status_codes: BTreeMap<u16, usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) latency_ms: Option<Percentiles>,
+}
+
+/// Live counters of single route
+#[derive(Default)]
+struct RouteCounters {
+    in_flight: AtomicUsize,
+    finished: Mutex<FinishedRequests>,
+}
+
+/// Requests of single route which got response
+#[derive(Default)]
+struct FinishedRequests {
+    requests: usize,
+    status_codes: BTreeMap<u16, usize>,
+    latencies: LatencySamples,
+}
+
+/// Latency percentiles in milliseconds, from last requests
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// Ring buffer of last [LATENCY_SAMPLES] latencies in microseconds
+#[derive(Clone, Default)]
+struct LatencySamples {
+    samples: Vec<u64>,
+    next: usize,
+}
+
+impl LatencySamples {
+    fn push(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        if self.samples.len() < LATENCY_SAMPLES {
+            self.samples.push(micros);
+        } else {
+            self.samples[self.next] = micros;
+        }
+        self.next = (self.next + 1) % LATENCY_SAMPLES;
+    }
+
+    fn percentiles(mut self) -> Option<Percentiles> {
+        let sorted = &mut self.samples;
+        sorted.sort_unstable();
+
+        let max = *sorted.last()?;
+        let at = |quantile: f64| {
+            let rank = (quantile * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1] as f64 / 1000.0
+        };
+
+        Some(Percentiles {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: max as f64 / 1000.0,
+        })
+    }
+}
+
+/// Increments gauge in base stats for its lifetime, f. ex. number of open connections
+pub(super) struct StatsGauge {
+    stats: Option<web::Data<BaseStats>>,
+    field: fn(&Counters) -> &AtomicUsize,
+}
+
+impl StatsGauge {
+    pub(super) fn new(
+        stats: Option<web::Data<BaseStats>>,
+        field: fn(&Counters) -> &AtomicUsize,
+    ) -> Self {
+        if let Some(stats) = &stats {
+            stats.increment(field);
+        }
+        Self { stats, field }
+    }
+}
+
+impl Drop for StatsGauge {
+    fn drop(&mut self) {
+        if let Some(stats) = &self.stats {
+            decrement((self.field)(&stats.0));
+        }
     }
 }
 
@@ -60,6 +325,7 @@ struct StatsConfig {
 }
 
 impl StatsWrapper {
+    /// Excludes are matched against both path and route pattern, f. ex. `/users/{id}`
     pub fn new(excludes: HashSet<String>) -> Self {
         Self(Rc::new(StatsConfig { excludes }))
     }
@@ -67,16 +333,17 @@ impl StatsWrapper {
 
 impl Default for StatsWrapper {
     fn default() -> Self {
-        let mut excludes = HashSet::with_capacity(2);
-        excludes.insert("/_healthcheck".to_string());
-        excludes.insert("/_ready".to_string());
-        excludes.insert("/_stats".to_string());
-        #[cfg(feature = "prometheus")]
-        excludes.insert("/_prometheus".to_string());
-        Self::new(excludes)
+        Self::new(default_excludes())
     }
 }
 
+pub(super) fn default_excludes() -> HashSet<String> {
+    DEFAULT_STATS_EXCLUDES
+        .iter()
+        .map(|path| path.to_string())
+        .collect()
+}
+
 impl<S, B> Transform<S, ServiceRequest> for StatsWrapper
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
@@ -121,21 +388,38 @@ where
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let count_it = !self.config.excludes.contains(req.path());
+        let pattern = req.match_pattern();
+        let count_it = !self.config.excludes.contains(req.path())
+            && !pattern
+                .as_ref()
+                .is_some_and(|pattern| self.config.excludes.contains(pattern));
+        let started = Instant::now();
 
         // Count request start-of-handling
-        let stats_arc_for_request = req.app_data::<web::Data<BaseStats>>();
-
-        if count_it
-            && let Some(stats_arc) = stats_arc_for_request
-            && let Ok(mut stats) = stats_arc.0.write()
-        {
-            stats.request_started += 1;
-        }
-
         // Get stats reference for later to count stop-of-handling
         // It seems in actix 3 app data can be not available after the call so we get a weak Arc to stats
-        let stats_arc_for_response = stats_arc_for_request.map(|bs| Arc::downgrade(&bs.0));
+        let stats = req
+            .app_data::<web::Data<BaseStats>>()
+            .filter(|_| count_it)
+            .and_then(|stats| {
+                let route = format!(
+                    "{} {}",
+                    method_name(req.method()),
+                    pattern.as_deref().unwrap_or(UNMATCHED_ROUTE)
+                );
+                let route = stats.0.route(&route)?;
+
+                stats.0.request_started.fetch_add(1, Ordering::Relaxed);
+                route.in_flight.fetch_add(1, Ordering::Relaxed);
+
+                Some((Arc::downgrade(&stats.0), route))
+            });
+
+        let mut guard = FinishGuard {
+            stats,
+            started,
+            status: None,
+        };
 
         let fut = self.service.call(req);
 
@@ -154,42 +438,94 @@ where
                 Err(err) => err.error_response().status(),
             };
 
-            if count_it {
-                // Try to acquire strong Arc to stats again
-                if let Some(stats_arc) = stats_arc_for_response.and_then(|wbs| Weak::upgrade(&wbs))
-                    && let Ok(mut stats) = stats_arc.write()
-                {
-                    stats.request_finished += 1;
-                    let left = stats.request_started - stats.request_finished;
-                    if left > 1 {
-                        warn!("Number of unfinished requests: {left}");
-                    }
-                    *stats.status_codes.entry(status_code.as_u16()).or_insert(0) += 1;
-                }
-            }
+            guard.status = Some(status_code);
+            drop(guard);
 
             res
         })
     }
 }
 
+/// Counts request as finished when dropped, so requests abandoned by disconnected clients
+/// don't stay in flight forever (they are not counted in status codes and latencies)
+struct FinishGuard {
+    stats: Option<(Weak<Counters>, Arc<RouteCounters>)>,
+    started: Instant,
+    status: Option<StatusCode>,
+}
+
+impl Drop for FinishGuard {
+    fn drop(&mut self) {
+        let Some((stats, route)) = self.stats.take() else {
+            return;
+        };
+
+        decrement(&route.in_flight);
+        if let Some(status_code) = self.status
+            && let Ok(mut finished) = route.finished.lock()
+        {
+            finished.requests += 1;
+            *finished
+                .status_codes
+                .entry(status_code.as_u16())
+                .or_insert(0) += 1;
+            finished.latencies.push(self.started.elapsed());
+        }
+
+        // Try to acquire strong Arc to stats again
+        if let Some(stats) = stats.upgrade() {
+            let finished = stats.request_finished.fetch_add(1, Ordering::Relaxed) + 1;
+            let left = stats
+                .request_started
+                .load(Ordering::Relaxed)
+                .saturating_sub(finished);
+            if left > 1 {
+                warn!("Number of unfinished requests: {left}");
+            }
+        }
+    }
+}
+
 /// Default alive healthcheck handler
 pub async fn default_healthcheck_handler() -> &'static str {
     ""
 }
 
 /// Default readiness handler
+///
+/// Reports not ready as soon as graceful shutdown begins or when some background task is restarting.
+/// Maintenance mode doesn't make service unready, it's only mentioned in response.
 pub async fn default_readiness_handler<S, D>(
     service_data: web::Data<S>,
+    shutdown: Option<web::Data<Shutdown>>,
+    tasks: Option<web::Data<TaskStates>>,
+    maintenance: Option<web::Data<Maintenance>>,
 ) -> Result<HttpResponse, Error>
 where
     D: AppDataWrapper,
     S: StatsPresenter<D>,
 {
+    if shutdown.is_some_and(|shutdown| shutdown.is_draining()) {
+        return Ok(
+            HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).body("Shutting down".to_string())
+        );
+    }
+
+    if let Some(task) = tasks.and_then(|tasks| tasks.failing()) {
+        return Ok(HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE)
+            .body(format!("Task {} is restarting", task.name)));
+    }
+
     let fut_res = service_data.is_ready().map(|result| match result {
         Err(error) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
             .body(format!("Can't check readiness: {error}")),
-        Ok(true) => HttpResponse::build(StatusCode::OK).body("OK".to_string()),
+        Ok(true) => match maintenance.and_then(|maintenance| maintenance.state()) {
+            Some(state) => HttpResponse::build(StatusCode::OK).body(format!(
+                "OK, maintenance mode since {}",
+                state.since.to_rfc3339()
+            )),
+            None => HttpResponse::build(StatusCode::OK).body("OK".to_string()),
+        },
         Ok(false) => {
             HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).body("Not ready yet".to_string())
         }
@@ -201,16 +537,20 @@ where
 pub async fn default_stats_handler<S, D>(
     base_data: web::Data<BaseStats>,
     service_data: web::Data<S>,
+    tasks: Option<web::Data<TaskStates>>,
+    jobs: Option<web::Data<JobStates>>,
 ) -> Result<HttpResponse, Error>
 where
     D: AppDataWrapper,
     S: StatsPresenter<D>,
 {
     let fut_res = service_data.get_stats().and_then(move |service_stats| {
-        if let Ok(base_stats) = base_data.0.read() {
+        if let Some(base_stats) = base_data.snapshot() {
             #[allow(clippy::unit_arg)]
             let output = StatsOutput {
-                base: base_stats.clone(),
+                base: base_stats,
+                tasks: tasks.map(|tasks| tasks.all()).unwrap_or_default(),
+                jobs: jobs.map(|jobs| jobs.all()).unwrap_or_default(),
                 service: Some(service_stats),
             };
 
@@ -234,6 +574,12 @@ where
 pub struct StatsOutput<D: Serialize> {
     pub(super) base: BaseStatsInner,
 
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(super) tasks: Vec<TaskStatus>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(super) jobs: Vec<JobStatus>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(super) service: Option<D>,
 }
@@ -312,4 +658,92 @@ impl<T> AppDataWrapper for T where T: Serialize + AsPrometheus + 'static {}
 #[cfg(not(feature = "prometheus"))]
 impl<T> AppDataWrapper for T where T: Serialize {}
 
-// TODO unittests - see logger tests
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use actix_service::Service;
+    use actix_web::{App, HttpResponse, http::Method, test, web};
+
+    use super::{BaseStats, LATENCY_SAMPLES, LatencySamples, StatsWrapper};
+
+    #[actix_web::test]
+    async fn counts_requests_per_route() {
+        let stats = BaseStats::default();
+        let excludes = HashSet::from(["/internal/{name}".to_string()]);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(stats.clone()))
+                .route("/users/{id}", web::get().to(HttpResponse::Ok))
+                .route("/internal/{name}", web::get().to(HttpResponse::Ok))
+                .wrap(StatsWrapper::new(excludes)),
+        )
+        .await;
+
+        for uri in ["/users/1", "/users/2", "/internal/x", "/missing"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            test::call_service(&app, req).await;
+        }
+        for method in ["PURGE", "X-CUSTOM"] {
+            let req = test::TestRequest::default()
+                .method(Method::from_bytes(method.as_bytes()).unwrap())
+                .uri("/missing")
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let snapshot = stats.snapshot().unwrap();
+        assert_eq!(snapshot.request_finished, 5);
+        assert_eq!(snapshot.in_flight, 0);
+        assert_eq!(
+            snapshot.routes.keys().collect::<Vec<_>>(),
+            ["GET /users/{id}", "GET unmatched", "OTHER unmatched"]
+        );
+        assert_eq!(snapshot.routes["OTHER unmatched"].requests, 2);
+
+        let users = &snapshot.routes["GET /users/{id}"];
+        assert_eq!(users.requests, 2);
+        assert_eq!(users.in_flight, 0);
+        assert_eq!(users.status_codes[&200], 2);
+        assert!(users.latency_ms.is_some());
+        assert_eq!(snapshot.routes["GET unmatched"].status_codes[&404], 1);
+    }
+
+    #[actix_web::test]
+    async fn finishes_requests_dropped_by_disconnected_clients() {
+        let stats = BaseStats::default();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(stats.clone()))
+                .route("/slow", web::get().to(std::future::pending::<HttpResponse>))
+                .wrap(StatsWrapper::default()),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/slow").to_request();
+        let call = tokio::time::timeout(Duration::from_millis(20), app.call(req));
+        assert!(call.await.is_err());
+
+        let snapshot = stats.snapshot().unwrap();
+        assert_eq!(snapshot.request_started, 1);
+        assert_eq!(stats.in_flight(), 0);
+        assert_eq!(snapshot.routes["GET /slow"].in_flight, 0);
+        assert_eq!(snapshot.routes["GET /slow"].requests, 0);
+    }
+
+    #[actix_web::test]
+    async fn keeps_only_latest_latencies() {
+        let mut latencies = LatencySamples::default();
+        for millis in 0..LATENCY_SAMPLES as u64 + 10 {
+            latencies.push(Duration::from_millis(millis));
+        }
+
+        assert_eq!(latencies.samples.len(), LATENCY_SAMPLES);
+        let percentiles = latencies.percentiles().unwrap();
+        assert_eq!(percentiles.max, (LATENCY_SAMPLES + 9) as f64);
+        assert_eq!(percentiles.p50, (LATENCY_SAMPLES / 2 + 9) as f64);
+    }
+}
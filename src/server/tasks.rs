@@ -0,0 +1,355 @@
+//! Lifecycle hooks and supervised background tasks
+//!
+//! Tasks added with [Serwus::add_task](super::Serwus::add_task) are started with the server
+//! and supervised: when a task panics or returns an error it is restarted with exponential
+//! backoff, when graceful shutdown begins it gets [stop_timeout](BackgroundTask::stop_timeout)
+//! to finish before being dropped.
+//!
+//! ```no_run
+//! # use serwus::{EmptyStats, server::{BackgroundTask, Serwus, default_cors, shutdown::Shutdown}, web};
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # async fn migrate(_app_data: web::Data<AppData>) -> std::io::Result<()> { Ok(()) }
+//! # async fn consume(_app_data: web::Data<AppData>, shutdown: Shutdown) -> Result<(), String> {
+//! #     shutdown.wait().await;
+//! #     Ok(())
+//! # }
+//! # fn configure_app(_cfg: &mut web::ServiceConfig) {}
+//! # #[actix_web::main]
+//! # async fn main() -> std::io::Result<()> {
+//! Serwus::default()
+//!     .on_start(|ctx| async move { migrate(ctx.app_data::<AppData>().unwrap()).await })
+//!     .add_task(BackgroundTask::new("rabbit", |ctx| async move {
+//!         let app_data = ctx.app_data::<AppData>().unwrap();
+//!         consume(app_data, ctx.shutdown().clone()).await
+//!     }))
+//!     .start(|| AppData, configure_app, default_cors)
+//!     .await
+//! # }
+//! ```
+//!
+//! State of every task is reported by `/_stats` (and `/_prometheus`), while any task
+//! is waiting for restart `/_ready` responds with 503.
+
+use std::any::Any;
+use std::fmt::Display;
+use std::future::Future;
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::pin::{Pin, pin};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use log::{error, info, warn};
+use serde::Serialize;
+
+#[cfg(not(feature = "swagger"))]
+use actix_web::web;
+
+#[cfg(feature = "swagger")]
+use paperclip::actix::web;
+
+use super::shutdown::Shutdown;
+
+type TaskFuture = Pin<Box<dyn Future<Output = Result<(), String>>>>;
+type TaskFn = Box<dyn Fn(TaskContext) -> TaskFuture + Send + Sync>;
+pub(super) type HookFn =
+    Box<dyn FnOnce(TaskContext) -> Pin<Box<dyn Future<Output = io::Result<()>>>> + Send + Sync>;
+
+/// Access to app data and shutdown state for hooks and tasks
+#[derive(Clone)]
+pub struct TaskContext {
+    app_data: Arc<dyn Any + Send + Sync>,
+    shutdown: Shutdown,
+}
+
+impl TaskContext {
+    pub(super) fn new<T: Send + Sync + 'static>(
+        app_data: web::Data<T>,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self {
+            app_data: Arc::new(app_data),
+            shutdown,
+        }
+    }
+
+    /// App data created by `prepare_app_data`, `None` if `T` is not its type
+    pub fn app_data<T: 'static>(&self) -> Option<web::Data<T>> {
+        self.app_data.downcast_ref::<web::Data<T>>().cloned()
+    }
+
+    /// Shutdown state, [Shutdown::wait] resolves when task should finish
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+}
+
+/// Long-running task supervised by [Serwus](super::Serwus), see [module docs](self)
+pub struct BackgroundTask {
+    name: String,
+    factory: TaskFn,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    stop_timeout: Duration,
+}
+
+impl BackgroundTask {
+    /// Task created by calling `factory` on every (re)start
+    pub fn new<F, Fut, E>(name: impl Into<String>, factory: F) -> Self
+    where
+        F: Fn(TaskContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + 'static,
+        E: Display,
+    {
+        Self {
+            name: name.into(),
+            factory: Box::new(move |ctx| {
+                let fut = factory(ctx);
+                Box::pin(async move { fut.await.map_err(|err| err.to_string()) })
+            }),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            stop_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sets delay before first restart, doubled on every next one up to `max` (default: 1 s, 60 s).
+    ///
+    /// Delay is reset when task was running for longer than `max`.
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    /// Sets how long task can run after shutdown begins (default: 5 s)
+    pub fn stop_timeout(mut self, timeout: Duration) -> Self {
+        self.stop_timeout = timeout;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// Waiting for restart after panic or error
+    Restarting,
+    /// Returned `Ok`, won't be restarted
+    Finished,
+    /// Stopped because of shutdown
+    Stopped,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: TaskState,
+    pub since: DateTime<Utc>,
+    pub restarts: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// States of all supervised tasks, available to handlers as `web::Data<TaskStates>`
+#[derive(Clone, Default)]
+pub struct TaskStates(Arc<RwLock<Vec<TaskStatus>>>);
+
+impl TaskStates {
+    pub fn all(&self) -> Vec<TaskStatus> {
+        self.0.read().map(|tasks| tasks.clone()).unwrap_or_default()
+    }
+
+    /// First task which is restarting, readiness fails because of it
+    pub fn failing(&self) -> Option<TaskStatus> {
+        self.0.read().ok().and_then(|tasks| {
+            tasks
+                .iter()
+                .find(|task| task.state == TaskState::Restarting)
+                .cloned()
+        })
+    }
+
+    fn register(&self, name: &str) -> usize {
+        let mut tasks = self.0.write().unwrap_or_else(|err| err.into_inner());
+        tasks.push(TaskStatus {
+            name: name.to_string(),
+            state: TaskState::Running,
+            since: Utc::now(),
+            restarts: 0,
+            last_error: None,
+        });
+        tasks.len() - 1
+    }
+
+    fn update(&self, index: usize, f: impl FnOnce(&mut TaskStatus)) {
+        if let Ok(mut tasks) = self.0.write()
+            && let Some(task) = tasks.get_mut(index)
+        {
+            f(task);
+        }
+    }
+
+    fn set_state(&self, index: usize, state: TaskState) {
+        self.update(index, |task| {
+            task.state = state;
+            task.since = Utc::now();
+        });
+    }
+}
+
+/// Spawns supervisor of every task on current runtime
+pub(super) fn spawn_tasks(tasks: Vec<BackgroundTask>, ctx: TaskContext, states: TaskStates) {
+    for task in tasks {
+        let index = states.register(&task.name);
+        actix_web::rt::spawn(supervise(task, ctx.clone(), states.clone(), index));
+    }
+}
+
+async fn supervise(task: BackgroundTask, ctx: TaskContext, states: TaskStates, index: usize) {
+    let shutdown = ctx.shutdown.clone();
+    let mut backoff = task.min_backoff;
+
+    loop {
+        info!("Task {} started", task.name);
+        states.set_state(index, TaskState::Running);
+        let started = Instant::now();
+
+        let mut run = pin!(AssertUnwindSafe((task.factory)(ctx.clone())).catch_unwind());
+
+        let outcome = tokio::select! {
+            outcome = &mut run => outcome,
+            _ = shutdown.wait() => {
+                match tokio::time::timeout(task.stop_timeout, run).await {
+                    Ok(outcome) => outcome,
+                    Err(_) => {
+                        warn!("Task {} didn't stop in {:?}, dropping it", task.name, task.stop_timeout);
+                        states.set_state(index, TaskState::Stopped);
+                        return;
+                    }
+                }
+            }
+        };
+
+        let error = match outcome {
+            Ok(Ok(())) => {
+                let state = match shutdown.is_draining() {
+                    true => TaskState::Stopped,
+                    false => TaskState::Finished,
+                };
+                info!("Task {} finished", task.name);
+                states.set_state(index, state);
+                return;
+            }
+            Ok(Err(err)) => err,
+            Err(panic) => panic_message(panic),
+        };
+
+        if shutdown.is_draining() {
+            warn!("Task {} failed while stopping: {error}", task.name);
+            states.set_state(index, TaskState::Stopped);
+            return;
+        }
+
+        if started.elapsed() > task.max_backoff {
+            backoff = task.min_backoff;
+        }
+
+        error!(
+            "Task {} failed, restarting in {backoff:?}: {error}",
+            task.name
+        );
+        states.update(index, |status| {
+            status.state = TaskState::Restarting;
+            status.since = Utc::now();
+            status.restarts += 1;
+            status.last_error = Some(error);
+        });
+
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.wait() => {
+                states.set_state(index, TaskState::Stopped);
+                return;
+            }
+        }
+
+        backoff = (backoff * 2).min(task.max_backoff);
+    }
+}
+
+pub(super) fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => format!("panicked: {message}"),
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => format!("panicked: {message}"),
+            Err(_) => "panicked".to_string(),
+        },
+    }
+}
+
+/// Runs hooks one by one, stopping at first error
+pub(super) async fn run_hooks(hooks: Vec<HookFn>, ctx: &TaskContext) -> io::Result<()> {
+    for hook in hooks {
+        hook(ctx.clone()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use actix_web::web;
+
+    use super::{BackgroundTask, TaskContext, TaskState, TaskStates, spawn_tasks};
+    use crate::server::shutdown::Shutdown;
+
+    /// Polls instead of sleeping for fixed time, as printing panic backtraces can take a while
+    async fn wait_for(states: &TaskStates, state: TaskState, restarts: usize) {
+        for _ in 0..500 {
+            let status = &states.all()[0];
+            if status.state == state && status.restarts == restarts {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn restarts_panicking_task_until_shutdown() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let shutdown = Shutdown::default();
+        let ctx = TaskContext::new(web::Data::new(runs.clone()), shutdown.clone());
+        let states = TaskStates::default();
+
+        let task = BackgroundTask::new("flaky", |ctx: TaskContext| async move {
+            let runs = ctx.app_data::<Arc<AtomicUsize>>().unwrap();
+            if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                panic!("boom");
+            }
+            ctx.shutdown().wait().await;
+            Ok::<_, String>(())
+        })
+        .backoff(Duration::from_millis(1), Duration::from_millis(10));
+
+        spawn_tasks(vec![task], ctx, states.clone());
+
+        wait_for(&states, TaskState::Running, 2).await;
+
+        let status = &states.all()[0];
+        assert_eq!(status.state, TaskState::Running);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_error.as_deref(), Some("panicked: boom"));
+        assert!(states.failing().is_none());
+
+        shutdown.begin();
+        wait_for(&states, TaskState::Stopped, 2).await;
+        assert_eq!(states.all()[0].state, TaskState::Stopped);
+    }
+}
@@ -0,0 +1,291 @@
+//! In-process test server built the same way as by [Serwus::start](super::Serwus::start)
+//!
+//! App gets all built-in middlewares (stats, error handlers, CORS, request IDs...) and internal
+//! endpoints, but no listener is bound and requests are called directly on the service.
+//!
+//! ```no_run
+//! # use actix_web::{Error, http::StatusCode};
+//! # use serde::{Deserialize, Serialize};
+//! # use serwus::{
+//! #     EmptyStats, web,
+//! #     server::{Serwus, TestServer, default_cors, json_error::{ErrorBuilder, JsonErrorType}},
+//! # };
+//! # #[derive(Clone, EmptyStats)]
+//! # struct AppData;
+//! # #[derive(Deserialize, Serialize)]
+//! # #[cfg_attr(feature = "swagger", derive(paperclip::actix::Apiv2Schema))]
+//! # struct User { id: u32 }
+//! # #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+//! # async fn get_user(id: web::Path<u32>) -> Result<web::Json<User>, Error> {
+//! #     match id.into_inner() {
+//! #         0 => Err(ErrorBuilder::not_found().finish().into()),
+//! #         id => Ok(web::Json(User { id })),
+//! #     }
+//! # }
+//! # fn configure_app(cfg: &mut web::ServiceConfig) {
+//! #     cfg.route("/users/{id}", web::get().to(get_user));
+//! # }
+//! # #[actix_web::main]
+//! # async fn main() {
+//! let server = TestServer::new(Serwus::default().json_errors(), || AppData, configure_app, default_cors).await;
+//!
+//! let user: User = server.get_json("/users/1").await.unwrap();
+//! server.assert_error(server.get("/users/0"), StatusCode::NOT_FOUND, JsonErrorType::NotFound).await;
+//! # }
+//! ```
+
+use actix_cors::Cors;
+use actix_http::Request;
+use actix_service::{
+    ServiceExt,
+    boxed::{self, BoxService},
+};
+use actix_web::{
+    Error,
+    body::BoxBody,
+    dev::ServiceResponse,
+    http::{Method, StatusCode, header::AUTHORIZATION},
+    test::{self, TestRequest},
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+#[cfg(not(feature = "swagger"))]
+use actix_web::web;
+
+#[cfg(feature = "swagger")]
+use paperclip::actix::web;
+
+use super::builder::Serwus;
+use super::json_error::{JsonError, JsonErrorType};
+use super::stats::{AppDataWrapper, BaseStats, StatsPresenter};
+
+/// Test server, see [module docs](self)
+pub struct TestServer {
+    service: BoxService<Request, ServiceResponse<BoxBody>, Error>,
+    stats: BaseStats,
+    bearer: Option<String>,
+}
+
+impl TestServer {
+    /// Builds app like [Serwus::start](super::Serwus::start) does, with internal endpoints
+    pub async fn new<D, T, F, C>(
+        serwus: Serwus,
+        prepare_app_data: impl Fn() -> T,
+        configure_app: F,
+        cors_factory: C,
+    ) -> Self
+    where
+        D: AppDataWrapper + 'static,
+        T: StatsPresenter<D> + 'static,
+        F: Fn(&mut web::ServiceConfig) + 'static,
+        C: Fn() -> Cors,
+    {
+        let shared = serwus.shared_data(prepare_app_data());
+        let stats = shared.stats.get_ref().clone();
+        let app = serwus.build_app::<D, T, F>(&shared, true, configure_app, cors_factory());
+        let service = test::init_service(app).await;
+
+        Self {
+            service: boxed::service(service.map(ServiceResponse::map_into_boxed_body)),
+            stats,
+            bearer: None,
+        }
+    }
+
+    /// Adds `Authorization: Bearer {token}` header to requests created with this server
+    pub fn with_bearer(mut self, token: impl Into<String>) -> Self {
+        self.bearer = Some(token.into());
+        self
+    }
+
+    /// Same as [with_bearer](Self::with_bearer) with token encoded by
+    /// [encode_jwt](crate::auth::jwt::encode_jwt)
+    ///
+    /// Panics if token can't be encoded.
+    #[cfg(feature = "auth")]
+    pub fn with_jwt<J>(self, claims: &J) -> Self
+    where
+        J: Serialize + crate::auth::jwt::KnowSecret,
+    {
+        let token = crate::auth::jwt::encode_jwt(claims).expect("Can't encode JWT");
+        self.with_bearer(token)
+    }
+
+    /// Base stats gathered by the app
+    pub fn stats(&self) -> &BaseStats {
+        &self.stats
+    }
+
+    /// Creates request, with bearer token if set
+    pub fn request(&self, method: Method, uri: &str) -> TestRequest {
+        let req = TestRequest::default().method(method).uri(uri);
+        match &self.bearer {
+            Some(token) => req.insert_header((AUTHORIZATION, format!("Bearer {token}"))),
+            None => req,
+        }
+    }
+
+    pub fn get(&self, uri: &str) -> TestRequest {
+        self.request(Method::GET, uri)
+    }
+
+    pub fn post(&self, uri: &str) -> TestRequest {
+        self.request(Method::POST, uri)
+    }
+
+    pub fn put(&self, uri: &str) -> TestRequest {
+        self.request(Method::PUT, uri)
+    }
+
+    pub fn delete(&self, uri: &str) -> TestRequest {
+        self.request(Method::DELETE, uri)
+    }
+
+    /// Calls the app
+    pub async fn call(&self, req: TestRequest) -> ServiceResponse<BoxBody> {
+        test::call_service(&self.service, req.to_request()).await
+    }
+
+    /// Calls the app and reads JSON body, [JsonError] if response is not successful.
+    ///
+    /// Panics if body can't be deserialized.
+    pub async fn json<R: DeserializeOwned>(&self, req: TestRequest) -> Result<R, JsonError> {
+        let res = self.call(req).await;
+
+        if res.status().is_success() {
+            Ok(test::read_body_json(res).await)
+        } else {
+            Err(read_json_error(res).await)
+        }
+    }
+
+    /// Sends GET request and reads JSON response
+    pub async fn get_json<R: DeserializeOwned>(&self, uri: &str) -> Result<R, JsonError> {
+        self.json(self.get(uri)).await
+    }
+
+    /// Sends POST request with JSON body and reads JSON response
+    pub async fn post_json<B, R>(&self, uri: &str, body: &B) -> Result<R, JsonError>
+    where
+        B: Serialize,
+        R: DeserializeOwned,
+    {
+        self.json(self.post(uri).set_json(body)).await
+    }
+
+    /// Sends PUT request with JSON body and reads JSON response
+    pub async fn put_json<B, R>(&self, uri: &str, body: &B) -> Result<R, JsonError>
+    where
+        B: Serialize,
+        R: DeserializeOwned,
+    {
+        self.json(self.put(uri).set_json(body)).await
+    }
+
+    /// Calls the app and asserts that response is [JsonError] with given status and type
+    pub async fn assert_error(
+        &self,
+        req: TestRequest,
+        status: StatusCode,
+        r#type: JsonErrorType,
+    ) -> JsonError {
+        let res = self.call(req).await;
+        assert_eq!(res.status(), status, "Unexpected response status");
+
+        let error = read_json_error(res).await;
+        assert_eq!(error.r#type, r#type, "Unexpected error type");
+        error
+    }
+}
+
+/// Panics if body is not a JsonError
+async fn read_json_error(res: ServiceResponse<BoxBody>) -> JsonError {
+    let status = res.status();
+    let body = test::read_body(res).await;
+
+    match serde_json::from_slice::<JsonError>(&body) {
+        Ok(error) => error.with_status_code(status),
+        Err(err) => panic!(
+            "Response {status} is not a JsonError ({err}): {}",
+            String::from_utf8_lossy(&body)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{Error, HttpResponse, http::StatusCode};
+    use serde::{Deserialize, Serialize};
+    use std::future::{Future, ready};
+    use std::pin::Pin;
+
+    use super::{TestServer, web};
+    use crate::server::{
+        Serwus, default_cors,
+        json_error::{ErrorBuilder, JsonErrorType},
+        stats::StatsPresenter,
+    };
+
+    #[derive(Clone)]
+    struct AppData;
+
+    impl StatsPresenter<()> for AppData {
+        fn is_ready(&self) -> Pin<Box<dyn Future<Output = Result<bool, Error>>>> {
+            Box::pin(ready(Ok(true)))
+        }
+
+        fn get_stats(&self) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+            Box::pin(ready(Ok(())))
+        }
+    }
+
+    #[derive(Deserialize, Serialize, PartialEq, Debug)]
+    #[cfg_attr(feature = "swagger", derive(paperclip::actix::Apiv2Schema))]
+    struct Echo {
+        value: u32,
+    }
+
+    #[cfg_attr(feature = "swagger", paperclip::actix::api_v2_operation)]
+    async fn echo(body: web::Json<Echo>) -> Result<HttpResponse, Error> {
+        if body.value == 0 {
+            return Err(ErrorBuilder::validation_fail_msg("Zero").finish().into());
+        }
+        Ok(HttpResponse::Ok().json(body.into_inner()))
+    }
+
+    #[actix_web::test]
+    async fn behaves_like_production_app() {
+        let server = TestServer::new(
+            Serwus::default().json_errors(),
+            || AppData,
+            |cfg: &mut web::ServiceConfig| {
+                cfg.route("/echo", web::post().to(echo));
+            },
+            default_cors,
+        )
+        .await;
+
+        let res = server.call(server.get("/_ready")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let echoed: Echo = server.post_json("/echo", &Echo { value: 5 }).await.unwrap();
+        assert_eq!(echoed, Echo { value: 5 });
+
+        let err = server
+            .post_json::<_, Echo>("/echo", &Echo { value: 0 })
+            .await
+            .unwrap_err();
+        assert_eq!(err.r#type, JsonErrorType::ValidationFail);
+        assert!(err.request_id.is_some());
+
+        server
+            .assert_error(
+                server.get("/missing"),
+                StatusCode::NOT_FOUND,
+                JsonErrorType::NotFound,
+            )
+            .await;
+
+        assert_eq!(server.stats().in_flight(), 0);
+    }
+}
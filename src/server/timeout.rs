@@ -0,0 +1,284 @@
+//! Per-request timeout middleware
+//!
+//! [Timeout] drops handler future when it runs past the deadline and responds with 504
+//! [JsonError](super::json_error::JsonError) instead. Note that work already passed to
+//! `web::block` keeps running on blocking thread, only response is not waited for.
+//!
+//! Timeout can be set globally with [Serwus::set_request_timeout](super::Serwus::set_request_timeout)
+//! and overridden per scope by wrapping it with another [Timeout]. Inner timeout replaces
+//! the deadline of outer one, so it can be both shorter and longer.
+
+use std::cell::Cell;
+use std::future::{Future, Ready, ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    Error, HttpMessage, HttpRequest, ResponseError,
+    body::{EitherBody, MessageBody},
+    dev::{RequestHead, ServiceRequest, ServiceResponse},
+    test::TestRequest,
+    web,
+};
+use log::warn;
+use tokio::sync::Notify;
+use tokio::time::{Instant, timeout_at};
+
+use super::json_error::ErrorBuilder;
+use super::request_id::RequestId;
+use super::stats::BaseStats;
+
+/// Deadline of request, shared between nested timeout middlewares
+#[derive(Clone)]
+struct Deadline(Rc<DeadlineInner>);
+
+struct DeadlineInner {
+    at: Cell<Instant>,
+    /// Wakes outer middleware, which may be already waiting for previous deadline
+    changed: Notify,
+}
+
+impl Deadline {
+    fn new(at: Instant) -> Self {
+        Self(Rc::new(DeadlineInner {
+            at: Cell::new(at),
+            changed: Notify::new(),
+        }))
+    }
+
+    fn at(&self) -> Instant {
+        self.0.at.get()
+    }
+
+    fn set(&self, at: Instant) {
+        self.0.at.set(at);
+        // Stores permit if outer middleware is not waiting right now
+        self.0.changed.notify_one();
+    }
+}
+
+/// Copy of request head for timeout response, passed through outer middlewares like any other.
+///
+/// Request itself is owned by dropped handler future and it can't be cloned in advance,
+/// because routing needs it to be the only reference.
+struct RequestCopy {
+    head: RequestHead,
+    request_id: Option<RequestId>,
+}
+
+impl RequestCopy {
+    fn new(req: &ServiceRequest) -> Self {
+        Self {
+            head: req.head().clone(),
+            request_id: req.extensions().get::<RequestId>().cloned(),
+        }
+    }
+
+    fn into_request(self) -> HttpRequest {
+        let head = self.head;
+        let mut req = TestRequest::default()
+            .method(head.method)
+            .uri(&head.uri.to_string())
+            .version(head.version);
+        for (name, value) in head.headers.iter() {
+            req = req.append_header((name.clone(), value.clone()));
+        }
+        if let Some(addr) = head.peer_addr {
+            req = req.peer_addr(addr);
+        }
+
+        let req = req.to_http_request();
+        if let Some(id) = self.request_id {
+            req.extensions_mut().insert(id);
+        }
+        req
+    }
+}
+
+/// Timeout middleware, see [module docs](self)
+#[derive(Clone, Copy, Debug)]
+pub struct Timeout(Duration);
+
+impl Timeout {
+    pub fn new(timeout: Duration) -> Self {
+        Self(timeout)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Timeout
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TimeoutMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TimeoutMiddleware {
+            service,
+            timeout: self.0,
+        }))
+    }
+}
+
+/// TimeoutMiddleware responds with 504 when request is not handled in time
+pub struct TimeoutMiddleware<S> {
+    service: S,
+    timeout: Duration,
+}
+
+impl<S, B> Service<ServiceRequest> for TimeoutMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let deadline = Instant::now() + self.timeout;
+
+        // Outer middleware already waits, just move its deadline
+        let outer = req.extensions().get::<Deadline>().cloned();
+        if let Some(outer) = outer {
+            outer.set(deadline);
+            let fut = self.service.call(req);
+            return Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) });
+        }
+
+        let shared = Deadline::new(deadline);
+        req.extensions_mut().insert(shared.clone());
+
+        let copy = RequestCopy::new(&req);
+        let stats = req.app_data::<web::Data<BaseStats>>().cloned();
+        let mut fut = Box::pin(self.service.call(req));
+
+        Box::pin(async move {
+            loop {
+                let deadline = shared.at();
+                tokio::select! {
+                    res = timeout_at(deadline, &mut fut) => match res {
+                        Ok(res) => return res.map(ServiceResponse::map_into_left_body),
+                        // Inner middleware moved deadline
+                        Err(_) if shared.at() > deadline => continue,
+                        Err(_) => break,
+                    },
+                    // Inner middleware moved deadline, maybe to earlier one
+                    _ = shared.0.changed.notified() => continue,
+                }
+            }
+
+            warn!(
+                "Request timed out: {} {}",
+                copy.head.method,
+                copy.head.uri.path()
+            );
+
+            if let Some(stats) = stats {
+                stats.increment(|stats| &stats.timeouts);
+            }
+
+            let error = ErrorBuilder::timeout().finish();
+            let res = ServiceResponse::new(copy.into_request(), error.error_response());
+            Ok(res.map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use actix_web::{
+        App, Error, HttpResponse,
+        body::BoxBody,
+        dev::{ServiceRequest, ServiceResponse},
+        http::StatusCode,
+        middleware::{DefaultHeaders, Next, from_fn},
+        rt::time::sleep,
+        test, web,
+    };
+
+    use super::Timeout;
+    use crate::server::request_id::RequestIdWrapper;
+
+    async fn slow() -> HttpResponse {
+        sleep(Duration::from_millis(100)).await;
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn inner_timeout_overrides_outer() {
+        let app = test::init_service(
+            App::new()
+                .route("/slow", web::get().to(slow))
+                .service(
+                    web::scope("/patient")
+                        .wrap(Timeout::new(Duration::from_secs(5)))
+                        .route("", web::get().to(slow)),
+                )
+                .wrap(Timeout::new(Duration::from_millis(20)))
+                .wrap(DefaultHeaders::new().add(("x-outer", "1"))),
+        )
+        .await;
+
+        // Response passes through outer middlewares
+        let req = test::TestRequest::get().uri("/slow").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(res.headers().get("x-outer").unwrap(), "1");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["type"], "Timeout");
+
+        let req = test::TestRequest::get().uri("/patient").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    async fn delay(
+        req: ServiceRequest,
+        next: Next<BoxBody>,
+    ) -> Result<ServiceResponse<BoxBody>, Error> {
+        sleep(Duration::from_millis(10)).await;
+        next.call(req).await
+    }
+
+    #[actix_web::test]
+    async fn shorter_inner_timeout_behind_async_middleware() {
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/hasty")
+                        .wrap(Timeout::new(Duration::from_millis(20)))
+                        .route("", web::get().to(slow)),
+                )
+                .wrap(from_fn(delay))
+                .wrap(Timeout::new(Duration::from_secs(5)))
+                .wrap(RequestIdWrapper),
+        )
+        .await;
+
+        let started = Instant::now();
+        let req = test::TestRequest::get().uri("/hasty").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        let request_id = res.headers().get("x-request-id").unwrap().clone();
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["request_id"], request_id.to_str().unwrap());
+        assert!(started.elapsed() < Duration::from_millis(90));
+    }
+}
@@ -0,0 +1,182 @@
+//! TLS (rustls) support with certificate reloading
+//!
+//! Certificate chain and private key are read from PEM files. Files are checked periodically
+//! and reloaded when their modification time changes, so renewed certificates are picked up
+//! without restarting the service.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_web::rt::time::interval;
+use log::{error, info};
+use rustls::{
+    ServerConfig,
+    crypto::ring::{default_provider, sign::any_supported_type},
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+/// How often certificate files are checked for changes
+pub const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Certificate resolver which always serves the most recently loaded certificate
+pub struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>)>,
+}
+
+impl ReloadableCert {
+    /// Reads certificate chain and private key from PEM files
+    pub fn load(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> io::Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+
+        let key = read_certified_key(&cert_path, &key_path)?;
+        let modified = last_modified(&cert_path, &key_path);
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new((key, modified)),
+        })
+    }
+
+    /// Reads files again and replaces served certificate.
+    ///
+    /// On error currently served certificate stays intact.
+    pub fn reload(&self) -> io::Result<()> {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+        let key = read_certified_key(&self.cert_path, &self.key_path)?;
+
+        if let Ok(mut current) = self.current.write() {
+            *current = (key, modified);
+        }
+
+        info!("TLS certificate reloaded from {}", self.cert_path.display());
+        Ok(())
+    }
+
+    /// Reloads certificate if any of files has been modified since last load
+    fn reload_if_modified(&self) {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+
+        let changed = self
+            .current
+            .read()
+            .map(|current| current.1 != modified)
+            .unwrap_or(false);
+
+        if changed && let Err(err) = self.reload() {
+            error!(
+                "Can't reload TLS certificate from {}: {err}",
+                self.cert_path.display()
+            );
+        }
+    }
+
+    /// Builds rustls server config using this certificate
+    pub fn server_config(self: &Arc<Self>) -> io::Result<ServerConfig> {
+        let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+
+        Ok(config)
+    }
+}
+
+impl fmt::Debug for ReloadableCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadableCert")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|current| current.0.clone())
+    }
+}
+
+/// Periodically reloads certificate when files change
+pub(super) async fn watch_files(cert: Arc<ReloadableCert>, period: Duration) {
+    let mut interval = interval(period);
+    // First tick completes immediately
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        cert.reload_if_modified();
+    }
+}
+
+fn read_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<Arc<CertifiedKey>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| pem_error(cert_path, err))?;
+
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", cert_path.display()),
+        ));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|err| pem_error(key_path, err))?;
+
+    let signing_key = any_supported_type(&key).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported private key in {}: {err}", key_path.display()),
+        )
+    })?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+fn pem_error(path: &Path, err: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Can't read PEM file {}: {err}", path.display()),
+    )
+}
+
+fn last_modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let cert = std::fs::metadata(cert_path)
+        .and_then(|m| m.modified())
+        .ok()?;
+    let key = std::fs::metadata(key_path)
+        .and_then(|m| m.modified())
+        .ok()?;
+    Some(cert.max(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::ReloadableCert;
+
+    #[test]
+    fn returns_error_for_unreadable_pem_files() {
+        let dir = std::env::temp_dir();
+        let missing = dir.join(format!("serwus-missing-{}.pem", std::process::id()));
+        let err = ReloadableCert::load(&missing, &missing).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Can't read PEM file"), "{err}");
+
+        let empty = dir.join(format!("serwus-empty-{}.pem", std::process::id()));
+        std::fs::write(&empty, "").unwrap();
+        let err = ReloadableCert::load(&empty, &empty).unwrap_err();
+        assert!(err.to_string().contains("No certificates found"), "{err}");
+        let _ = std::fs::remove_file(&empty);
+    }
+}
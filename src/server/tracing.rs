@@ -1,34 +1,75 @@
-use actix_web::Error;
 use actix_web::body::MessageBody;
 use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Uri;
+use actix_web::{Error, HttpMessage};
+use std::borrow::Cow;
+use std::ops::Deref;
+use std::sync::OnceLock;
 use tracing::Span;
 use tracing_actix_web::{DefaultRootSpanBuilder, Level, RootSpanBuilder};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, reload};
 
-use crate::logger;
+use super::HUSHED_PATHS;
+use super::access_log::redact_query;
+use super::request_id::RequestId;
+use crate::logger::{self, LogFilter};
 
-pub struct TracingSpanBuilder;
+/// Handle replacing filter of registered subscriber, see [logger::set_log_filter]
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Request passed to `root_span!`, with redacted query in `uri()` (recorded as `http.target`)
+struct RedactedRequest<'a> {
+    request: &'a ServiceRequest,
+    uri: Uri,
+}
+
+impl<'a> RedactedRequest<'a> {
+    fn new(request: &'a ServiceRequest) -> Self {
+        let uri = match request.uri().query().map(redact_query) {
+            Some(Cow::Owned(query)) => format!("{}?{query}", request.path())
+                .parse()
+                .unwrap_or_default(),
+            _ => request.uri().clone(),
+        };
+
+        Self { request, uri }
+    }
+
+    fn uri(&self) -> &Uri {
+        &self.uri
+    }
+}
+
+impl Deref for RedactedRequest<'_> {
+    type Target = ServiceRequest;
 
-const HUSHED_PATHS: [&str; 6] = [
-    "/_ready",
-    "/_healthcheck",
-    "/_stats",
-    "/_prometheus",
-    "/metrics",
-    "/swagger",
-];
+    fn deref(&self) -> &Self::Target {
+        self.request
+    }
+}
+
+pub struct TracingSpanBuilder;
 
 impl RootSpanBuilder for TracingSpanBuilder {
     fn on_request_start(request: &ServiceRequest) -> Span {
+        let request = &RedactedRequest::new(request);
+
         let level = if HUSHED_PATHS.contains(&request.path()) {
             Level::DEBUG
         } else {
             Level::INFO
         };
 
-        tracing_actix_web::root_span!(level = level, request)
+        let x_request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+
+        tracing_actix_web::root_span!(level = level, request, x_request_id = %x_request_id)
     }
 
     fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
@@ -37,9 +78,24 @@ impl RootSpanBuilder for TracingSpanBuilder {
 }
 
 pub fn register_tracing() {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(logger::logger_level()))
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(logger::log_filter().0.directives()));
+    let _ = FILTER_HANDLE.set(handle);
+
+    if let Err(err) = tracing_subscriber::registry()
+        .with(filter)
         .with(JsonStorageLayer)
         .with(BunyanFormattingLayer::new("serwus".into(), std::io::stdout))
-        .init();
+        .try_init()
+    {
+        log::error!("Can't register tracing subscriber: {err}");
+    }
+}
+
+/// Replaces filter of subscriber registered with [register_tracing], if any
+pub(crate) fn reload_filter(filter: &LogFilter) {
+    if let Some(handle) = FILTER_HANDLE.get()
+        && let Err(err) = handle.reload(EnvFilter::new(filter.directives()))
+    {
+        log::error!("Can't reload tracing filter: {err}");
+    }
 }
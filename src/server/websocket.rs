@@ -0,0 +1,349 @@
+//! WebSocket sessions with typed JSON messages
+//!
+//! [WsConfig::upgrade] (or [upgrade_authenticated](WsConfig::upgrade_authenticated), checking JWT
+//! from `Authorization` header or `access_token` query param, as browsers can't set headers
+//! on WebSocket requests) turns request into WebSocket session and returns response to be
+//! sent back along with [WsConnection]:
+//!
+//! ```no_run
+//! # #[cfg(feature = "auth")]
+//! # mod example {
+//! # use actix_web::{Error, HttpRequest, HttpResponse};
+//! # use serde::{Deserialize, Serialize};
+//! # use serwus::{auth::jwt::KnowSecret, server::websocket::{Closed, WsConfig}, web};
+//! # #[derive(Clone, Deserialize)]
+//! # struct AccessToken { sub: String }
+//! # impl KnowSecret for AccessToken { fn get_secret() -> Vec<u8> { b"secret".to_vec() } }
+//! # #[derive(Deserialize)]
+//! # enum ChatIn { Say(String) }
+//! # #[derive(Serialize)]
+//! # enum ChatOut { Said(String, String), Invalid(String) }
+//! async fn chat(req: HttpRequest, body: web::Payload) -> Result<HttpResponse, Error> {
+//!     let (res, mut conn, token) =
+//!         WsConfig::default().upgrade_authenticated::<AccessToken, ChatIn, ChatOut>(&req, body)?;
+//!
+//!     actix_web::rt::spawn(async move {
+//!         while let Some(msg) = conn.recv().await {
+//!             match msg {
+//!                 Ok(ChatIn::Say(text)) => conn.send(&ChatOut::Said(token.sub.clone(), text)).await?,
+//!                 Err(err) => conn.send(&ChatOut::Invalid(err.to_string())).await?,
+//!             }
+//!         }
+//!         Ok::<_, Closed>(())
+//!     });
+//!
+//!     Ok(res)
+//! }
+//! # }
+//! ```
+//!
+//! While waiting in [recv](WsConnection::recv) connection is pinged every `heartbeat` interval
+//! and closed when client sends nothing (pongs included) for `idle_timeout`. When graceful
+//! shutdown begins session is closed with `1001 Going Away`, so clients reconnect to other
+//! instance. Number of open sessions is reported as `websocket_sessions` in base stats.
+
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use actix_web::{Error, HttpRequest, HttpResponse, web};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, CloseCode, CloseReason};
+pub use actix_ws::{Closed, Session};
+use log::debug;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::time::{Instant, Interval, MissedTickBehavior, interval};
+
+use super::shutdown::{Shutdown, wait_for_shutdown};
+use super::stats::{BaseStats, StatsGauge};
+
+/// Settings of WebSocket sessions, see [module docs](self)
+#[derive(Clone, Debug)]
+pub struct WsConfig {
+    heartbeat: Duration,
+    idle_timeout: Duration,
+    max_message_size: usize,
+    token_query_param: String,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(30),
+            max_message_size: 64 * 1024,
+            token_query_param: "access_token".to_string(),
+        }
+    }
+}
+
+impl WsConfig {
+    /// Sets ping interval and time without any message from client after which session
+    /// is closed (default: 10 s, 30 s)
+    pub fn heartbeat(mut self, interval: Duration, idle_timeout: Duration) -> Self {
+        self.heartbeat = interval;
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets size limit of single (aggregated) message (default: 64 KiB)
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Sets name of query param with JWT used when `Authorization` header is missing
+    /// (default: `access_token`).
+    ///
+    /// Only values of [REDACTED_QUERY_PARAMS](crate::server::access_log::REDACTED_QUERY_PARAMS)
+    /// are hidden in access logs and tracing spans, token passed in param with other name
+    /// is logged as part of request target.
+    pub fn token_query_param(mut self, name: impl Into<String>) -> Self {
+        self.token_query_param = name.into();
+        self
+    }
+
+    /// Starts WebSocket session, returned response has to be sent back from handler
+    pub fn upgrade<In, Out>(
+        &self,
+        req: &HttpRequest,
+        body: web::Payload,
+    ) -> Result<(HttpResponse, WsConnection<In, Out>), Error> {
+        let (res, session, stream) = actix_ws::handle(req, body)?;
+
+        let stream = stream
+            .max_frame_size(self.max_message_size)
+            .aggregate_continuations()
+            .max_continuation_size(self.max_message_size);
+
+        let mut heartbeat = interval(self.heartbeat);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let conn = WsConnection {
+            session,
+            stream,
+            heartbeat,
+            idle_timeout: self.idle_timeout,
+            last_seen: Instant::now(),
+            closed: false,
+            shutdown: req
+                .app_data::<web::Data<Shutdown>>()
+                .map(|shutdown| shutdown.get_ref().clone()),
+            _gauge: StatsGauge::new(req.app_data::<web::Data<BaseStats>>().cloned(), |stats| {
+                &stats.websocket_sessions
+            }),
+            _messages: PhantomData,
+        };
+
+        Ok((res, conn))
+    }
+
+    /// Starts WebSocket session for request with valid JWT, responds with 401 otherwise
+    #[cfg(feature = "auth")]
+    pub fn upgrade_authenticated<T, In, Out>(
+        &self,
+        req: &HttpRequest,
+        body: web::Payload,
+    ) -> Result<(HttpResponse, WsConnection<In, Out>, T), Error>
+    where
+        T: crate::auth::jwt::FromEncoded,
+    {
+        let token = self.authenticate::<T>(req)?;
+        let (res, conn) = self.upgrade(req, body)?;
+        Ok((res, conn, token))
+    }
+
+    #[cfg(feature = "auth")]
+    fn authenticate<T>(&self, req: &HttpRequest) -> Result<T, Error>
+    where
+        T: crate::auth::jwt::FromEncoded,
+    {
+        use super::json_error::ErrorBuilder;
+        use actix_web::http::header::AUTHORIZATION;
+        use std::collections::HashMap;
+
+        if req.headers().contains_key(AUTHORIZATION) {
+            return crate::auth::jwt::from_request::<T>(req)
+                .map_err(|err| ErrorBuilder::unauthorized(err).finish().into());
+        }
+
+        let token = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|mut query| query.remove(&self.token_query_param))
+            .ok_or_else(|| ErrorBuilder::unauthorized("Missing auth token").finish())?;
+
+        T::from_encoded(&token).map_err(|err| {
+            debug!("Invalid WebSocket auth token: {err}");
+            ErrorBuilder::unauthorized("Invalid Token").finish().into()
+        })
+    }
+}
+
+/// Open WebSocket session receiving `In` and sending `Out` messages as JSON
+pub struct WsConnection<In, Out> {
+    session: Session,
+    stream: AggregatedMessageStream,
+    heartbeat: Interval,
+    idle_timeout: Duration,
+    last_seen: Instant,
+    closed: bool,
+    shutdown: Option<Shutdown>,
+    _gauge: StatsGauge,
+    _messages: PhantomData<fn(In) -> Out>,
+}
+
+impl<In, Out> WsConnection<In, Out>
+where
+    In: DeserializeOwned,
+    Out: Serialize,
+{
+    /// Next text or binary message parsed from JSON, `None` when session is closed.
+    ///
+    /// Answers pings and sends heartbeats meanwhile, so keep calling it (f. ex. in `select!`
+    /// with other sources of messages, it's cancel safe) for the whole session.
+    pub async fn recv(&mut self) -> Option<Result<In, serde_json::Error>> {
+        while !self.closed {
+            let shutdown = self.shutdown.clone();
+
+            tokio::select! {
+                msg = self.stream.recv() => {
+                    let msg = match msg {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(err)) => {
+                            debug!("WebSocket protocol error: {err}");
+                            self.close_with(CloseCode::Protocol, "protocol error").await;
+                            return None;
+                        }
+                        None => {
+                            self.closed = true;
+                            return None;
+                        }
+                    };
+
+                    self.last_seen = Instant::now();
+
+                    match msg {
+                        AggregatedMessage::Text(text) => return Some(serde_json::from_str(&text)),
+                        AggregatedMessage::Binary(bytes) => return Some(serde_json::from_slice(&bytes)),
+                        AggregatedMessage::Ping(bytes) => {
+                            if self.session.pong(&bytes).await.is_err() {
+                                self.closed = true;
+                            }
+                        }
+                        AggregatedMessage::Pong(_) => {}
+                        AggregatedMessage::Close(reason) => {
+                            self.closed = true;
+                            let _ = self.session.clone().close(reason).await;
+                        }
+                    }
+                }
+                _ = self.heartbeat.tick() => {
+                    if self.last_seen.elapsed() > self.idle_timeout {
+                        self.close_with(CloseCode::Normal, "idle timeout").await;
+                    } else if self.session.ping(b"").await.is_err() {
+                        self.closed = true;
+                    }
+                }
+                _ = wait_for_shutdown(shutdown) => {
+                    self.close_with(CloseCode::Away, "server is shutting down").await;
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Sends message as JSON text
+    pub async fn send(&mut self, msg: &Out) -> Result<(), Closed> {
+        let text = serde_json::to_string(msg).map_err(|err| {
+            log::error!("Can't serialize WebSocket message: {err}");
+            Closed
+        })?;
+        self.session.text(text).await
+    }
+
+    /// Raw session, can be cloned to send messages from other tasks
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Closes session with given reason
+    pub async fn close(mut self, reason: Option<CloseReason>) {
+        self.closed = true;
+        let _ = self.session.clone().close(reason).await;
+    }
+
+    async fn close_with(&mut self, code: CloseCode, description: &str) {
+        self.closed = true;
+        let reason = CloseReason {
+            code,
+            description: Some(description.to_string()),
+        };
+        let _ = self.session.clone().close(Some(reason)).await;
+    }
+}
+
+#[cfg(all(test, feature = "auth"))]
+mod tests {
+    use actix_web::{
+        App, Error, HttpRequest, HttpResponse,
+        http::{StatusCode, header},
+        test, web,
+    };
+
+    use super::WsConfig;
+    use crate::server::stats::BaseStats;
+
+    #[derive(Clone, serde::Serialize, serde::Deserialize)]
+    struct Token {
+        sub: String,
+        exp: u64,
+    }
+
+    impl crate::auth::jwt::KnowSecret for Token {
+        fn get_secret() -> Vec<u8> {
+            b"secret".to_vec()
+        }
+    }
+
+    async fn ws(req: HttpRequest, body: web::Payload) -> Result<HttpResponse, Error> {
+        let (res, conn, _token) =
+            WsConfig::default().upgrade_authenticated::<Token, (), ()>(&req, body)?;
+
+        let stats = req.app_data::<web::Data<BaseStats>>().unwrap();
+        assert_eq!(stats.snapshot().unwrap().websocket_sessions, 1);
+        drop(conn);
+        assert_eq!(stats.snapshot().unwrap().websocket_sessions, 0);
+
+        Ok(res)
+    }
+
+    fn handshake(uri: &str) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+    }
+
+    #[actix_web::test]
+    async fn authenticates_upgrade_with_query_token() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(BaseStats::default()))
+                .route("/ws", web::get().to(ws)),
+        )
+        .await;
+
+        let res = test::call_service(&app, handshake("/ws").to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let token = crate::auth::jwt::encode_jwt(&Token {
+            sub: "user".to_string(),
+            exp: u64::MAX / 2,
+        })
+        .unwrap();
+        let req = handshake(&format!("/ws?access_token={token}")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+}
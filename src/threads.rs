@@ -1,11 +1,13 @@
 use std::env;
 
+/// Number of CPUs (but not less than 2), capped to 2 if `TEST` env variable is defined
 pub fn num_threads() -> usize {
-    let num_cpus = if env::var("TEST").is_ok() {
-        2
-    } else {
-        num_cpus::get()
-    };
+    num_threads_for(env::var("TEST").is_ok())
+}
+
+/// Number of CPUs (but not less than 2), capped to 2 in test mode
+pub fn num_threads_for(test: bool) -> usize {
+    let num_cpus = if test { 2 } else { num_cpus::get() };
 
     if num_cpus < 2 { 2 } else { num_cpus }
 }